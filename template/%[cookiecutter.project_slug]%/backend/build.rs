@@ -95,6 +95,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     for full_name in &all_message_names {
         config.type_attribute(full_name, derive_attr);
     }
+    // `prost::Name` gives the type of events published through the outbox
+    config.enable_type_names();
 
     // Compile protos
     let proto_strs: Vec<&str> = protos.iter().map(|p| p.to_str().unwrap()).collect();
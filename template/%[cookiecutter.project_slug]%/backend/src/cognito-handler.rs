@@ -1,4 +1,6 @@
 use aws_sdk_dynamodb::Client;
+use backend::shared::dynamodb::RepoError;
+use backend::shared::idempotency::IdempotencyStore;
 use backend::{load_aws_config, CognitoUserPoolEvent };
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use protocol_macro::protocols;
@@ -6,10 +8,15 @@ use protocol_macro::protocols;
 #[protocols("sign_up_data")]
 pub mod protocols {}
 
+#[protocols("user_events")]
+pub mod user_events {}
+
 ///
 /// This lambda reacts on Cognito's lifecycle events.
 ///
-/// The default version stores sign up data in the users table at post confirmation.
+/// The default version stores sign up data in the users table at post confirmation
+/// and publishes `UserCreated` through the outbox.
+/// Cognito retries triggers, so the post confirmation runs once per user.
 ///
 /// If you add more cases, make sure to add them to local/cognito-local-volume/config.json
 /// and to infrastructure/lib/constructs/backend/identity.ts
@@ -17,6 +24,7 @@ pub mod protocols {}
 async fn function_handler(
     event: LambdaEvent<CognitoUserPoolEvent>,
     repo: &backend::shared::users::UserRepo,
+    idempotency: &IdempotencyStore,
 ) -> Result<CognitoUserPoolEvent, Error> {
     let mut cognito_event = event.payload;
     match &mut cognito_event {
@@ -37,20 +45,36 @@ async fn function_handler(
                         .get("sub")
                         .cloned()
                         .unwrap_or_default(),
-                    email: post_confirmation
-                        .request
-                        .user_attributes
-                        .get("email")
-                        .cloned()
-                        .unwrap_or_default(),
-                    first_name: sign_up_data.first_name,
-                    last_name: sign_up_data.last_name,
+                    email: backend::shared::users::normalize_email(
+                        post_confirmation
+                            .request
+                            .user_attributes
+                            .get("email")
+                            .map(String::as_str)
+                            .unwrap_or_default(),
+                    )
+                    .into(),
+                    first_name: sign_up_data.first_name.into(),
+                    last_name: sign_up_data.last_name.into(),
                 };
 
-                if let Err(e) = repo.insert(user_data).await {
-                    println!("Failed to insert user: {:?}", e);
-                    return Err(Error::from(format!("Failed to insert user: {:?}", e)));
-                }
+                let created = UserCreated {
+                    username: user_data.username.clone(),
+                };
+                let key = format!("post-confirmation#{}", user_data.username);
+                idempotency
+                    .once(&key, || async {
+                        match repo.publishing(&created).insert(user_data).await {
+                            Ok(_) => Ok(()),
+                            // a timed out run stored the user and its event already
+                            Err(RepoError::AlreadyExists) => Ok(()),
+                            Err(e) => {
+                                println!("Failed to insert user: {:?}", e);
+                                Err(Error::from(format!("Failed to insert user: {:?}", e)))
+                            }
+                        }
+                    })
+                    .await?;
             }
         }
         CognitoUserPoolEvent::CustomMessage(_custom_message) => {
@@ -94,17 +118,46 @@ fn get_table_name() -> String {
     std::env::var("USERS_TABLE_NAME").expect("USERS_TABLE_NAME must be set")
 }
 
+#[cfg(debug_assertions)]
+fn get_idempotency_table_name() -> String {
+    std::env::var("IDEMPOTENCY_TABLE_NAME").unwrap_or_else(|_| "idempotency".to_string())
+}
+
+#[cfg(not(debug_assertions))]
+fn get_idempotency_table_name() -> String {
+    std::env::var("IDEMPOTENCY_TABLE_NAME").expect("IDEMPOTENCY_TABLE_NAME must be set")
+}
+
+#[cfg(debug_assertions)]
+fn get_outbox_table_name() -> String {
+    std::env::var("OUTBOX_TABLE_NAME").unwrap_or_else(|_| "outbox".to_string())
+}
+
+#[cfg(not(debug_assertions))]
+fn get_outbox_table_name() -> String {
+    std::env::var("OUTBOX_TABLE_NAME").expect("OUTBOX_TABLE_NAME must be set")
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let table_name = get_table_name();
+    let idempotency_table_name = get_idempotency_table_name();
+    let outbox_table_name = get_outbox_table_name();
     let config = load_aws_config().await;
+    backend::shared::encryption::install_keyring(&config).await?;
 
     let client = Client::new(&config);
-    let repo = backend::shared::users::UserRepo::new(client, table_name);
+    backend::shared::users::ensure_local_table(&client, &table_name).await?;
+    backend::shared::idempotency::ensure_local_table(&client, &idempotency_table_name).await?;
+    backend::shared::events::ensure_local_table(&client, &outbox_table_name).await?;
+    let repo = backend::shared::users::UserRepo::new(client.clone(), table_name)
+        .with_outbox(outbox_table_name);
+    let idempotency = IdempotencyStore::new(client, idempotency_table_name);
 
     run(service_fn(move |event| {
         let repo = repo.clone();
-        async move { function_handler(event, &repo).await }
+        let idempotency = idempotency.clone();
+        async move { function_handler(event, &repo, &idempotency).await }
     }))
     .await
 }
@@ -115,28 +168,13 @@ mod tests {
     use aws_lambda_events::cognito::{
         CognitoEventUserPoolsPostConfirmation, CognitoEventUserPoolsPostConfirmationRequest,
     };
-    use wiremock::matchers::{body_string_contains, method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
-
-    #[tokio::test]
-    async fn test_post_confirmation_writes_to_dynamodb() {
-        let server = MockServer::start().await;
-
-        // Mock DynamoDB PutItem
-        Mock::given(method("POST"))
-            .and(path("/"))
-            .and(body_string_contains("DynamoDB_20120810.PutItem"))
-            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
-            .mount(&server)
-            .await;
-
-        let shared_config = backend::shared::aws_config::load_aws_config_for_mock(&server).await;
-        let client = aws_sdk_dynamodb::Client::new(&shared_config);
-        let repo = backend::shared::users::UserRepo::new(client, "users".to_string());
+    use backend::shared::dynamodb::MemoryStorage;
+    use std::sync::Arc;
 
+    fn post_confirmation_event() -> LambdaEvent<CognitoUserPoolEvent> {
         let sign_up_data = serde_json::json!({
-            "first_name": "Test",
-            "last_name": "User"
+            "firstName": "Test",
+            "lastName": "User"
         })
         .to_string();
 
@@ -154,12 +192,60 @@ mod tests {
         let mut post_confirmation = CognitoEventUserPoolsPostConfirmation::default();
         post_confirmation.request = request;
 
-        let event = LambdaEvent::new(
+        LambdaEvent::new(
             CognitoUserPoolEvent::PostConfirmation(post_confirmation),
             Default::default(),
-        );
+        )
+    }
 
-        let result = function_handler(event, &repo).await;
+    #[tokio::test]
+    async fn test_post_confirmation_writes_to_dynamodb() {
+        let storage = MemoryStorage::new();
+        let repo: backend::shared::users::UserRepo = storage.repo("users").with_outbox("outbox");
+        storage.create_table("idempotency", &[]);
+        storage.create_table("outbox", &[]);
+        let idempotency =
+            IdempotencyStore::from_storage(Arc::new(storage.clone()), "idempotency".to_string());
+        let event = post_confirmation_event();
+
+        let result = function_handler(event.clone(), &repo, &idempotency).await;
         assert!(result.is_ok());
+
+        // Cognito retries the trigger
+        let retried = function_handler(event, &repo, &idempotency).await;
+        assert!(retried.is_ok());
+
+        let user = repo.read("test-sub").await.unwrap().unwrap();
+        assert_eq!(user.data.email.as_str(), "test@example.com");
+        assert_eq!(user.data.first_name.as_str(), "Test");
+        assert_eq!(user.data.last_name.as_str(), "User");
+
+        let events = storage.items("outbox");
+        assert_eq!(events.len(), 1);
+        let event = backend::shared::dynamodb::OutboxEvent::from_item(events[0].clone()).unwrap();
+        assert_eq!(event.event_type, "user_events.UserCreated");
+    }
+
+    #[tokio::test]
+    async fn test_post_confirmation_retried_after_lock_timeout() {
+        let storage = MemoryStorage::new();
+        let repo: backend::shared::users::UserRepo = storage.repo("users").with_outbox("outbox");
+        storage.create_table("idempotency", &[]);
+        storage.create_table("outbox", &[]);
+        let idempotency =
+            IdempotencyStore::from_storage(Arc::new(storage.clone()), "idempotency".to_string());
+        function_handler(post_confirmation_event(), &repo, &idempotency)
+            .await
+            .unwrap();
+
+        // the first run timed out after the insert, its record is gone
+        storage.create_table("idempotency-retry", &[]);
+        let retry = IdempotencyStore::from_storage(
+            Arc::new(storage.clone()),
+            "idempotency-retry".to_string(),
+        );
+        let retried = function_handler(post_confirmation_event(), &repo, &retry).await;
+        assert!(retried.is_ok());
+        assert_eq!(storage.items("outbox").len(), 1);
     }
 }
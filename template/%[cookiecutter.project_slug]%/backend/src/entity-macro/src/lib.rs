@@ -0,0 +1,215 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitStr, Type};
+
+///
+/// Declares the global secondary indexes of an entity and generates typed queries for them.
+///
+/// Usage:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Indexes)]
+/// #[index(name = "email-index", partition = email)]
+/// #[index(name = "team-index", partition = team, sort = joined, keys_only)]
+/// #[index(name = "city-index", partition = city, include(first_name, last_name))]
+/// pub struct UserData { ... }
+///
+/// impl Entity for UserData {
+///     const INDEXES: &'static [Index] = &[Self::EMAIL_INDEX, Self::TEAM_INDEX, Self::CITY_INDEX];
+///     // ...
+/// }
+///
+/// repo.query_by_email("jane@example.com", PageRequest::first(1)).await?;
+/// repo.query_by_team("red", Some(KeyCondition::Gt(2020)), PageRequest::default()).await?;
+/// ```
+///
+/// The key attributes must be fields of the struct, their types define the parameter types.
+/// The `-index` suffix of the name is dropped for the names of the `Index` constant and the query method.
+///
+#[proc_macro_derive(Indexes, attributes(index))]
+pub fn derive_indexes(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct IndexDeclaration {
+    name: LitStr,
+    partition: Ident,
+    sort: Option<Ident>,
+    projection: Projection,
+}
+
+enum Projection {
+    All,
+    KeysOnly,
+    Include(Vec<Ident>),
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let entity = &input.ident;
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            entity,
+            "Indexes can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            entity,
+            "Indexes requires named fields",
+        ));
+    };
+    let field_type = |name: &Ident| -> syn::Result<&Type> {
+        fields
+            .named
+            .iter()
+            .find(|field| field.ident.as_ref() == Some(name))
+            .map(|field| &field.ty)
+            .ok_or_else(|| {
+                syn::Error::new_spanned(name, format!("`{}` has no field `{}`", entity, name))
+            })
+    };
+
+    let mut constants = Vec::new();
+    let mut queries = Vec::new();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("index"))
+    {
+        let index = parse_index(attr)?;
+        let name = index.name.value();
+        let base = name.trim_end_matches("-index").replace('-', "_");
+        let constant = format_ident!("{}_INDEX", base.to_uppercase());
+        let method = format_ident!("query_by_{}", base);
+
+        let partition = &index.partition;
+        let partition_name = partition.to_string();
+        let partition_type = field_type(partition)?;
+
+        let sort_key = match &index.sort {
+            Some(sort) => {
+                let sort_name = sort.to_string();
+                let sort_type = field_type(sort)?;
+                quote! { Some((#sort_name, <#sort_type as crate::shared::dynamodb::IndexKey>::TYPE)) }
+            }
+            None => quote! { None },
+        };
+        let projection = match &index.projection {
+            Projection::All => quote! { crate::shared::dynamodb::Projection::All },
+            Projection::KeysOnly => quote! { crate::shared::dynamodb::Projection::KeysOnly },
+            Projection::Include(attributes) => {
+                for attribute in attributes {
+                    field_type(attribute)?;
+                }
+                let attributes = attributes.iter().map(Ident::to_string);
+                quote! { crate::shared::dynamodb::Projection::Include(&[#(#attributes),*]) }
+            }
+        };
+
+        let doc = format!("The `{}` index by `{}`.", name, partition_name);
+        constants.push(quote! {
+            #[doc = #doc]
+            pub const #constant: crate::shared::dynamodb::Index = crate::shared::dynamodb::Index {
+                name: #name,
+                partition_key: (#partition_name, <#partition_type as crate::shared::dynamodb::IndexKey>::TYPE),
+                sort_key: #sort_key,
+                projection: #projection,
+            };
+        });
+
+        let doc = format!(
+            "Queries a page of the `{}` index by `{}`.",
+            name, partition_name
+        );
+        let query = match &index.sort {
+            Some(sort) => {
+                let sort_type = field_type(sort)?;
+                quote! {
+                    #[doc = #doc]
+                    pub async fn #method(
+                        &self,
+                        #partition: impl Into<#partition_type>,
+                        #sort: Option<crate::shared::dynamodb::KeyCondition<#sort_type>>,
+                        page: crate::shared::dynamodb::PageRequest,
+                    ) -> Result<crate::shared::dynamodb::Page<#entity>, crate::shared::dynamodb::RepoError> {
+                        self.query_by(&#entity::#constant, &#partition.into(), #sort, page).await
+                    }
+                }
+            }
+            None => quote! {
+                #[doc = #doc]
+                pub async fn #method(
+                    &self,
+                    #partition: impl Into<#partition_type>,
+                    page: crate::shared::dynamodb::PageRequest,
+                ) -> Result<crate::shared::dynamodb::Page<#entity>, crate::shared::dynamodb::RepoError> {
+                    self.query_by(
+                        &#entity::#constant,
+                        &#partition.into(),
+                        None::<crate::shared::dynamodb::KeyCondition<#partition_type>>,
+                        page,
+                    )
+                    .await
+                }
+            },
+        };
+        queries.push(query);
+    }
+
+    Ok(quote! {
+        impl #entity {
+            #(#constants)*
+        }
+
+        impl crate::shared::dynamodb::VersionedRepo<#entity> {
+            #(#queries)*
+        }
+    })
+}
+
+fn parse_index(attr: &syn::Attribute) -> syn::Result<IndexDeclaration> {
+    let mut name = None;
+    let mut partition = None;
+    let mut sort = None;
+    let mut projection = Projection::All;
+
+    attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("name") {
+            name = Some(meta.value()?.parse::<LitStr>()?);
+        } else if meta.path.is_ident("partition") {
+            partition = Some(meta.value()?.parse::<Ident>()?);
+        } else if meta.path.is_ident("sort") {
+            sort = Some(meta.value()?.parse::<Ident>()?);
+        } else if meta.path.is_ident("keys_only") {
+            projection = Projection::KeysOnly;
+        } else if meta.path.is_ident("include") {
+            let mut attributes = Vec::new();
+            meta.parse_nested_meta(|attribute| {
+                let ident = attribute
+                    .path
+                    .get_ident()
+                    .ok_or_else(|| attribute.error("expected a field name"))?;
+                attributes.push(ident.clone());
+                Ok(())
+            })?;
+            projection = Projection::Include(attributes);
+        } else {
+            return Err(
+                meta.error("expected `name`, `partition`, `sort`, `keys_only` or `include`")
+            );
+        }
+        Ok(())
+    })?;
+
+    Ok(IndexDeclaration {
+        name: name.ok_or_else(|| syn::Error::new_spanned(attr, "missing `name = \"...\"`"))?,
+        partition: partition
+            .ok_or_else(|| syn::Error::new_spanned(attr, "missing `partition = <field>`"))?,
+        sort,
+        projection,
+    })
+}
@@ -1,13 +1,26 @@
 use aws_lambda_events::event::sqs::SqsEvent;
+use backend::shared::events::PublishedEvent;
 use lambda_runtime::{run, service_fn, tracing, Error, LambdaEvent};
+use protocol_macro::protocols;
+
+#[protocols("user_events")]
+pub mod protocols {}
 
 /// This is the main body for the function.
 /// Write your code inside it.
+/// Events published through the outbox arrive at least once: drop the ids you have processed,
+/// e.g. with `IdempotencyStore::once(&event.id, ...)`.
 /// There are some code example in the following URLs:
 /// - https://github.com/awslabs/aws-lambda-rust-runtime/tree/main/examples
 /// - https://github.com/aws-samples/serverless-rust-demo/
 async fn function_handler(event: LambdaEvent<SqsEvent>) -> Result<(), Error> {
     tracing::info!("Received {} SQS messages", event.payload.records.len());
+    for message in &event.payload.records {
+        let event = PublishedEvent::from_message(message)?;
+        if let Some(user) = event.decode::<UserCreated>()? {
+            tracing::info!("User {} was created ({})", user.username, event.id);
+        }
+    }
     Ok(())
 }
 
@@ -0,0 +1,156 @@
+use aws_sdk_dynamodb::Client;
+use backend::load_aws_config;
+use backend::shared::dynamodb::{BackfillOptions, Entity, VersionedRepo};
+use backend::shared::users::UserData;
+use lambda_runtime::Error;
+
+const USAGE: &str = "Usage: migrate-table <entity> --table <name> [--target-version <n>] \
+[--segments <n>] [--dry-run] [--resume <token>] [--unique]";
+
+///
+/// Migrates the items of a versioned table below a target `data_version` eagerly.
+///
+/// Lazy migration on read leaves items that are never read on old `data_version`s.
+/// Run this binary after deploying a migration to upgrade them, e.g. locally against LocalStack:
+///
+/// ```bash
+/// cargo run --bin migrate-table -- user_data --table users --dry-run
+/// ```
+///
+/// A resume token is printed after every page. Pass it with `--resume` to continue an interrupted run.
+///
+/// With `--unique` it claims the sentinels of the unique values of all items instead,
+/// required after declaring a `Unique` on an entity whose table has items already.
+///
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args = Args::parse(std::env::args().skip(1))?;
+
+    // Register new entities here
+    match args.entity.as_str() {
+        UserData::NAME => run::<UserData>(args).await,
+        other => Err(format!("Unknown entity: {}\n{}", other, USAGE).into()),
+    }
+}
+
+async fn run<T: Entity>(args: Args) -> Result<(), Error> {
+    let config = load_aws_config().await;
+    backend::shared::encryption::install_keyring(&config).await?;
+    let repo = VersionedRepo::<T>::new(Client::new(&config), args.table);
+
+    if args.unique {
+        println!("Claiming the unique values of {}", T::NAME);
+        let taken = repo.claim_unique_values().await?;
+        for (key, attribute) in &taken {
+            println!("{} of {:?} is taken by another item", attribute, key);
+        }
+        println!("Finished: {} values taken", taken.len());
+        return Ok(());
+    }
+
+    let mut options = BackfillOptions::for_entity::<T>();
+    if let Some(target_version) = args.target_version {
+        options.target_version = target_version;
+    }
+    if let Some(segments) = args.segments {
+        options.segments = segments;
+    }
+    options.dry_run = args.dry_run;
+    options.resume = args.resume.as_deref().map(str::parse).transpose()?;
+
+    println!(
+        "Migrating {} to data_version {}{}",
+        T::NAME,
+        options.target_version,
+        if options.dry_run { " (dry run)" } else { "" }
+    );
+
+    let report = repo
+        .backfill(options, |report, token| {
+            if token.is_done() {
+                println!("{}", report);
+            } else {
+                println!("{} (resume token: {})", report, token);
+            }
+        })
+        .await?;
+
+    println!("Finished: {}", report);
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    entity: String,
+    table: String,
+    target_version: Option<u16>,
+    segments: Option<u32>,
+    dry_run: bool,
+    resume: Option<String>,
+    unique: bool,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, Error> {
+        let mut parsed = Args::default();
+        let mut table = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| Error::from(USAGE));
+            match arg.as_str() {
+                "--table" => table = Some(value()?),
+                "--target-version" => parsed.target_version = Some(value()?.parse()?),
+                "--segments" => parsed.segments = Some(value()?.parse()?),
+                "--resume" => parsed.resume = Some(value()?),
+                "--dry-run" => parsed.dry_run = true,
+                "--unique" => parsed.unique = true,
+                entity if !entity.starts_with("--") && parsed.entity.is_empty() => {
+                    parsed.entity = entity.to_string()
+                }
+                _ => return Err(USAGE.into()),
+            }
+        }
+
+        if parsed.entity.is_empty() {
+            return Err(USAGE.into());
+        }
+        parsed.table = table.ok_or_else(|| Error::from(USAGE))?;
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Args, Error> {
+        Args::parse(line.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let parsed =
+            args("user_data --table users --segments 8 --dry-run --resume abc --target-version 2")
+                .unwrap();
+        assert_eq!(
+            parsed,
+            Args {
+                entity: "user_data".to_string(),
+                table: "users".to_string(),
+                target_version: Some(2),
+                segments: Some(8),
+                dry_run: true,
+                resume: Some("abc".to_string()),
+                unique: false,
+            }
+        );
+        assert!(args("user_data --table users --unique").unwrap().unique);
+    }
+
+    #[test]
+    fn requires_entity_and_table() {
+        assert!(args("--table users").is_err());
+        assert!(args("user_data").is_err());
+        assert!(args("user_data --table").is_err());
+    }
+}
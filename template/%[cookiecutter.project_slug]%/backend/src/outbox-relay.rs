@@ -0,0 +1,33 @@
+use aws_lambda_events::event::dynamodb::Event;
+use aws_lambda_events::event::streams::DynamoDbEventResponse;
+use backend::load_aws_config;
+use backend::shared::events::OutboxRelay;
+use lambda_runtime::{run, service_fn, tracing, Error, LambdaEvent};
+
+///
+/// This lambda publishes the events of the outbox table to the events queue.
+///
+/// It consumes the stream of the outbox table, see `VersionedRepo::publishing`.
+///
+async fn function_handler(
+    event: LambdaEvent<Event>,
+    relay: &OutboxRelay,
+) -> Result<DynamoDbEventResponse, Error> {
+    tracing::info!("Received {} outbox records", event.payload.records.len());
+    Ok(relay.handle_stream(&event.payload).await)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing::init_default_subscriber();
+
+    let queue_url = std::env::var("EVENTS_QUEUE_URL").expect("EVENTS_QUEUE_URL must be set");
+    let config = load_aws_config().await;
+    let relay = OutboxRelay::new(aws_sdk_sqs::Client::new(&config), queue_url);
+
+    run(service_fn(move |event| {
+        let relay = relay.clone();
+        async move { function_handler(event, &relay).await }
+    }))
+    .await
+}
@@ -89,6 +89,7 @@ fn default_user_pool_id() -> Option<String> {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     use wiremock::matchers::header;
@@ -0,0 +1,406 @@
+use crate::shared::dynamodb::key::entity_filter;
+use crate::shared::dynamodb::{
+    current_version, from_item, migrate_to, to_item, Entity, RepoError, Scan, VersionedRepo,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::{engine::general_purpose, Engine as _};
+use futures::future::try_join_all;
+use lambda_http::tracing;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Options of an eager migration of a table.
+#[derive(Clone, Debug)]
+pub struct BackfillOptions {
+    /// Items below this `data_version` are migrated up to it.
+    pub target_version: u16,
+    /// Number of scan segments processed in parallel.
+    pub segments: u32,
+    /// Counts the items to migrate without writing them.
+    pub dry_run: bool,
+    /// Continues an interrupted run from its last reported position.
+    pub resume: Option<ResumeToken>,
+}
+
+impl BackfillOptions {
+    /// Migrates all items to the current `data_version` of `T`.
+    pub fn for_entity<T: Entity>() -> Self {
+        Self {
+            target_version: current_version::<T>(),
+            segments: 4,
+            dry_run: false,
+            resume: None,
+        }
+    }
+}
+
+/// Counts of a backfill run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackfillReport {
+    /// Items read by the scan.
+    pub scanned: usize,
+    /// Items below the target `data_version`.
+    pub outdated: usize,
+    /// Items written in the target shape (or that would be in a dry run).
+    pub migrated: usize,
+    /// Items skipped because they were written meanwhile.
+    pub conflicts: usize,
+    /// Items whose migration failed.
+    pub failed: usize,
+}
+
+impl BackfillReport {
+    fn add(&mut self, other: &BackfillReport) {
+        self.scanned += other.scanned;
+        self.outdated += other.outdated;
+        self.migrated += other.migrated;
+        self.conflicts += other.conflicts;
+        self.failed += other.failed;
+    }
+}
+
+impl Display for BackfillReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scanned: {}, outdated: {}, migrated: {}, conflicts: {}, failed: {}",
+            self.scanned, self.outdated, self.migrated, self.conflicts, self.failed
+        )
+    }
+}
+
+///
+/// Position of a backfill in each scan segment.
+///
+/// Displayed as an opaque string to pass it back via `BackfillOptions::resume`.
+///
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResumeToken {
+    segments: Vec<SegmentPosition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SegmentPosition {
+    Start,
+    /// The `LastEvaluatedKey` of the last processed page
+    After(serde_json::Value),
+    Done,
+}
+
+impl ResumeToken {
+    fn new(segments: u32) -> Self {
+        Self {
+            segments: vec![SegmentPosition::Start; segments.max(1) as usize],
+        }
+    }
+
+    /// True if all segments are scanned completely.
+    pub fn is_done(&self) -> bool {
+        self.segments.iter().all(|s| *s == SegmentPosition::Done)
+    }
+}
+
+impl Display for ResumeToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", general_purpose::URL_SAFE_NO_PAD.encode(json))
+    }
+}
+
+impl FromStr for ResumeToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let json = general_purpose::URL_SAFE_NO_PAD.decode(s)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Scans the table in parallel segments and migrates all items below the target `data_version`.
+    ///
+    /// Writes are conditional on `last_write`. Items written meanwhile are skipped and counted as conflicts.
+    /// Failing migrations are logged and counted. Failing requests abort the run.
+    /// `progress` is called after every page with the counts so far and the position to resume from.
+    ///
+    pub async fn backfill<P>(
+        &self,
+        options: BackfillOptions,
+        progress: P,
+    ) -> Result<BackfillReport, RepoError>
+    where
+        P: Fn(&BackfillReport, &ResumeToken),
+    {
+        let token = options
+            .resume
+            .clone()
+            .unwrap_or_else(|| ResumeToken::new(options.segments));
+        let total_segments = token.segments.len();
+        let state = Mutex::new((BackfillReport::default(), token));
+
+        try_join_all((0..total_segments).map(|segment| {
+            self.backfill_segment(segment, total_segments, &options, &state, &progress)
+        }))
+        .await?;
+
+        let (report, _) = state.into_inner().expect("no panic while locked");
+        Ok(report)
+    }
+
+    async fn backfill_segment<P>(
+        &self,
+        segment: usize,
+        total_segments: usize,
+        options: &BackfillOptions,
+        state: &Mutex<(BackfillReport, ResumeToken)>,
+        progress: &P,
+    ) -> Result<(), RepoError>
+    where
+        P: Fn(&BackfillReport, &ResumeToken),
+    {
+        let position = state.lock().expect("no panic while locked").1.segments[segment].clone();
+        let mut start = match position {
+            SegmentPosition::Start => None,
+            SegmentPosition::After(key) => Some(to_item(&key)?),
+            SegmentPosition::Done => return Ok(()),
+        };
+
+        let outdated = "attribute_not_exists(data_version) OR data_version < :target";
+        let (filter, mut values) = match entity_filter::<T>() {
+            Some((entity, values)) => (format!("({}) AND ({})", entity, outdated), values),
+            None => (outdated.to_string(), HashMap::new()),
+        };
+        values.insert(
+            ":target".to_string(),
+            AttributeValue::N(options.target_version.to_string()),
+        );
+
+        loop {
+            let resp = self
+                .storage
+                .scan(Scan {
+                    table: self.table_name.clone(),
+                    filter: Some(filter.clone()),
+                    values: values.clone(),
+                    start,
+                    segment: Some((segment as u32, total_segments as u32)),
+                    ..Default::default()
+                })
+                .await?;
+
+            let mut page = BackfillReport {
+                scanned: resp.scanned,
+                ..Default::default()
+            };
+            for item in resp.items {
+                page.outdated += 1;
+                match migrate_to::<T>(&item, options.target_version) {
+                    Ok(Some(_)) if options.dry_run => page.migrated += 1,
+                    Ok(Some(migrated)) => match self.put_migrated(&item, &migrated).await {
+                        Ok(()) => page.migrated += 1,
+                        Err(RepoError::Conflict) => page.conflicts += 1,
+                        Err(e) => return Err(e),
+                    },
+                    // target above the current version
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::warn!("Failed to migrate {}: {}", T::NAME, e);
+                        page.failed += 1;
+                    }
+                }
+            }
+
+            start = resp.last_key;
+            let position = match &start {
+                Some(key) => SegmentPosition::After(from_item(key.clone())?),
+                None => SegmentPosition::Done,
+            };
+
+            {
+                let mut state = state.lock().expect("no panic while locked");
+                let (report, token) = &mut *state;
+                report.add(&page);
+                token.segments[segment] = position;
+                progress(report, token);
+            }
+
+            if start.is_none() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::Migration;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Labeled {
+        id: String,
+        label: String,
+    }
+
+    impl Entity for Labeled {
+        const NAME: &'static str = "labeled";
+        const MIGRATIONS: &'static [Migration] = &[|mut value| {
+            value["label"] = value["name"].take();
+            Ok(value)
+        }];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    async fn mock_scan(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Scan"))
+            .and(body_partial_json(serde_json::json!({
+                "Segment": 0,
+                "TotalSegments": 2,
+                "ExpressionAttributeValues": {":target": {"N": "2"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [{
+                    "pk": {"S": "1"},
+                    "id": {"S": "1"},
+                    "name": {"S": "One"},
+                    "data_version": {"N": "1"},
+                    "last_write": {"N": "42"}
+                }],
+                "Count": 1,
+                "ScannedCount": 5
+            })))
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Scan"))
+            .and(body_partial_json(serde_json::json!({"Segment": 1})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [],
+                "Count": 0,
+                "ScannedCount": 3
+            })))
+            .mount(server)
+            .await;
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Labeled> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "labeled".to_string())
+    }
+
+    fn options() -> BackfillOptions {
+        BackfillOptions {
+            segments: 2,
+            ..BackfillOptions::for_entity::<Labeled>()
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_outdated_items_of_all_segments() {
+        let server = MockServer::start().await;
+        mock_scan(&server).await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ExpressionAttributeValues": {":expected": {"N": "42"}},
+                "Item": {"label": {"S": "One"}, "data_version": {"N": "2"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let last_token = Mutex::new(None);
+        let report = repo(&server)
+            .await
+            .backfill(options(), |_, token| {
+                *last_token.lock().unwrap() = Some(token.clone())
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            BackfillReport {
+                scanned: 8,
+                outdated: 1,
+                migrated: 1,
+                conflicts: 0,
+                failed: 0
+            }
+        );
+        assert!(last_token.into_inner().unwrap().unwrap().is_done());
+    }
+
+    #[tokio::test]
+    async fn dry_run_does_not_write() {
+        let server = MockServer::start().await;
+        mock_scan(&server).await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let options = BackfillOptions {
+            dry_run: true,
+            ..options()
+        };
+        let report = repo(&server)
+            .await
+            .backfill(options, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.migrated, 1);
+    }
+
+    #[tokio::test]
+    async fn resumes_after_position() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Scan"))
+            .and(body_partial_json(serde_json::json!({
+                "Segment": 1,
+                "ExclusiveStartKey": {"pk": {"S": "7"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [],
+                "Count": 0,
+                "ScannedCount": 2
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let resume = ResumeToken {
+            segments: vec![
+                SegmentPosition::Done,
+                SegmentPosition::After(serde_json::json!({"pk": "7"})),
+            ],
+        };
+        let options = BackfillOptions {
+            resume: Some(resume.to_string().parse().unwrap()),
+            ..options()
+        };
+        let report = repo(&server)
+            .await
+            .backfill(options, |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.scanned, 2);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{BatchWrite, Entity, Key, RepoError, Versioned, VersionedRepo};
+use futures::future::try_join_all;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Maximum number of keys of a `BatchGetItem` request.
+const GET_CHUNK: usize = 100;
+/// Maximum number of requests of a `BatchWriteItem` request.
+const WRITE_CHUNK: usize = 25;
+/// Attempts to process a chunk before failing with `Throttled`.
+const MAX_ATTEMPTS: u32 = 8;
+#[cfg(not(test))]
+const BASE_DELAY: Duration = Duration::from_millis(50);
+#[cfg(test)]
+const BASE_DELAY: Duration = Duration::from_millis(1);
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Reads the items of the given keys (eventually consistent).
+    ///
+    /// Returns the found items in the order of the keys. Missing and deleted keys are left out.
+    /// Keys are requested in chunks of 100. Unprocessed keys are retried with backoff.
+    ///
+    pub async fn batch_read(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<Versioned<T>>, RepoError> {
+        let keys: Vec<Key> = keys.into_iter().map(Into::into).collect();
+        let mut unique = HashSet::new();
+        let items = keys
+            .iter()
+            .filter(|key| unique.insert(*key))
+            .map(|key| self.stored_key(key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let chunks =
+            try_join_all(items.chunks(GET_CHUNK).map(|chunk| self.batch_get(chunk))).await?;
+        let mut found: HashMap<Key, Versioned<T>> = HashMap::new();
+        for item in chunks.into_iter().flatten() {
+            if let Some(entity) = self.decode_visible(item).await? {
+                found.insert(entity.data.key(), entity);
+            }
+        }
+
+        Ok(keys.iter().filter_map(|key| found.remove(key)).collect())
+    }
+
+    ///
+    /// Writes new items for the given data in chunks of 25.
+    ///
+    /// Batch writes cannot be conditional: existing items with the same key are replaced
+    /// without optimistic locking. Use it for bulk creates and imports, not for updates.
+    /// Of several entries with the same key the last one is written.
+    /// Entities with unique attributes cannot be batch written, as the values could not be claimed.
+    /// Neither can events be published with them.
+    ///
+    pub async fn batch_put(&self, data: Vec<T>) -> Result<Vec<Versioned<T>>, RepoError> {
+        self.no_events()?;
+        if !T::UNIQUE.is_empty() {
+            return Err(RepoError::Transport(
+                format!("{} has unique attributes, insert it", T::NAME).into(),
+            ));
+        }
+        let mut positions = HashMap::new();
+        let mut entities: Vec<Versioned<T>> = Vec::new();
+        for data in data {
+            let entity = Versioned::new(data);
+            match positions.get(&entity.data.key()) {
+                Some(&i) => entities[i] = entity,
+                None => {
+                    positions.insert(entity.data.key(), entities.len());
+                    entities.push(entity);
+                }
+            }
+        }
+
+        let writes = entities
+            .iter()
+            .map(|entity| Ok(BatchWrite::Put(self.entity_item(entity)?)))
+            .collect::<Result<Vec<_>, RepoError>>()?;
+        self.batch_write(writes).await?;
+
+        Ok(entities)
+    }
+
+    ///
+    /// Deletes the items of the given keys in chunks of 25.
+    ///
+    /// Unlike `delete` this is not conditional on `last_write`. Missing keys are ignored.
+    /// Events cannot be published with it.
+    ///
+    pub async fn batch_delete(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<(), RepoError> {
+        self.no_events()?;
+        let mut unique = HashSet::new();
+        let writes = keys
+            .into_iter()
+            .map(Into::into)
+            .filter(|key: &Key| unique.insert(key.clone()))
+            .map(|key| Ok(BatchWrite::Delete(self.stored_key(&key)?)))
+            .collect::<Result<_, RepoError>>()?;
+        self.batch_write(writes).await
+    }
+
+    /// Gets one chunk of keys, retrying unprocessed keys.
+    async fn batch_get(&self, keys: &[Item]) -> Result<Vec<Item>, RepoError> {
+        let mut items = Vec::new();
+        let mut pending = keys.to_vec();
+
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+
+            let (found, unprocessed) = self.storage.batch_get(&self.table_name, pending).await?;
+            items.extend(found);
+            pending = unprocessed;
+            if pending.is_empty() {
+                return Ok(items);
+            }
+        }
+
+        Err(RepoError::Throttled)
+    }
+
+    /// Writes in chunks, retrying unprocessed writes.
+    async fn batch_write(&self, writes: Vec<BatchWrite>) -> Result<(), RepoError> {
+        try_join_all(
+            writes
+                .chunks(WRITE_CHUNK)
+                .map(|chunk| self.batch_write_chunk(chunk.to_vec())),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn batch_write_chunk(&self, mut pending: Vec<BatchWrite>) -> Result<(), RepoError> {
+        for attempt in 0..MAX_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+
+            pending = self.storage.batch_write(&self.table_name, pending).await?;
+            if pending.is_empty() {
+                return Ok(());
+            }
+        }
+
+        Err(RepoError::Throttled)
+    }
+}
+
+/// Exponential backoff before the given attempt.
+fn backoff(attempt: u32) -> Duration {
+    BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt - 1))
+        .min(MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Thing {
+        id: String,
+    }
+
+    impl Entity for Thing {
+        const NAME: &'static str = "thing";
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Thing> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "things".to_string())
+    }
+
+    fn item(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "pk": {"S": id},
+            "id": {"S": id},
+            "data_version": {"N": "1"},
+            "last_write": {"N": "1"}
+        })
+    }
+
+    fn requested_keys(request: &Request) -> usize {
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        body["RequestItems"]["things"]["Keys"]
+            .as_array()
+            .unwrap()
+            .len()
+    }
+
+    #[tokio::test]
+    async fn batch_read_chunks_and_keeps_order() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchGetItem"))
+            .respond_with(|request: &Request| {
+                let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+                let items: Vec<_> = body["RequestItems"]["things"]["Keys"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|key| key["pk"]["S"].as_str().unwrap())
+                    // "0" does not exist
+                    .filter(|pk| *pk != "0")
+                    .map(item)
+                    .rev()
+                    .collect();
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"Responses": {"things": items}}))
+            })
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let pks: Vec<String> = (0..150).map(|i| i.to_string()).collect();
+        let things = repo(&server).await.batch_read(&pks).await.unwrap();
+
+        assert_eq!(things.len(), 149);
+        assert_eq!(things[0].data.id, "1");
+        assert_eq!(things[148].data.id, "149");
+        let requests = server.received_requests().await.unwrap();
+        let mut sizes: Vec<_> = requests.iter().map(requested_keys).collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![50, 100]);
+    }
+
+    #[tokio::test]
+    async fn batch_read_retries_unprocessed_keys() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchGetItem"))
+            .and(body_partial_json(serde_json::json!({
+                "RequestItems": {"things": {"Keys": [{"pk": {"S": "2"}}]}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Responses": {"things": [item("2")]}
+            })))
+            .with_priority(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchGetItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Responses": {"things": [item("1")]},
+                "UnprocessedKeys": {"things": {"Keys": [{"pk": {"S": "2"}}]}}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let things = repo(&server).await.batch_read(["1", "2"]).await.unwrap();
+        let ids: Vec<_> = things.iter().map(|t| t.data.id.as_str()).collect();
+        assert_eq!(ids, vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn batch_put_chunks_writes() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchWriteItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let things = (0..60).map(|i| Thing { id: i.to_string() }).collect();
+        let written = repo(&server).await.batch_put(things).await.unwrap();
+        assert_eq!(written.len(), 60);
+        assert_eq!(written[0].data_version, 1);
+    }
+
+    #[tokio::test]
+    async fn batch_delete_gives_up_when_throttled() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchWriteItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "UnprocessedItems": {"things": [
+                    {"DeleteRequest": {"Key": {"pk": {"S": "1"}}}}
+                ]}
+            })))
+            .expect(MAX_ATTEMPTS as u64)
+            .mount(&server)
+            .await;
+
+        let result = repo(&server).await.batch_delete(["1"]).await;
+        assert!(matches!(result, Err(RepoError::Throttled)));
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        assert_eq!(backoff(1), BASE_DELAY);
+        assert_eq!(backoff(2), BASE_DELAY * 2);
+        assert_eq!(backoff(3), BASE_DELAY * 4);
+        assert_eq!(backoff(30), MAX_DELAY);
+    }
+}
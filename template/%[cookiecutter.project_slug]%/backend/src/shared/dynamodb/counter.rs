@@ -0,0 +1,225 @@
+use crate::shared::dynamodb::key::PK;
+use crate::shared::dynamodb::storage::Item;
+#[cfg(any(debug_assertions, test))]
+use crate::shared::dynamodb::{provision_tables, LocalTable};
+use crate::shared::dynamodb::{DynamoDbStorage, RepoError, Storage, StorageError, UpdateItem};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+///
+/// At most `limit` hits per `window` and key, e.g. 10 profile updates per user and hour.
+///
+/// Windows are fixed: they start at multiples of `window` since the epoch and count from 0 again.
+/// So a burst around the start of a window gets up to `2 * limit` hits within one `window`,
+/// `limit` at the end of the last one and `limit` at the start of the next.
+/// A `limit` of 0 rejects all hits.
+///
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Name of the limit, part of the keys of its counters.
+    pub name: &'static str,
+    pub limit: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    pub const fn per_hour(name: &'static str, limit: u32) -> Self {
+        Self {
+            name,
+            limit,
+            window: Duration::from_secs(3600),
+        }
+    }
+}
+
+///
+/// Atomic counters and rate limits in a table with the key `pk` and TTL on `expires_at`.
+///
+/// Increments are `ADD` updates, so concurrent writers never lose a count.
+///
+/// ```ignore
+/// const PROFILE_UPDATES: RateLimit = RateLimit::per_hour("profile-update", 10);
+///
+/// if let Err(e) = counters.hit(&PROFILE_UPDATES, &sub).await {
+///     return repo_error_response(&e); // 429 with Retry-After
+/// }
+/// let invitations = counters.increment(&format!("invitations#{}", sub), 1).await?;
+/// ```
+///
+#[derive(Clone)]
+pub struct Counters {
+    storage: Arc<dyn Storage>,
+    table_name: String,
+}
+
+impl Counters {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self::from_storage(Arc::new(DynamoDbStorage::new(client)), table_name)
+    }
+
+    /// Counters on another storage than DynamoDB, e.g. `MemoryStorage` in tests.
+    pub fn from_storage(storage: Arc<dyn Storage>, table_name: String) -> Self {
+        Self {
+            storage,
+            table_name,
+        }
+    }
+
+    ///
+    /// Creates the counters table if it is missing, e.g. on a fresh LocalStack.
+    ///
+    /// Does nothing in release builds, there the table is created by the CDK.
+    ///
+    #[cfg(any(debug_assertions, test))]
+    pub async fn ensure_local_table(client: &Client, table_name: &str) -> Result<(), RepoError> {
+        provision_tables(client, &[LocalTable::new(table_name)]).await
+    }
+
+    #[cfg(not(any(debug_assertions, test)))]
+    pub async fn ensure_local_table(_client: &Client, _table_name: &str) -> Result<(), RepoError> {
+        Ok(())
+    }
+
+    /// Adds `by` (may be negative) to the counter `name`, starting at 0, and returns the new value.
+    pub async fn increment(&self, name: &str, by: i64) -> Result<i64, RepoError> {
+        let item = self
+            .storage
+            .update(UpdateItem {
+                table: self.table_name.clone(),
+                key: key(name),
+                update: "ADD #count :by".to_string(),
+                names: count_name(),
+                values: HashMap::from([(":by".to_string(), AttributeValue::N(by.to_string()))]),
+                ..Default::default()
+            })
+            .await?;
+        Ok(count(&item))
+    }
+
+    /// The value of the counter `name`, 0 if it was never incremented.
+    pub async fn read(&self, name: &str) -> Result<i64, RepoError> {
+        let item = self.storage.get(&self.table_name, key(name), true).await?;
+        Ok(item.as_ref().map_or(0, count))
+    }
+
+    ///
+    /// Counts a hit of `key`, e.g. the `sub` of the caller, against the limit.
+    ///
+    /// Returns the hits left in the current window. Fails with `RateLimited` if there are none,
+    /// without counting the hit. The counter of a window is removed by TTL after the next window.
+    ///
+    pub async fn hit(&self, limit: &RateLimit, key: &str) -> Result<u32, RepoError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let window = (limit.window.as_millis() as i64).max(1);
+        let window_end = (now / window + 1) * window;
+        let limited = RepoError::RateLimited {
+            retry_after: Duration::from_millis((window_end - now) as u64),
+        };
+        if limit.limit == 0 {
+            return Err(limited);
+        }
+
+        let result = self
+            .storage
+            .update(UpdateItem {
+                table: self.table_name.clone(),
+                key: self::key(&format!("{}#{}#{}", limit.name, key, now / window)),
+                update: "SET expires_at = :expires_at ADD #count :one".to_string(),
+                condition: Some("attribute_not_exists(pk) OR #count < :limit".to_string()),
+                names: count_name(),
+                values: HashMap::from([
+                    (
+                        ":expires_at".to_string(),
+                        AttributeValue::N(((window_end + window) / 1000).to_string()),
+                    ),
+                    (":one".to_string(), AttributeValue::N("1".to_string())),
+                    (
+                        ":limit".to_string(),
+                        AttributeValue::N(limit.limit.to_string()),
+                    ),
+                ]),
+            })
+            .await;
+
+        match result {
+            Ok(item) => Ok(limit.limit.saturating_sub(count(&item) as u32)),
+            Err(StorageError::ConditionFailed(_)) => Err(limited),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn key(name: &str) -> Item {
+    HashMap::from([(PK.to_string(), AttributeValue::S(name.to_string()))])
+}
+
+// COUNT is a reserved word
+fn count_name() -> HashMap<String, String> {
+    HashMap::from([("#count".to_string(), "count".to_string())])
+}
+
+fn count(item: &Item) -> i64 {
+    match item.get("count") {
+        Some(AttributeValue::N(n)) => n.parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::MemoryStorage;
+    use futures::future::join_all;
+
+    fn counters(storage: &MemoryStorage) -> Counters {
+        storage.create_table("counters", &[]);
+        Counters::from_storage(Arc::new(storage.clone()), "counters".to_string())
+    }
+
+    #[tokio::test]
+    async fn increments_atomically() {
+        let counters = counters(&MemoryStorage::new());
+
+        assert_eq!(counters.read("invitations#1").await.unwrap(), 0);
+        join_all((0..10).map(|_| counters.increment("invitations#1", 1))).await;
+        assert_eq!(counters.increment("invitations#1", -3).await.unwrap(), 7);
+        assert_eq!(counters.read("invitations#1").await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn limits_hits_per_window_and_key() {
+        let storage = MemoryStorage::new();
+        let counters = counters(&storage);
+        let limit = RateLimit::per_hour("profile-update", 2);
+
+        assert_eq!(counters.hit(&limit, "a").await.unwrap(), 1);
+        assert_eq!(counters.hit(&limit, "a").await.unwrap(), 0);
+        let Err(RepoError::RateLimited { retry_after }) = counters.hit(&limit, "a").await else {
+            panic!("hit not limited");
+        };
+        assert!(retry_after > Duration::ZERO && retry_after <= limit.window);
+        assert_eq!(counters.hit(&limit, "b").await.unwrap(), 1);
+
+        // rejected hits are not counted, counters expire
+        for item in storage.items("counters") {
+            assert!(item.contains_key("expires_at"));
+            assert!(count(&item) <= 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn limit_of_zero_rejects_all_hits() {
+        let storage = MemoryStorage::new();
+        let counters = counters(&storage);
+        let limit = RateLimit::per_hour("disabled", 0);
+
+        assert!(matches!(
+            counters.hit(&limit, "a").await,
+            Err(RepoError::RateLimited { .. })
+        ));
+        assert!(storage.items("counters").is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use lambda_http::http::StatusCode;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+///
+/// Errors of repository operations.
+///
+/// Distinguishes the failures callers react on from generic infrastructure failures.
+/// `status_code` maps them to the HTTP status an API lambda should respond with.
+///
+#[derive(Debug)]
+pub enum RepoError {
+    /// The item was written since it was read (optimistic lock failed).
+    Conflict,
+    /// An insert found an item with the same key.
+    AlreadyExists,
+    /// Another item has the value of a unique attribute, see `Unique`.
+    UniqueViolation { attribute: &'static str },
+    /// There is no item for the key.
+    NotFound,
+    /// The repository was not created `with_history`, so there are no revisions.
+    HistoryDisabled,
+    /// The item belongs to another tenant, or the repository of a tenant scoped entity has no tenant.
+    Forbidden,
+    /// DynamoDB throttled the request. Retrying later may succeed.
+    Throttled,
+    /// The caller exceeded a `RateLimit`. A new window starts after `retry_after`.
+    RateLimited { retry_after: Duration },
+    /// A page cursor was malformed, tampered with or issued for another query.
+    InvalidCursor,
+    /// The partition key starts with `UNIQUE#`, which is reserved for the sentinels of unique values.
+    InvalidKey,
+    /// The item could not be converted from or into the entity.
+    Serialization(serde_dynamo::Error),
+    /// The migration of an item from `data_version` `version` to the next failed.
+    Migration { version: u16, source: anyhow::Error },
+    /// A transaction was canceled. Holds the error of each of its items in order, `None` if it was not at fault.
+    TransactionCanceled(Vec<Option<RepoError>>),
+    /// Any other failure talking to DynamoDB, e.g. a missing table or a network error.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl RepoError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RepoError::Conflict | RepoError::AlreadyExists | RepoError::UniqueViolation { .. } => {
+                StatusCode::CONFLICT
+            }
+            RepoError::NotFound | RepoError::HistoryDisabled => StatusCode::NOT_FOUND,
+            RepoError::Forbidden => StatusCode::FORBIDDEN,
+            RepoError::Throttled | RepoError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            RepoError::InvalidCursor | RepoError::InvalidKey => StatusCode::BAD_REQUEST,
+            // the status of the first item at fault
+            RepoError::TransactionCanceled(reasons) => reasons
+                .iter()
+                .flatten()
+                .next()
+                .map_or(StatusCode::CONFLICT, RepoError::status_code),
+            RepoError::Serialization(_) | RepoError::Migration { .. } | RepoError::Transport(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Whole seconds (rounded up) after which a retry may succeed, the value of a `Retry-After` header.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            RepoError::RateLimited { retry_after } => {
+                Some(retry_after.as_millis().div_ceil(1000).max(1) as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for RepoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::Conflict => write!(f, "Item was modified concurrently"),
+            RepoError::AlreadyExists => write!(f, "Item already exists"),
+            RepoError::UniqueViolation { attribute } => {
+                write!(f, "Value of {} is already taken", attribute)
+            }
+            RepoError::NotFound => write!(f, "Item not found"),
+            RepoError::HistoryDisabled => write!(f, "History is not recorded"),
+            RepoError::Forbidden => write!(f, "Access to the item is forbidden"),
+            RepoError::Throttled => write!(f, "Request was throttled"),
+            RepoError::RateLimited { .. } => write!(
+                f,
+                "Rate limit exceeded, retry after {} seconds",
+                self.retry_after().unwrap_or_default()
+            ),
+            RepoError::InvalidCursor => write!(f, "Invalid page cursor"),
+            RepoError::InvalidKey => write!(f, "Invalid key"),
+            RepoError::Serialization(e) => write!(f, "Item serialization failed: {}", e),
+            RepoError::Migration { version, source } => {
+                write!(
+                    f,
+                    "Migration of data_version {} failed: {}",
+                    version, source
+                )
+            }
+            RepoError::TransactionCanceled(reasons) => {
+                write!(f, "Transaction was canceled")?;
+                for (i, reason) in reasons.iter().enumerate() {
+                    if let Some(reason) = reason {
+                        write!(f, ", item {}: {}", i, reason)?;
+                    }
+                }
+                Ok(())
+            }
+            RepoError::Transport(e) => write!(f, "DynamoDB request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RepoError::Serialization(e) => Some(e),
+            RepoError::Migration { source, .. } => Some(source.as_ref()),
+            RepoError::Transport(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_dynamo::Error> for RepoError {
+    fn from(e: serde_dynamo::Error) -> Self {
+        RepoError::Serialization(e)
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for RepoError
+where
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+    R: std::fmt::Debug + Send + Sync + 'static,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        match e.code() {
+            Some("ConditionalCheckFailedException") => RepoError::Conflict,
+            Some(
+                "ProvisionedThroughputExceededException"
+                | "ThrottlingException"
+                | "RequestLimitExceeded",
+            ) => RepoError::Throttled,
+            _ => RepoError::Transport(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_to_http_status() {
+        assert_eq!(RepoError::Conflict.status_code(), StatusCode::CONFLICT);
+        assert_eq!(RepoError::AlreadyExists.status_code(), StatusCode::CONFLICT);
+        assert_eq!(
+            RepoError::UniqueViolation { attribute: "email" }.status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(RepoError::NotFound.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            RepoError::HistoryDisabled.status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(RepoError::Forbidden.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            RepoError::Throttled.status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            RepoError::RateLimited {
+                retry_after: Duration::from_secs(1)
+            }
+            .status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            RepoError::InvalidCursor.status_code(),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(RepoError::InvalidKey.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            RepoError::TransactionCanceled(vec![None, Some(RepoError::NotFound)]).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            RepoError::Transport("no table".into()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
@@ -0,0 +1,1038 @@
+use crate::shared::dynamodb::storage::Item;
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+///
+/// Parses the expressions of one request against its `#name` and `:value` placeholders.
+///
+/// Like DynamoDB it rejects unknown placeholders and, with `all_used`, unused ones.
+///
+pub(super) struct Placeholders<'a> {
+    names: &'a HashMap<String, String>,
+    values: &'a Item,
+    used_names: HashSet<String>,
+    used_values: HashSet<String>,
+}
+
+/// A parsed condition, key condition or filter expression.
+#[derive(Debug)]
+pub(super) enum Condition {
+    Compare(Operand, Comparator, Operand),
+    Between(Operand, Operand, Operand),
+    In(Operand, Vec<Operand>),
+    AttributeExists(Path),
+    AttributeNotExists(Path),
+    AttributeType(Path, Operand),
+    BeginsWith(Operand, Operand),
+    Contains(Operand, Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Comparator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+pub(super) enum Operand {
+    Path(Path),
+    Value(AttributeValue),
+    Size(Path),
+}
+
+type Path = Vec<PathElement>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum PathElement {
+    Attribute(String),
+    Index(usize),
+}
+
+/// The actions of a parsed update expression.
+#[derive(Debug, Default)]
+pub(super) struct Update {
+    set: Vec<(Path, SetValue)>,
+    remove: Vec<Path>,
+    add: Vec<(Path, AttributeValue)>,
+    delete: Vec<(Path, AttributeValue)>,
+}
+
+#[derive(Debug)]
+enum SetValue {
+    Operand(UpdateOperand),
+    Plus(UpdateOperand, UpdateOperand),
+    Minus(UpdateOperand, UpdateOperand),
+}
+
+#[derive(Debug)]
+enum UpdateOperand {
+    Path(Path),
+    Value(AttributeValue),
+    IfNotExists(Path, Box<UpdateOperand>),
+    ListAppend(Box<UpdateOperand>, Box<UpdateOperand>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Name(String),
+    Value(String),
+    Number(usize),
+    Symbol(&'static str),
+}
+
+impl<'a> Placeholders<'a> {
+    pub(super) fn new(names: &'a HashMap<String, String>, values: &'a Item) -> Self {
+        Self {
+            names,
+            values,
+            used_names: HashSet::new(),
+            used_values: HashSet::new(),
+        }
+    }
+
+    pub(super) fn condition(&mut self, expression: &str) -> Result<Condition, String> {
+        let mut parser = Parser::new(expression, self)?;
+        let condition = parser.or()?;
+        parser.end()?;
+        Ok(condition)
+    }
+
+    pub(super) fn update(&mut self, expression: &str) -> Result<Update, String> {
+        let mut parser = Parser::new(expression, self)?;
+        let update = parser.update()?;
+        parser.end()?;
+        Ok(update)
+    }
+
+    /// Fails on placeholders none of the parsed expressions used.
+    pub(super) fn all_used(&self) -> Result<(), String> {
+        if let Some(name) = self.names.keys().find(|n| !self.used_names.contains(*n)) {
+            return Err(format!(
+                "Value provided in ExpressionAttributeNames unused in expressions: {}",
+                name
+            ));
+        }
+        if let Some(value) = self.values.keys().find(|v| !self.used_values.contains(*v)) {
+            return Err(format!(
+                "Value provided in ExpressionAttributeValues unused in expressions: {}",
+                value
+            ));
+        }
+        Ok(())
+    }
+}
+
+struct Parser<'p, 'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    placeholders: &'p mut Placeholders<'a>,
+}
+
+impl<'p, 'a> Parser<'p, 'a> {
+    fn new(expression: &str, placeholders: &'p mut Placeholders<'a>) -> Result<Self, String> {
+        Ok(Self {
+            tokens: tokenize(expression)?,
+            position: 0,
+            placeholders,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word))
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    /// True if the next tokens are a call of the function.
+    fn is_call(&self, function: &str) -> bool {
+        self.is_word(function)
+            && matches!(self.tokens.get(self.position + 1), Some(Token::Symbol("(")))
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Symbol(s)) if s == symbol => Ok(()),
+            token => Err(format!(
+                "Syntax error: expected {} but found {:?}",
+                symbol, token
+            )),
+        }
+    }
+
+    fn end(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("Syntax error: unexpected {:?}", token)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.is_word("OR") {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.not()?;
+        while self.is_word("AND") {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, String> {
+        if self.is_word("NOT") {
+            self.next();
+            return Ok(Condition::Not(Box::new(self.not()?)));
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Condition, String> {
+        if self.is_symbol("(") {
+            self.next();
+            let condition = self.or()?;
+            self.expect(")")?;
+            return Ok(condition);
+        }
+        for function in [
+            "attribute_exists",
+            "attribute_not_exists",
+            "attribute_type",
+            "begins_with",
+            "contains",
+        ] {
+            if self.is_call(function) {
+                return self.function(function);
+            }
+        }
+
+        let left = self.operand()?;
+        if self.is_word("BETWEEN") {
+            self.next();
+            let from = self.operand()?;
+            if !self.is_word("AND") {
+                return Err("Syntax error: expected AND of BETWEEN".to_string());
+            }
+            self.next();
+            return Ok(Condition::Between(left, from, self.operand()?));
+        }
+        if self.is_word("IN") {
+            self.next();
+            self.expect("(")?;
+            let mut candidates = vec![self.operand()?];
+            while self.is_symbol(",") {
+                self.next();
+                candidates.push(self.operand()?);
+            }
+            self.expect(")")?;
+            return Ok(Condition::In(left, candidates));
+        }
+        let comparator = match self.next() {
+            Some(Token::Symbol("=")) => Comparator::Eq,
+            Some(Token::Symbol("<>")) => Comparator::Ne,
+            Some(Token::Symbol("<")) => Comparator::Lt,
+            Some(Token::Symbol("<=")) => Comparator::Le,
+            Some(Token::Symbol(">")) => Comparator::Gt,
+            Some(Token::Symbol(">=")) => Comparator::Ge,
+            token => {
+                return Err(format!(
+                    "Syntax error: expected a comparator but found {:?}",
+                    token
+                ))
+            }
+        };
+        Ok(Condition::Compare(left, comparator, self.operand()?))
+    }
+
+    fn function(&mut self, function: &str) -> Result<Condition, String> {
+        self.next();
+        self.expect("(")?;
+        let condition = match function {
+            "attribute_exists" => Condition::AttributeExists(self.path()?),
+            "attribute_not_exists" => Condition::AttributeNotExists(self.path()?),
+            "attribute_type" => {
+                let path = self.path()?;
+                self.expect(",")?;
+                Condition::AttributeType(path, self.operand()?)
+            }
+            "begins_with" | "contains" => {
+                let left = self.operand()?;
+                self.expect(",")?;
+                let right = self.operand()?;
+                if function == "begins_with" {
+                    Condition::BeginsWith(left, right)
+                } else {
+                    Condition::Contains(left, right)
+                }
+            }
+            _ => unreachable!("only known functions are parsed"),
+        };
+        self.expect(")")?;
+        Ok(condition)
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        if self.is_call("size") {
+            self.next();
+            self.expect("(")?;
+            let path = self.path()?;
+            self.expect(")")?;
+            return Ok(Operand::Size(path));
+        }
+        if let Some(Token::Value(_)) = self.peek() {
+            return Ok(Operand::Value(self.value()?));
+        }
+        Ok(Operand::Path(self.path()?))
+    }
+
+    fn value(&mut self) -> Result<AttributeValue, String> {
+        match self.next() {
+            Some(Token::Value(placeholder)) => {
+                let value = self
+                    .placeholders
+                    .values
+                    .get(&placeholder)
+                    .cloned()
+                    .ok_or_else(|| {
+                        format!(
+                            "An expression attribute value used in expression is not defined: {}",
+                            placeholder
+                        )
+                    })?;
+                self.placeholders.used_values.insert(placeholder);
+                Ok(value)
+            }
+            token => Err(format!(
+                "Syntax error: expected a value but found {:?}",
+                token
+            )),
+        }
+    }
+
+    fn path(&mut self) -> Result<Path, String> {
+        let mut path = vec![PathElement::Attribute(self.attribute()?)];
+        loop {
+            if self.is_symbol(".") {
+                self.next();
+                path.push(PathElement::Attribute(self.attribute()?));
+            } else if self.is_symbol("[") {
+                self.next();
+                match self.next() {
+                    Some(Token::Number(index)) => path.push(PathElement::Index(index)),
+                    token => {
+                        return Err(format!(
+                            "Syntax error: expected an index but found {:?}",
+                            token
+                        ))
+                    }
+                }
+                self.expect("]")?;
+            } else {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn attribute(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Word(word)) => Ok(word),
+            Some(Token::Name(placeholder)) => {
+                let name = self
+                    .placeholders
+                    .names
+                    .get(&placeholder)
+                    .cloned()
+                    .ok_or_else(|| {
+                        format!(
+                        "An expression attribute name used in the document path is not defined: {}",
+                        placeholder
+                    )
+                    })?;
+                self.placeholders.used_names.insert(placeholder);
+                Ok(name)
+            }
+            token => Err(format!(
+                "Syntax error: expected an attribute but found {:?}",
+                token
+            )),
+        }
+    }
+
+    fn update(&mut self) -> Result<Update, String> {
+        let mut update = Update::default();
+        let mut clauses = HashSet::new();
+        while let Some(token) = self.next() {
+            let clause = match token {
+                Token::Word(word) => word.to_ascii_uppercase(),
+                token => {
+                    return Err(format!(
+                        "Syntax error: expected a clause but found {:?}",
+                        token
+                    ))
+                }
+            };
+            if !clauses.insert(clause.clone()) {
+                return Err(format!("The {} section can only be used once", clause));
+            }
+            loop {
+                match clause.as_str() {
+                    "SET" => {
+                        let path = self.path()?;
+                        self.expect("=")?;
+                        let value = self.set_value()?;
+                        update.set.push((path, value));
+                    }
+                    "REMOVE" => update.remove.push(self.path()?),
+                    "ADD" => {
+                        let path = self.path()?;
+                        update.add.push((path, self.value()?));
+                    }
+                    "DELETE" => {
+                        let path = self.path()?;
+                        update.delete.push((path, self.value()?));
+                    }
+                    _ => return Err(format!("Syntax error: unknown clause {}", clause)),
+                }
+                if !self.is_symbol(",") {
+                    break;
+                }
+                self.next();
+            }
+        }
+        Ok(update)
+    }
+
+    fn set_value(&mut self) -> Result<SetValue, String> {
+        let left = self.update_operand()?;
+        if self.is_symbol("+") {
+            self.next();
+            return Ok(SetValue::Plus(left, self.update_operand()?));
+        }
+        if self.is_symbol("-") {
+            self.next();
+            return Ok(SetValue::Minus(left, self.update_operand()?));
+        }
+        Ok(SetValue::Operand(left))
+    }
+
+    fn update_operand(&mut self) -> Result<UpdateOperand, String> {
+        if self.is_call("if_not_exists") {
+            self.next();
+            self.expect("(")?;
+            let path = self.path()?;
+            self.expect(",")?;
+            let default = self.update_operand()?;
+            self.expect(")")?;
+            return Ok(UpdateOperand::IfNotExists(path, Box::new(default)));
+        }
+        if self.is_call("list_append") {
+            self.next();
+            self.expect("(")?;
+            let first = self.update_operand()?;
+            self.expect(",")?;
+            let second = self.update_operand()?;
+            self.expect(")")?;
+            return Ok(UpdateOperand::ListAppend(Box::new(first), Box::new(second)));
+        }
+        if let Some(Token::Value(_)) = self.peek() {
+            return Ok(UpdateOperand::Value(self.value()?));
+        }
+        Ok(UpdateOperand::Path(self.path()?))
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    let word_end = |start: usize| {
+        expression[start..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map_or(expression.len(), |end| start + end)
+    };
+
+    while let Some(&(i, c)) = chars.peek() {
+        let token =
+            match c {
+                c if c.is_whitespace() => {
+                    chars.next();
+                    continue;
+                }
+                '#' | ':' => {
+                    let end = word_end(i + 1);
+                    if end == i + 1 {
+                        return Err(format!("Syntax error: empty placeholder at {}", i));
+                    }
+                    let placeholder = expression[i..end].to_string();
+                    (i..end).for_each(|_| {
+                        chars.next();
+                    });
+                    if c == '#' {
+                        Token::Name(placeholder)
+                    } else {
+                        Token::Value(placeholder)
+                    }
+                }
+                c if c.is_ascii_digit() => {
+                    let end = word_end(i);
+                    (i..end).for_each(|_| {
+                        chars.next();
+                    });
+                    Token::Number(expression[i..end].parse().map_err(|_| {
+                        format!("Syntax error: invalid index {}", &expression[i..end])
+                    })?)
+                }
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    let end = word_end(i);
+                    (i..end).for_each(|_| {
+                        chars.next();
+                    });
+                    Token::Word(expression[i..end].to_string())
+                }
+                _ => {
+                    let symbol = [
+                        "<>", "<=", ">=", "(", ")", ",", ".", "[", "]", "=", "<", ">", "+", "-",
+                    ]
+                    .into_iter()
+                    .find(|symbol| expression[i..].starts_with(symbol))
+                    .ok_or_else(|| format!("Syntax error: unexpected '{}' at {}", c, i))?;
+                    (0..symbol.len()).for_each(|_| {
+                        chars.next();
+                    });
+                    Token::Symbol(symbol)
+                }
+            };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+impl Condition {
+    /// Evaluates the condition on the item, empty if there is none.
+    pub(super) fn evaluate(&self, item: &Item) -> bool {
+        match self {
+            Condition::Compare(left, comparator, right) => {
+                let (Some(left), Some(right)) = (left.resolve(item), right.resolve(item)) else {
+                    return false;
+                };
+                match comparator {
+                    Comparator::Eq => equals(&left, &right),
+                    Comparator::Ne => !equals(&left, &right),
+                    Comparator::Lt => compare(&left, &right) == Some(Ordering::Less),
+                    Comparator::Le => matches!(
+                        compare(&left, &right),
+                        Some(Ordering::Less | Ordering::Equal)
+                    ),
+                    Comparator::Gt => compare(&left, &right) == Some(Ordering::Greater),
+                    Comparator::Ge => matches!(
+                        compare(&left, &right),
+                        Some(Ordering::Greater | Ordering::Equal)
+                    ),
+                }
+            }
+            Condition::Between(value, from, to) => {
+                let (Some(value), Some(from), Some(to)) =
+                    (value.resolve(item), from.resolve(item), to.resolve(item))
+                else {
+                    return false;
+                };
+                matches!(
+                    compare(&value, &from),
+                    Some(Ordering::Greater | Ordering::Equal)
+                ) && matches!(compare(&value, &to), Some(Ordering::Less | Ordering::Equal))
+            }
+            Condition::In(value, candidates) => value.resolve(item).is_some_and(|value| {
+                candidates
+                    .iter()
+                    .filter_map(|candidate| candidate.resolve(item))
+                    .any(|candidate| equals(&value, &candidate))
+            }),
+            Condition::AttributeExists(path) => get(item, path).is_some(),
+            Condition::AttributeNotExists(path) => get(item, path).is_none(),
+            Condition::AttributeType(path, expected) => {
+                match (get(item, path), expected.resolve(item)) {
+                    (Some(value), Some(AttributeValue::S(expected))) => {
+                        type_name(value) == expected
+                    }
+                    _ => false,
+                }
+            }
+            Condition::BeginsWith(value, prefix) => {
+                match (value.resolve(item), prefix.resolve(item)) {
+                    (Some(AttributeValue::S(value)), Some(AttributeValue::S(prefix))) => {
+                        value.starts_with(&prefix)
+                    }
+                    (Some(AttributeValue::B(value)), Some(AttributeValue::B(prefix))) => {
+                        value.as_ref().starts_with(prefix.as_ref())
+                    }
+                    _ => false,
+                }
+            }
+            Condition::Contains(value, element) => {
+                let (Some(value), Some(element)) = (value.resolve(item), element.resolve(item))
+                else {
+                    return false;
+                };
+                match (value, element) {
+                    (AttributeValue::S(value), AttributeValue::S(element)) => {
+                        value.contains(&element)
+                    }
+                    (AttributeValue::Ss(set), AttributeValue::S(element)) => set.contains(&element),
+                    (AttributeValue::Ns(set), AttributeValue::N(element)) => set
+                        .iter()
+                        .any(|n| compare_numbers(n, &element) == Some(Ordering::Equal)),
+                    (AttributeValue::Bs(set), AttributeValue::B(element)) => set.contains(&element),
+                    (AttributeValue::L(list), element) => {
+                        list.iter().any(|value| equals(value, &element))
+                    }
+                    _ => false,
+                }
+            }
+            Condition::And(left, right) => left.evaluate(item) && right.evaluate(item),
+            Condition::Or(left, right) => left.evaluate(item) || right.evaluate(item),
+            Condition::Not(condition) => !condition.evaluate(item),
+        }
+    }
+}
+
+impl Operand {
+    fn resolve(&self, item: &Item) -> Option<AttributeValue> {
+        match self {
+            Operand::Path(path) => get(item, path).cloned(),
+            Operand::Value(value) => Some(value.clone()),
+            Operand::Size(path) => {
+                let size = match get(item, path)? {
+                    AttributeValue::S(s) => s.chars().count(),
+                    AttributeValue::B(b) => b.as_ref().len(),
+                    AttributeValue::Ss(set) => set.len(),
+                    AttributeValue::Ns(set) => set.len(),
+                    AttributeValue::Bs(set) => set.len(),
+                    AttributeValue::L(list) => list.len(),
+                    AttributeValue::M(map) => map.len(),
+                    _ => return None,
+                };
+                Some(AttributeValue::N(size.to_string()))
+            }
+        }
+    }
+}
+
+impl Update {
+    ///
+    /// Applies the actions to the item, empty if there is none.
+    ///
+    /// Like DynamoDB all values are computed from the item before the update.
+    ///
+    pub(super) fn apply(&self, item: &mut Item) -> Result<(), String> {
+        let before = item.clone();
+        let mut values = Vec::with_capacity(self.set.len());
+        for (path, value) in &self.set {
+            values.push((path, value.resolve(&before)?));
+        }
+
+        for (path, value) in values {
+            set(item, path, value)?;
+        }
+        for path in &self.remove {
+            remove(item, path);
+        }
+        for (path, value) in &self.add {
+            let added = match (get(item, path), value) {
+                (None, value) => value.clone(),
+                (Some(AttributeValue::N(current)), AttributeValue::N(value)) => {
+                    AttributeValue::N(add_numbers(current, value, false)?)
+                }
+                (Some(current), value) => {
+                    set_union(current, value).ok_or_else(incorrect_operand)?
+                }
+            };
+            set(item, path, added)?;
+        }
+        for (path, value) in &self.delete {
+            let Some(current) = get(item, path) else {
+                continue;
+            };
+            match set_difference(current, value).ok_or_else(incorrect_operand)? {
+                Some(remaining) => set(item, path, remaining)?,
+                None => remove(item, path),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SetValue {
+    fn resolve(&self, item: &Item) -> Result<AttributeValue, String> {
+        match self {
+            SetValue::Operand(operand) => operand.resolve(item),
+            SetValue::Plus(left, right) | SetValue::Minus(left, right) => {
+                match (left.resolve(item)?, right.resolve(item)?) {
+                    (AttributeValue::N(left), AttributeValue::N(right)) => Ok(AttributeValue::N(
+                        add_numbers(&left, &right, matches!(self, SetValue::Minus(..)))?,
+                    )),
+                    _ => Err(incorrect_operand()),
+                }
+            }
+        }
+    }
+}
+
+impl UpdateOperand {
+    fn resolve(&self, item: &Item) -> Result<AttributeValue, String> {
+        match self {
+            UpdateOperand::Path(path) => get(item, path).cloned().ok_or_else(|| {
+                "The provided expression refers to an attribute that does not exist in the item"
+                    .to_string()
+            }),
+            UpdateOperand::Value(value) => Ok(value.clone()),
+            UpdateOperand::IfNotExists(path, default) => match get(item, path) {
+                Some(value) => Ok(value.clone()),
+                None => default.resolve(item),
+            },
+            UpdateOperand::ListAppend(first, second) => {
+                match (first.resolve(item)?, second.resolve(item)?) {
+                    (AttributeValue::L(mut first), AttributeValue::L(second)) => {
+                        first.extend(second);
+                        Ok(AttributeValue::L(first))
+                    }
+                    _ => Err(incorrect_operand()),
+                }
+            }
+        }
+    }
+}
+
+fn incorrect_operand() -> String {
+    "An operand in the update expression has an incorrect data type".to_string()
+}
+
+fn get<'i>(item: &'i Item, path: &[PathElement]) -> Option<&'i AttributeValue> {
+    let (PathElement::Attribute(first), rest) = path.split_first()? else {
+        return None;
+    };
+    let mut value = item.get(first)?;
+    for element in rest {
+        value = match (element, value) {
+            (PathElement::Attribute(name), AttributeValue::M(map)) => map.get(name)?,
+            (PathElement::Index(index), AttributeValue::L(list)) => list.get(*index)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// The parent of the last path element, which must exist.
+fn parent<'i>(item: &'i mut Item, path: &[PathElement]) -> Result<Parent<'i>, String> {
+    let invalid =
+        || "The document path provided in the update expression is invalid for update".to_string();
+    let Some((PathElement::Attribute(first), rest)) = path.split_first() else {
+        return Err(invalid());
+    };
+    if rest.is_empty() {
+        return Ok(Parent::Item(item, first.clone()));
+    }
+    let mut value = item.get_mut(first).ok_or_else(invalid)?;
+    for element in &rest[..rest.len() - 1] {
+        value = match (element, value) {
+            (PathElement::Attribute(name), AttributeValue::M(map)) => {
+                map.get_mut(name).ok_or_else(invalid)?
+            }
+            (PathElement::Index(index), AttributeValue::L(list)) => {
+                list.get_mut(*index).ok_or_else(invalid)?
+            }
+            _ => return Err(invalid()),
+        };
+    }
+    match (rest.last().expect("rest is not empty"), value) {
+        (PathElement::Attribute(name), AttributeValue::M(map)) => {
+            Ok(Parent::Map(map, name.clone()))
+        }
+        (PathElement::Index(index), AttributeValue::L(list)) => Ok(Parent::List(list, *index)),
+        _ => Err(invalid()),
+    }
+}
+
+enum Parent<'i> {
+    Item(&'i mut Item, String),
+    Map(&'i mut HashMap<String, AttributeValue>, String),
+    List(&'i mut Vec<AttributeValue>, usize),
+}
+
+fn set(item: &mut Item, path: &[PathElement], value: AttributeValue) -> Result<(), String> {
+    match parent(item, path)? {
+        Parent::Item(map, name) | Parent::Map(map, name) => {
+            map.insert(name, value);
+        }
+        // like DynamoDB, indexes past the end append
+        Parent::List(list, index) if index < list.len() => list[index] = value,
+        Parent::List(list, _) => list.push(value),
+    }
+    Ok(())
+}
+
+fn remove(item: &mut Item, path: &[PathElement]) {
+    match parent(item, path) {
+        Ok(Parent::Item(map, name) | Parent::Map(map, name)) => {
+            map.remove(&name);
+        }
+        Ok(Parent::List(list, index)) if index < list.len() => {
+            list.remove(index);
+        }
+        _ => {}
+    }
+}
+
+fn equals(left: &AttributeValue, right: &AttributeValue) -> bool {
+    match (left, right) {
+        (AttributeValue::N(left), AttributeValue::N(right)) => {
+            compare_numbers(left, right) == Some(Ordering::Equal)
+        }
+        (AttributeValue::Ss(left), AttributeValue::Ss(right)) => same_elements(left, right),
+        (AttributeValue::Ns(left), AttributeValue::Ns(right)) => same_elements(left, right),
+        (AttributeValue::Bs(left), AttributeValue::Bs(right)) => {
+            left.len() == right.len() && left.iter().all(|b| right.contains(b))
+        }
+        _ => left == right,
+    }
+}
+
+fn same_elements(left: &[String], right: &[String]) -> bool {
+    let left: HashSet<_> = left.iter().collect();
+    let right: HashSet<_> = right.iter().collect();
+    left == right
+}
+
+/// Order of values of the same scalar type, `None` for other types.
+pub(super) fn compare(left: &AttributeValue, right: &AttributeValue) -> Option<Ordering> {
+    match (left, right) {
+        (AttributeValue::N(left), AttributeValue::N(right)) => compare_numbers(left, right),
+        (AttributeValue::S(left), AttributeValue::S(right)) => Some(left.cmp(right)),
+        (AttributeValue::B(left), AttributeValue::B(right)) => {
+            Some(left.as_ref().cmp(right.as_ref()))
+        }
+        _ => None,
+    }
+}
+
+fn compare_numbers(left: &str, right: &str) -> Option<Ordering> {
+    match (left.parse::<i128>(), right.parse::<i128>()) {
+        (Ok(left), Ok(right)) => Some(left.cmp(&right)),
+        _ => left
+            .parse::<f64>()
+            .ok()?
+            .partial_cmp(&right.parse::<f64>().ok()?),
+    }
+}
+
+fn add_numbers(left: &str, right: &str, subtract: bool) -> Result<String, String> {
+    let invalid = || "A value provided cannot be converted into a number".to_string();
+    match (left.parse::<i128>(), right.parse::<i128>()) {
+        (Ok(left), Ok(right)) if subtract => Ok((left - right).to_string()),
+        (Ok(left), Ok(right)) => Ok((left + right).to_string()),
+        _ => {
+            let left: f64 = left.parse().map_err(|_| invalid())?;
+            let right: f64 = right.parse().map_err(|_| invalid())?;
+            Ok(if subtract { left - right } else { left + right }.to_string())
+        }
+    }
+}
+
+fn set_union(current: &AttributeValue, added: &AttributeValue) -> Option<AttributeValue> {
+    fn union<V: Clone + PartialEq>(current: &[V], added: &[V]) -> Vec<V> {
+        let mut union = current.to_vec();
+        union.extend(added.iter().filter(|v| !current.contains(v)).cloned());
+        union
+    }
+    match (current, added) {
+        (AttributeValue::Ss(current), AttributeValue::Ss(added)) => {
+            Some(AttributeValue::Ss(union(current, added)))
+        }
+        (AttributeValue::Ns(current), AttributeValue::Ns(added)) => {
+            Some(AttributeValue::Ns(union(current, added)))
+        }
+        (AttributeValue::Bs(current), AttributeValue::Bs(added)) => {
+            Some(AttributeValue::Bs(union(current, added)))
+        }
+        _ => None,
+    }
+}
+
+/// The remaining set, `Some(None)` if it is empty.
+fn set_difference(
+    current: &AttributeValue,
+    removed: &AttributeValue,
+) -> Option<Option<AttributeValue>> {
+    fn difference<V: Clone + PartialEq>(current: &[V], removed: &[V]) -> Vec<V> {
+        current
+            .iter()
+            .filter(|v| !removed.contains(v))
+            .cloned()
+            .collect()
+    }
+    let remaining = match (current, removed) {
+        (AttributeValue::Ss(current), AttributeValue::Ss(removed)) => {
+            AttributeValue::Ss(difference(current, removed))
+        }
+        (AttributeValue::Ns(current), AttributeValue::Ns(removed)) => {
+            AttributeValue::Ns(difference(current, removed))
+        }
+        (AttributeValue::Bs(current), AttributeValue::Bs(removed)) => {
+            AttributeValue::Bs(difference(current, removed))
+        }
+        _ => return None,
+    };
+    let empty = matches!(&remaining, AttributeValue::Ss(s) | AttributeValue::Ns(s) if s.is_empty())
+        || matches!(&remaining, AttributeValue::Bs(s) if s.is_empty());
+    Some((!empty).then_some(remaining))
+}
+
+fn type_name(value: &AttributeValue) -> &'static str {
+    match value {
+        AttributeValue::S(_) => "S",
+        AttributeValue::N(_) => "N",
+        AttributeValue::B(_) => "B",
+        AttributeValue::Ss(_) => "SS",
+        AttributeValue::Ns(_) => "NS",
+        AttributeValue::Bs(_) => "BS",
+        AttributeValue::M(_) => "M",
+        AttributeValue::L(_) => "L",
+        AttributeValue::Null(_) => "NULL",
+        AttributeValue::Bool(_) => "BOOL",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Item {
+        HashMap::from([
+            ("pk".to_string(), AttributeValue::S("USER#1".to_string())),
+            (
+                "last_write".to_string(),
+                AttributeValue::N("42".to_string()),
+            ),
+            (
+                "tags".to_string(),
+                AttributeValue::L(vec![AttributeValue::S("a".to_string())]),
+            ),
+        ])
+    }
+
+    fn evaluate(expression: &str, values: &[(&str, AttributeValue)]) -> bool {
+        let names = HashMap::from([("#pk".to_string(), "pk".to_string())]);
+        let values: Item = values
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        let mut placeholders = Placeholders::new(&names, &values);
+        placeholders
+            .condition(expression)
+            .unwrap()
+            .evaluate(&item())
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let n = |n: &str| AttributeValue::N(n.to_string());
+        let s = |s: &str| AttributeValue::S(s.to_string());
+
+        assert!(evaluate("last_write = :v", &[(":v", n("42.0"))]));
+        assert!(evaluate("last_write < :v", &[(":v", n("100"))]));
+        assert!(!evaluate("last_write < :v", &[(":v", s("100"))]));
+        assert!(evaluate(
+            "attribute_exists(pk) AND NOT attribute_exists(sk)",
+            &[]
+        ));
+        assert!(evaluate(
+            "attribute_not_exists(sk) OR last_write = :v AND begins_with(#pk, :p)",
+            &[(":v", n("1")), (":p", s("ORDER#"))]
+        ));
+        assert!(!evaluate(
+            "(attribute_not_exists(sk) OR last_write = :v) AND begins_with(#pk, :p)",
+            &[(":v", n("1")), (":p", s("ORDER#"))]
+        ));
+        assert!(evaluate(
+            "last_write BETWEEN :a AND :b AND size(tags) IN (:one, :two)",
+            &[
+                (":a", n("1")),
+                (":b", n("42")),
+                (":one", n("1")),
+                (":two", n("2"))
+            ]
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_and_unused_placeholders() {
+        let names = HashMap::new();
+        let values = HashMap::from([(":unused".to_string(), AttributeValue::N("1".to_string()))]);
+        let mut placeholders = Placeholders::new(&names, &values);
+        assert!(placeholders.condition("#missing = :unused").is_err());
+
+        let mut placeholders = Placeholders::new(&names, &values);
+        placeholders.condition("attribute_exists(pk)").unwrap();
+        assert!(placeholders.all_used().is_err());
+    }
+
+    #[test]
+    fn applies_updates_on_the_previous_item() {
+        let names = HashMap::from([("#tags".to_string(), "tags".to_string())]);
+        let values = HashMap::from([
+            (":one".to_string(), AttributeValue::N("1".to_string())),
+            (
+                ":b".to_string(),
+                AttributeValue::L(vec![AttributeValue::S("b".to_string())]),
+            ),
+            (":empty".to_string(), AttributeValue::L(vec![])),
+        ]);
+        let mut placeholders = Placeholders::new(&names, &values);
+        let update = placeholders
+            .update(
+                "SET last_write = last_write + :one, #tags = list_append(if_not_exists(#tags, :empty), :b), \
+                 logins = if_not_exists(logins, last_write) REMOVE pk ADD visits :one",
+            )
+            .unwrap();
+        placeholders.all_used().unwrap();
+
+        let mut item = item();
+        update.apply(&mut item).unwrap();
+        assert_eq!(item["last_write"], AttributeValue::N("43".to_string()));
+        assert_eq!(item["logins"], AttributeValue::N("42".to_string()));
+        assert_eq!(item["visits"], AttributeValue::N("1".to_string()));
+        assert_eq!(
+            item["tags"],
+            AttributeValue::L(vec![
+                AttributeValue::S("a".to_string()),
+                AttributeValue::S("b".to_string())
+            ])
+        );
+        assert!(!item.contains_key("pk"));
+    }
+}
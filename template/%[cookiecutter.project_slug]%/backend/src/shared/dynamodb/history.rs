@@ -0,0 +1,349 @@
+use crate::shared::dynamodb::key::{PK, SK};
+use crate::shared::dynamodb::migration::{decode, item_last_write};
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{
+    Entity, Key, PageRequest, Put, Query, RepoError, TransactWrite, Versioned, VersionedRepo,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::HashMap;
+
+/// Write that replaced a revision.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteOperation {
+    Update,
+    Patch,
+    Delete,
+}
+
+impl WriteOperation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            WriteOperation::Update => "update",
+            WriteOperation::Patch => "patch",
+            WriteOperation::Delete => "delete",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "update" => Some(WriteOperation::Update),
+            "patch" => Some(WriteOperation::Patch),
+            "delete" => Some(WriteOperation::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// An entity as it was before a write, recorded in the history table.
+#[derive(Clone, Debug)]
+pub struct Revision<T> {
+    /// The entity before the write, migrated to the current shape.
+    pub previous: Versioned<T>,
+    pub operation: WriteOperation,
+    /// `sub` of the user who wrote, see `VersionedRepo::acting_as`.
+    pub actor: Option<String>,
+    /// Epoch millis of the write. The `last_write` of the new entity for updates and patches.
+    pub changed_at: i64,
+}
+
+/// A page of revisions, newest first, and the opaque cursor to request the next one.
+#[derive(Clone, Debug)]
+pub struct Revisions<T> {
+    pub items: Vec<Revision<T>>,
+    /// `None` on the last page.
+    pub next: Option<String>,
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Records the previous entity of every update, patch and delete in the history table.
+    ///
+    /// The history record is written in one transaction with the item, so every change has one.
+    /// The history table has the keys `pk` and `sk` and is shared by all entities.
+    /// Inserts, batch writes and `Transaction`s are not recorded.
+    ///
+    pub fn with_history(mut self, history_table: impl Into<String>) -> Self {
+        self.history_table = Some(history_table.into());
+        self
+    }
+
+    /// The repository recording `sub` as actor of its writes in the history, e.g. the caller of a lambda.
+    pub fn acting_as(&self, sub: impl Into<String>) -> Self {
+        let mut repo = self.clone();
+        repo.actor = Some(sub.into());
+        repo
+    }
+
+    /// Lists a page of the revisions of an entity, newest first. Fails with `HistoryDisabled` without `with_history`.
+    pub async fn revisions(
+        &self,
+        key: impl Into<Key>,
+        page: PageRequest,
+    ) -> Result<Revisions<T>, RepoError> {
+        let Some(history_table) = &self.history_table else {
+            return Err(RepoError::HistoryDisabled);
+        };
+
+        let partition = history_partition::<T>(&self.scoped(key.into())?);
+        let query = Query {
+            table: history_table.clone(),
+            key_condition: "#pk = :pk".to_string(),
+            names: HashMap::from([("#pk".to_string(), PK.to_string())]),
+            values: HashMap::from([(":pk".to_string(), AttributeValue::S(partition.clone()))]),
+            descending: true,
+            ..Default::default()
+        };
+        // a cursor is only valid for the query it was issued for
+        let context = format!("{}/{}", history_table, partition);
+
+        let (items, next) = self.query_items(query, &context, page).await?;
+        let items = items.into_iter().map(revision).collect::<Result<_, _>>()?;
+        Ok(Revisions { items, next })
+    }
+
+    /// The put of the history record of the `previous` item.
+    pub(super) fn history_record(
+        &self,
+        history_table: &str,
+        previous: &Item,
+        operation: WriteOperation,
+        changed_at: i64,
+    ) -> Result<TransactWrite, RepoError> {
+        let Some(key) = Key::from_item::<T>(previous) else {
+            return Err(RepoError::Transport(
+                format!("The item is no {}", T::NAME).into(),
+            ));
+        };
+        let last_write = item_last_write(previous);
+
+        let mut record = HashMap::from([
+            (
+                PK.to_string(),
+                AttributeValue::S(history_partition::<T>(&key)),
+            ),
+            // unique per item, as every write increases last_write
+            (
+                SK.to_string(),
+                AttributeValue::S(format!("{:020}", last_write)),
+            ),
+            ("item".to_string(), AttributeValue::M(previous.clone())),
+            (
+                "operation".to_string(),
+                AttributeValue::S(operation.as_str().to_string()),
+            ),
+            (
+                "changed_at".to_string(),
+                AttributeValue::N(changed_at.to_string()),
+            ),
+        ]);
+        if let Some(actor) = &self.actor {
+            record.insert("actor".to_string(), AttributeValue::S(actor.clone()));
+        }
+
+        Ok(TransactWrite::Put(Put {
+            table: history_table.to_string(),
+            item: record,
+            ..Default::default()
+        }))
+    }
+
+    /// Reads the item as stored, to record it in the history.
+    pub(super) async fn read_previous(&self, key: &Key) -> Result<Option<Item>, RepoError> {
+        Ok(self
+            .storage
+            .get(&self.table_name, self.stored_key(key)?, true)
+            .await?)
+    }
+
+    ///
+    /// Reads the item as stored if a write needs it for the history or to release unique values.
+    ///
+    /// Fails with `NotFound` if there is no item.
+    ///
+    pub(super) async fn previous_if_needed(&self, key: &Key) -> Result<Option<Item>, RepoError> {
+        if self.history_table.is_none() && T::UNIQUE.is_empty() {
+            return Ok(None);
+        }
+        match self.read_previous(key).await? {
+            Some(previous) => Ok(Some(previous)),
+            None => Err(RepoError::NotFound),
+        }
+    }
+}
+
+/// The partition of the revisions of an entity in the history table.
+fn history_partition<T: Entity>(key: &Key) -> String {
+    match &key.sk {
+        Some(sk) => format!("{}#{}#{}", T::NAME, key.pk, sk),
+        None => format!("{}#{}", T::NAME, key.pk),
+    }
+}
+
+fn revision<T: Entity>(mut record: Item) -> Result<Revision<T>, RepoError> {
+    let invalid = || RepoError::Transport("Invalid history record".into());
+    let string = |record: &mut Item, name: &str| match record.remove(name) {
+        Some(AttributeValue::S(value)) => Some(value),
+        _ => None,
+    };
+
+    let operation = string(&mut record, "operation")
+        .as_deref()
+        .and_then(WriteOperation::parse)
+        .ok_or_else(invalid)?;
+    let actor = string(&mut record, "actor");
+    let changed_at = match record.remove("changed_at") {
+        Some(AttributeValue::N(n)) => n.parse().map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    let Some(AttributeValue::M(previous)) = record.remove("item") else {
+        return Err(invalid());
+    };
+
+    Ok(Revision {
+        previous: decode(previous)?,
+        operation,
+        actor,
+        changed_at,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity_field;
+    use crate::shared::dynamodb::{MemoryStorage, Update};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Account {
+        id: String,
+        name: String,
+        logins: u32,
+    }
+
+    impl Entity for Account {
+        const NAME: &'static str = "account";
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn repo(storage: &MemoryStorage) -> VersionedRepo<Account> {
+        storage.create_table("history", &[]);
+        storage.repo::<Account>("accounts").with_history("history")
+    }
+
+    fn account(name: &str) -> Account {
+        Account {
+            id: "1".to_string(),
+            name: name.to_string(),
+            logins: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn records_previous_entities_newest_first() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage).acting_as("admin");
+        repo.insert(account("Ann")).await.unwrap();
+
+        let ann = repo.read("1").await.unwrap().unwrap();
+        let anna = repo
+            .update(&Versioned {
+                data: account("Anna"),
+                ..ann.clone()
+            })
+            .await
+            .unwrap();
+        let patched = repo
+            .patch("1", Update::new().add(entity_field!(Account, logins), 1))
+            .await
+            .unwrap();
+        assert_eq!(patched.data.logins, 1);
+        repo.delete("1", patched.last_write).await.unwrap();
+
+        let revisions = repo
+            .revisions("1", PageRequest::default())
+            .await
+            .unwrap()
+            .items;
+        let operations: Vec<_> = revisions.iter().map(|r| r.operation).collect();
+        assert_eq!(
+            operations,
+            vec![
+                WriteOperation::Delete,
+                WriteOperation::Patch,
+                WriteOperation::Update
+            ]
+        );
+        assert_eq!(revisions[0].previous.data.logins, 1);
+        assert_eq!(revisions[1].previous.data.name, "Anna");
+        assert_eq!(revisions[1].changed_at, patched.last_write);
+        assert_eq!(revisions[2].previous.data.name, "Ann");
+        assert_eq!(revisions[2].previous.last_write, ann.last_write);
+        assert_eq!(revisions[2].changed_at, anna.last_write);
+        assert_eq!(revisions[2].actor.as_deref(), Some("admin"));
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_not_recorded() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage);
+        repo.insert(account("Ann")).await.unwrap();
+        let ann = repo.read("1").await.unwrap().unwrap();
+
+        let stale = Versioned {
+            last_write: ann.last_write - 1,
+            ..ann.clone()
+        };
+        assert!(matches!(
+            repo.update(&stale).await,
+            Err(RepoError::Conflict)
+        ));
+        assert!(matches!(
+            repo.delete("2", ann.last_write).await,
+            Err(RepoError::NotFound)
+        ));
+
+        assert!(storage.items("history").is_empty());
+    }
+
+    #[tokio::test]
+    async fn pages_through_revisions() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage).with_cursor_secret("secret");
+        repo.insert(account("v0")).await.unwrap();
+        for i in 1..=3 {
+            repo.modify("1", |account| account.name = format!("v{}", i))
+                .await
+                .unwrap();
+        }
+
+        let first = repo.revisions("1", PageRequest::first(2)).await.unwrap();
+        let second = repo
+            .revisions("1", PageRequest::after(2, first.next.unwrap()))
+            .await
+            .unwrap();
+        let names: Vec<_> = first
+            .items
+            .iter()
+            .chain(&second.items)
+            .map(|r| r.previous.data.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["v2", "v1", "v0"]);
+        assert!(second.next.is_none());
+    }
+
+    #[tokio::test]
+    async fn revisions_need_a_history() {
+        let repo = MemoryStorage::new().repo::<Account>("accounts");
+        repo.insert(account("Ann")).await.unwrap();
+
+        assert!(matches!(
+            repo.revisions("1", PageRequest::default()).await,
+            Err(RepoError::HistoryDisabled)
+        ));
+    }
+}
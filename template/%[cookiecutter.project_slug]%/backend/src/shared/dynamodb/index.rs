@@ -0,0 +1,342 @@
+use crate::shared::dynamodb::{Entity, Key, Page, PageRequest, Query, RepoError, VersionedRepo};
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Serialize;
+use std::collections::HashMap;
+
+///
+/// A global secondary index of the table of an entity.
+///
+/// Declare indexes with `#[derive(Indexes)]` and list them in `Entity::INDEXES`.
+/// The declarations are checked into `schema/{entity}.indexes.json`, which the CDK `VersionedTable` creates.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Index {
+    pub name: &'static str,
+    pub partition_key: (&'static str, KeyType),
+    pub sort_key: Option<(&'static str, KeyType)>,
+    pub projection: Projection,
+}
+
+/// DynamoDB type of a key attribute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    S,
+    N,
+}
+
+/// Attributes copied into the index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// The whole item.
+    All,
+    /// Only the table and index keys. Queries read the items from the table.
+    KeysOnly,
+    /// The keys and the given attributes. Queries read the items from the table.
+    Include(&'static [&'static str]),
+}
+
+/// Types of fields usable as index keys.
+pub trait IndexKey: Serialize {
+    const TYPE: KeyType;
+}
+
+macro_rules! index_key {
+    ($key_type:ident: $($t:ty),*) => { $(impl IndexKey for $t { const TYPE: KeyType = KeyType::$key_type; })* };
+}
+
+index_key!(S: String);
+index_key!(N: i8, i16, i32, i64, u8, u16, u32, u64, f32, f64);
+
+/// Condition on the sort key of an index query.
+#[derive(Clone, Debug)]
+pub enum KeyCondition<S> {
+    Eq(S),
+    Lt(S),
+    Le(S),
+    Gt(S),
+    Ge(S),
+    Between(S, S),
+    BeginsWith(S),
+}
+
+impl<S: IndexKey> KeyCondition<S> {
+    /// The key condition on `#sk` with its values.
+    pub(super) fn expression(&self) -> Result<(String, Vec<AttributeValue>), serde_dynamo::Error> {
+        let (expression, values) = match self {
+            KeyCondition::Eq(v) => ("#sk = :sk0", vec![v]),
+            KeyCondition::Lt(v) => ("#sk < :sk0", vec![v]),
+            KeyCondition::Le(v) => ("#sk <= :sk0", vec![v]),
+            KeyCondition::Gt(v) => ("#sk > :sk0", vec![v]),
+            KeyCondition::Ge(v) => ("#sk >= :sk0", vec![v]),
+            KeyCondition::Between(from, to) => ("#sk BETWEEN :sk0 AND :sk1", vec![from, to]),
+            KeyCondition::BeginsWith(v) => ("begins_with(#sk, :sk0)", vec![v]),
+        };
+        let values = values
+            .into_iter()
+            .map(serde_dynamo::to_attribute_value)
+            .collect::<Result<_, _>>()?;
+        Ok((expression.to_string(), values))
+    }
+}
+
+impl Index {
+    /// The declaration as checked into `schema/{entity}.indexes.json`, named like the CDK properties.
+    pub fn to_json(&self) -> serde_json::Value {
+        let key = |(name, key_type): (&str, KeyType)| serde_json::json!({"name": name, "type": format!("{:?}", key_type)});
+        let mut json = serde_json::json!({
+            "indexName": self.name,
+            "partitionKey": key(self.partition_key),
+        });
+        if let Some(sort_key) = self.sort_key {
+            json["sortKey"] = key(sort_key);
+        }
+        match self.projection {
+            Projection::All => json["projectionType"] = "ALL".into(),
+            Projection::KeysOnly => json["projectionType"] = "KEYS_ONLY".into(),
+            Projection::Include(attributes) => {
+                json["projectionType"] = "INCLUDE".into();
+                json["nonKeyAttributes"] = attributes.into();
+            }
+        }
+        json
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Queries a page of an index by its partition key and an optional condition on its sort key.
+    ///
+    /// Prefer the typed `query_by_<index>` methods generated by `#[derive(Indexes)]`.
+    /// Indexes without `Projection::All` return the keys only, their items are read from the table.
+    /// A sort key condition on an index without a sort key fails.
+    ///
+    pub async fn query_by<P: IndexKey, S: IndexKey>(
+        &self,
+        index: &Index,
+        partition: &P,
+        sort: Option<KeyCondition<S>>,
+        page: PageRequest,
+    ) -> Result<Page<T>, RepoError> {
+        debug_assert!(
+            T::INDEXES.contains(index),
+            "index {} is missing in Entity::INDEXES",
+            index.name
+        );
+        if sort.is_some() && index.sort_key.is_none() {
+            return Err(RepoError::Transport(
+                format!("index {} has no sort key", index.name).into(),
+            ));
+        }
+
+        let mut expression = "#pk = :pk".to_string();
+        let mut names = HashMap::from([("#pk".to_string(), index.partition_key.0.to_string())]);
+        let mut values = HashMap::from([(
+            ":pk".to_string(),
+            serde_dynamo::to_attribute_value(partition)?,
+        )]);
+        if let (Some(condition), Some((sort_key, _))) = (sort, index.sort_key) {
+            let (condition, sort_values) = condition.expression()?;
+            expression = format!("{} AND {}", expression, condition);
+            names.insert("#sk".to_string(), sort_key.to_string());
+            for (i, value) in sort_values.into_iter().enumerate() {
+                values.insert(format!(":sk{}", i), value);
+            }
+        }
+        let mut filter = None;
+        self.filter_tenant(&mut filter, &mut values)?;
+
+        let query = Query {
+            table: self.table_name.clone(),
+            index: Some(index.name.to_string()),
+            key_condition: expression.clone(),
+            filter,
+            names,
+            values: values.clone(),
+            ..Default::default()
+        };
+
+        // a cursor is only valid for the query it was issued for
+        let mut values: Vec<_> = values.into_iter().collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        let context = format!(
+            "{}/{}/{}/{:?}",
+            self.table_name, index.name, expression, values
+        );
+
+        let (items, next) = self.query_items(query, &context, page).await?;
+        let items = match index.projection {
+            Projection::All => {
+                let mut decoded = Vec::with_capacity(items.len());
+                for item in items {
+                    decoded.extend(self.decode_visible(item).await?);
+                }
+                decoded
+            }
+            Projection::KeysOnly | Projection::Include(_) => {
+                let keys: Vec<Key> = items
+                    .iter()
+                    .filter_map(Key::from_item::<T>)
+                    .filter_map(|key| self.unscoped(key))
+                    .collect();
+                self.batch_read(keys).await?
+            }
+        };
+
+        Ok(Page { items, next })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::Indexes;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Indexes)]
+    #[index(name = "team-index", partition = team, sort = joined)]
+    #[index(name = "city-index", partition = city, keys_only)]
+    struct Member {
+        id: String,
+        team: String,
+        joined: i64,
+        city: String,
+    }
+
+    impl Entity for Member {
+        const NAME: &'static str = "member";
+        const INDEXES: &'static [Index] = &[Self::TEAM_INDEX, Self::CITY_INDEX];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Member> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "members".to_string())
+    }
+
+    fn item(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "pk": {"S": id},
+            "id": {"S": id},
+            "team": {"S": "red"},
+            "joined": {"N": "2020"},
+            "city": {"S": "Berlin"},
+            "data_version": {"N": "1"},
+            "last_write": {"N": "1"}
+        })
+    }
+
+    #[test]
+    fn derives_declarations() {
+        assert_eq!(
+            Member::TEAM_INDEX,
+            Index {
+                name: "team-index",
+                partition_key: ("team", KeyType::S),
+                sort_key: Some(("joined", KeyType::N)),
+                projection: Projection::All,
+            }
+        );
+        assert_eq!(
+            Member::CITY_INDEX.to_json(),
+            serde_json::json!({
+                "indexName": "city-index",
+                "partitionKey": {"name": "city", "type": "S"},
+                "projectionType": "KEYS_ONLY"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_with_sort_key_condition() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Query"))
+            .and(body_partial_json(serde_json::json!({
+                "IndexName": "team-index",
+                "KeyConditionExpression": "#pk = :pk AND #sk BETWEEN :sk0 AND :sk1",
+                "ExpressionAttributeNames": {"#pk": "team", "#sk": "joined"},
+                "ExpressionAttributeValues": {
+                    ":pk": {"S": "red"},
+                    ":sk0": {"N": "2019"},
+                    ":sk1": {"N": "2021"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [item("1")],
+                "Count": 1
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let page = repo(&server)
+            .await
+            .query_by_team(
+                "red",
+                Some(KeyCondition::Between(2019, 2021)),
+                PageRequest::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].data.id, "1");
+    }
+
+    #[tokio::test]
+    async fn reads_items_of_keys_only_index() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Query"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [{"pk": {"S": "1"}, "city": {"S": "Berlin"}}],
+                "Count": 1
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.BatchGetItem"))
+            .and(body_partial_json(serde_json::json!({
+                "RequestItems": {"members": {"Keys": [{"pk": {"S": "1"}}]}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Responses": {"members": [item("1")]}
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let page = repo(&server)
+            .await
+            .query_by_city("Berlin", PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].data.team, "red");
+    }
+
+    #[tokio::test]
+    async fn rejects_sort_key_condition_without_sort_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let result = repo(&server)
+            .await
+            .query_by(
+                &Member::CITY_INDEX,
+                &"Berlin".to_string(),
+                Some(KeyCondition::Eq(2020)),
+                PageRequest::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(RepoError::Transport(_))));
+    }
+}
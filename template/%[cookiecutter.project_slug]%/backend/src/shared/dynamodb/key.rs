@@ -0,0 +1,365 @@
+use crate::shared::dynamodb::unique::SENTINEL_PREFIX;
+use crate::shared::dynamodb::{
+    Entity, KeyCondition, Page, PageRequest, Query, RepoError, VersionedRepo,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use std::collections::HashMap;
+
+/// Name of the partition key attribute of every versioned table.
+pub(super) const PK: &str = "pk";
+/// Name of the sort key attribute of tables with composite keys.
+pub(super) const SK: &str = "sk";
+
+///
+/// The key of an item, without the `PK_PREFIX` and `SK_PREFIX` of its entity.
+///
+/// Converts from a partition key string for tables without sort key.
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub pk: String,
+    pub sk: Option<String>,
+}
+
+impl Key {
+    pub fn new(pk: impl Into<String>) -> Self {
+        Self {
+            pk: pk.into(),
+            sk: None,
+        }
+    }
+
+    pub fn composite(pk: impl Into<String>, sk: impl Into<String>) -> Self {
+        Self {
+            pk: pk.into(),
+            sk: Some(sk.into()),
+        }
+    }
+
+    /// The key attributes as stored, with the prefixes of `T`.
+    pub(super) fn to_item<T: Entity>(&self) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::from([(
+            PK.to_string(),
+            AttributeValue::S(format!("{}{}", T::PK_PREFIX, self.pk)),
+        )]);
+        if let Some(sk) = &self.sk {
+            item.insert(
+                SK.to_string(),
+                AttributeValue::S(format!("{}{}", T::SK_PREFIX, sk)),
+            );
+        }
+        item
+    }
+
+    /// The key of a stored item of `T`, `None` if the item belongs to another entity or is a sentinel.
+    pub(super) fn from_item<T: Entity>(item: &HashMap<String, AttributeValue>) -> Option<Key> {
+        let pk = item.get(PK).and_then(|pk| pk.as_s().ok())?;
+        if T::PK_PREFIX.is_empty() && pk.starts_with(SENTINEL_PREFIX) {
+            return None;
+        }
+        let attribute = |name, prefix: &str| -> Option<Option<String>> {
+            match item.get(name) {
+                Some(value) => Some(Some(value.as_s().ok()?.strip_prefix(prefix)?.to_string())),
+                None => Some(None),
+            }
+        };
+        Some(Key {
+            pk: attribute(PK, T::PK_PREFIX)??,
+            sk: attribute(SK, T::SK_PREFIX)?,
+        })
+    }
+}
+
+impl From<&str> for Key {
+    fn from(pk: &str) -> Self {
+        Key::new(pk)
+    }
+}
+
+impl From<&String> for Key {
+    fn from(pk: &String) -> Self {
+        Key::new(pk)
+    }
+}
+
+impl From<String> for Key {
+    fn from(pk: String) -> Self {
+        Key::new(pk)
+    }
+}
+
+///
+/// Filter on the key prefixes of `T` for scans of tables shared by several entities.
+///
+/// Excludes the sentinels of unique values of entities without prefix.
+/// `None` if the entity has neither prefixes nor unique attributes.
+///
+pub(super) fn entity_filter<T: Entity>() -> Option<(String, HashMap<String, AttributeValue>)> {
+    let mut conditions = Vec::new();
+    let mut values = HashMap::new();
+    for (attribute, prefix) in [(PK, T::PK_PREFIX), (SK, T::SK_PREFIX)] {
+        if !prefix.is_empty() {
+            let placeholder = format!(":{}_prefix", attribute);
+            conditions.push(format!("begins_with({}, {})", attribute, placeholder));
+            values.insert(placeholder, AttributeValue::S(prefix.to_string()));
+        }
+    }
+    if T::PK_PREFIX.is_empty() && !T::UNIQUE.is_empty() {
+        conditions.push(format!("NOT begins_with({}, :sentinel_prefix)", PK));
+        values.insert(
+            ":sentinel_prefix".to_string(),
+            AttributeValue::S(SENTINEL_PREFIX.to_string()),
+        );
+    }
+    (!conditions.is_empty()).then(|| (conditions.join(" AND "), values))
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Queries a page of the items of `T` in a partition, optionally narrowed by a condition on the sort key.
+    ///
+    /// The values of the condition are given without `SK_PREFIX`, the partition key without the tenant.
+    /// Items of other entities in the same partition are left out.
+    ///
+    pub async fn query_partition(
+        &self,
+        pk: &str,
+        sort: Option<KeyCondition<String>>,
+        page: PageRequest,
+    ) -> Result<Page<T>, RepoError> {
+        let pk = self.scoped(Key::new(pk))?.pk;
+        let prefix = T::SK_PREFIX;
+        let prefixed = |value: String| format!("{}{}", prefix, value);
+        let mut names = HashMap::from([("#pk".to_string(), PK.to_string())]);
+        let mut values = HashMap::from([(
+            ":pk".to_string(),
+            AttributeValue::S(format!("{}{}", T::PK_PREFIX, pk)),
+        )]);
+        let mut expression = "#pk = :pk".to_string();
+        let mut filter = None;
+
+        let sort = match sort {
+            // ranges keep to the prefix via the key condition
+            Some(KeyCondition::Eq(v)) => Some(KeyCondition::Eq(prefixed(v))),
+            Some(KeyCondition::BeginsWith(v)) => Some(KeyCondition::BeginsWith(prefixed(v))),
+            Some(KeyCondition::Between(from, to)) => {
+                Some(KeyCondition::Between(prefixed(from), prefixed(to)))
+            }
+            // open ranges reach into the items of other prefixes
+            Some(condition) => {
+                if !prefix.is_empty() {
+                    filter = Some("begins_with(#sk, :sk_prefix)".to_string());
+                    values.insert(
+                        ":sk_prefix".to_string(),
+                        AttributeValue::S(prefix.to_string()),
+                    );
+                }
+                Some(match condition {
+                    KeyCondition::Lt(v) => KeyCondition::Lt(prefixed(v)),
+                    KeyCondition::Le(v) => KeyCondition::Le(prefixed(v)),
+                    KeyCondition::Gt(v) => KeyCondition::Gt(prefixed(v)),
+                    KeyCondition::Ge(v) => KeyCondition::Ge(prefixed(v)),
+                    other => other,
+                })
+            }
+            None if !prefix.is_empty() => Some(KeyCondition::BeginsWith(prefix.to_string())),
+            None => None,
+        };
+        if let Some(sort) = sort {
+            let (condition, sort_values) = sort.expression()?;
+            expression = format!("{} AND {}", expression, condition);
+            names.insert("#sk".to_string(), SK.to_string());
+            for (i, value) in sort_values.into_iter().enumerate() {
+                values.insert(format!(":sk{}", i), value);
+            }
+        }
+
+        let query = Query {
+            table: self.table_name.clone(),
+            key_condition: expression.clone(),
+            filter: filter.clone(),
+            names,
+            values: values.clone(),
+            ..Default::default()
+        };
+
+        // a cursor is only valid for the query it was issued for
+        let mut values: Vec<_> = values.into_iter().collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        let context = format!(
+            "{}/{}/{:?}/{:?}",
+            self.table_name, expression, filter, values
+        );
+
+        let (items, next) = self.query_items(query, &context, page).await?;
+        let mut decoded = Vec::with_capacity(items.len());
+        for item in items {
+            decoded.extend(self.decode_visible(item).await?);
+        }
+        Ok(Page {
+            items: decoded,
+            next,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Order {
+        user_id: String,
+        order_id: String,
+    }
+
+    impl Entity for Order {
+        const NAME: &'static str = "order";
+        const PK_PREFIX: &'static str = "USER#";
+        const SK_PREFIX: &'static str = "ORDER#";
+
+        fn pk(&self) -> String {
+            self.user_id.clone()
+        }
+
+        fn sk(&self) -> Option<String> {
+            Some(self.order_id.clone())
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Order> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "app".to_string())
+    }
+
+    fn item(order_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "pk": {"S": "USER#1"},
+            "sk": {"S": format!("ORDER#{}", order_id)},
+            "user_id": {"S": "1"},
+            "order_id": {"S": order_id},
+            "data_version": {"N": "1"},
+            "last_write": {"N": "1"}
+        })
+    }
+
+    #[test]
+    fn prefixes_stored_keys() {
+        let key = Key::composite("1", "2024-01");
+        let item = key.to_item::<Order>();
+        assert_eq!(item[PK], AttributeValue::S("USER#1".to_string()));
+        assert_eq!(item[SK], AttributeValue::S("ORDER#2024-01".to_string()));
+        assert_eq!(Key::from_item::<Order>(&item), Some(key));
+
+        let other = Key::composite("1", "profile").to_item::<Order>();
+        let mut session = other.clone();
+        session.insert(SK.to_string(), AttributeValue::S("SESSION#a".to_string()));
+        assert_eq!(Key::from_item::<Order>(&session), None);
+    }
+
+    #[tokio::test]
+    async fn reads_composite_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .and(body_partial_json(serde_json::json!({
+                "Key": {"pk": {"S": "USER#1"}, "sk": {"S": "ORDER#2024-01"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Item": item("2024-01")
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let order = repo(&server)
+            .await
+            .read(Key::composite("1", "2024-01"))
+            .await
+            .unwrap();
+        assert_eq!(order.unwrap().data.order_id, "2024-01");
+    }
+
+    #[tokio::test]
+    async fn queries_partition_within_prefix() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Query"))
+            .and(body_partial_json(serde_json::json!({
+                "KeyConditionExpression": "#pk = :pk AND #sk BETWEEN :sk0 AND :sk1",
+                "ExpressionAttributeValues": {
+                    ":pk": {"S": "USER#1"},
+                    ":sk0": {"S": "ORDER#2024-01"},
+                    ":sk1": {"S": "ORDER#2024-12"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [item("2024-01"), item("2024-02")],
+                "Count": 2
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Query"))
+            .and(body_partial_json(serde_json::json!({
+                "KeyConditionExpression": "#pk = :pk AND begins_with(#sk, :sk0)",
+                "ExpressionAttributeValues": {":sk0": {"S": "ORDER#"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [item("2024-01")],
+                "Count": 1
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let repo = repo(&server).await;
+
+        let between = KeyCondition::Between("2024-01".to_string(), "2024-12".to_string());
+        let page = repo
+            .query_partition("1", Some(between), PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 2);
+
+        let page = repo
+            .query_partition("1", None, PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn open_ranges_filter_other_prefixes() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.Query"))
+            .and(body_partial_json(serde_json::json!({
+                "KeyConditionExpression": "#pk = :pk AND #sk > :sk0",
+                "FilterExpression": "begins_with(#sk, :sk_prefix)",
+                "ExpressionAttributeValues": {
+                    ":sk0": {"S": "ORDER#2024-06"},
+                    ":sk_prefix": {"S": "ORDER#"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [],
+                "Count": 0
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let after = KeyCondition::Gt("2024-06".to_string());
+        repo(&server)
+            .await
+            .query_partition("1", Some(after), PageRequest::default())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use crate::shared::dynamodb::key::PK;
+use crate::shared::dynamodb::migration::item_last_write;
+use crate::shared::dynamodb::repo::next_write;
+use crate::shared::dynamodb::storage::Item;
+#[cfg(any(debug_assertions, test))]
+use crate::shared::dynamodb::{provision_tables, LocalTable};
+use crate::shared::dynamodb::{DynamoDbStorage, Put, RepoError, Storage, StorageError, UpdateItem};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Seconds a lease item is kept after its lease ended, before DynamoDB TTL removes it.
+const RETENTION: i64 = 24 * 3600;
+
+/// A lease held by an owner until it expires or is released.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    pub name: String,
+    pub owner: String,
+    ///
+    /// Fencing token, increasing with every acquisition of the lease.
+    ///
+    /// Pass it along with the writes of the holder, so a resource can reject writes of a holder
+    /// that lost the lease, e.g. after a pause longer than the TTL.
+    /// It is the `last_write` of the lease item.
+    ///
+    pub token: i64,
+    /// Epoch millis when the lease ends unless it is renewed.
+    pub until: i64,
+}
+
+///
+/// Leases on names, so a job runs only once at a time across Lambda instances.
+///
+/// Each lease is an item of a table with the key `pk` and TTL on `expires_at`.
+/// Writes are conditional on the `last_write` token of the item, like the writes of `VersionedRepo`.
+///
+/// ```ignore
+/// let Some(mut lease) = leases.acquire("nightly-report", &request_id, Duration::from_secs(300)).await? else {
+///     return Ok(()); // runs elsewhere
+/// };
+/// for chunk in chunks {
+///     process(chunk, lease.token).await?;
+///     lease = leases.renew(&lease, Duration::from_secs(300)).await?;
+/// }
+/// leases.release(&lease).await?;
+/// ```
+///
+#[derive(Clone)]
+pub struct Leases {
+    storage: Arc<dyn Storage>,
+    table_name: String,
+}
+
+impl Leases {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self::from_storage(Arc::new(DynamoDbStorage::new(client)), table_name)
+    }
+
+    /// Leases on another storage than DynamoDB, e.g. `MemoryStorage` in tests.
+    pub fn from_storage(storage: Arc<dyn Storage>, table_name: String) -> Self {
+        Self {
+            storage,
+            table_name,
+        }
+    }
+
+    ///
+    /// Creates the leases table if it is missing, e.g. on a fresh LocalStack.
+    ///
+    /// Does nothing in release builds, there the table is created by the CDK.
+    ///
+    #[cfg(any(debug_assertions, test))]
+    pub async fn ensure_local_table(client: &Client, table_name: &str) -> Result<(), RepoError> {
+        provision_tables(client, &[LocalTable::new(table_name)]).await
+    }
+
+    #[cfg(not(any(debug_assertions, test)))]
+    pub async fn ensure_local_table(_client: &Client, _table_name: &str) -> Result<(), RepoError> {
+        Ok(())
+    }
+
+    ///
+    /// Acquires the lease `name` for `owner` for `ttl`.
+    ///
+    /// Returns `None` if another owner holds it. An owner acquiring its own lease again gets a new token.
+    ///
+    pub async fn acquire(
+        &self,
+        name: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<Option<Lease>, RepoError> {
+        let key = key(name);
+        let current = self
+            .storage
+            .get(&self.table_name, key.clone(), true)
+            .await?;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let (token, condition, values) = match &current {
+            Some(item) => {
+                let held_by_other = lease_until(item) > now
+                    && item.get("owner") != Some(&AttributeValue::S(owner.to_string()));
+                if held_by_other {
+                    return Ok(None);
+                }
+                let last_write = item_last_write(item);
+                (
+                    next_write(last_write),
+                    "last_write = :expected",
+                    HashMap::from([(
+                        ":expected".to_string(),
+                        AttributeValue::N(last_write.to_string()),
+                    )]),
+                )
+            }
+            None => (now, "attribute_not_exists(pk)", HashMap::new()),
+        };
+
+        let lease = Lease {
+            name: name.to_string(),
+            owner: owner.to_string(),
+            token,
+            until: now + ttl.as_millis() as i64,
+        };
+        let mut item = key;
+        item.extend(lease_attributes(&lease));
+        let result = self
+            .storage
+            .put(Put {
+                table: self.table_name.clone(),
+                item,
+                condition: Some(condition.to_string()),
+                values,
+                ..Default::default()
+            })
+            .await;
+
+        match result {
+            Ok(()) => Ok(Some(lease)),
+            // acquired by another owner meanwhile
+            Err(StorageError::ConditionFailed(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///
+    /// Extends the lease to `ttl` from now.
+    ///
+    /// Fails with `Conflict` if the lease expired or was acquired again meanwhile.
+    /// The holder must stop then, another owner may be running.
+    ///
+    pub async fn renew(&self, lease: &Lease, ttl: Duration) -> Result<Lease, RepoError> {
+        let renewed = Lease {
+            until: chrono::Utc::now().timestamp_millis() + ttl.as_millis() as i64,
+            ..lease.clone()
+        };
+        self.write_until(&renewed, lease.token).await?;
+        Ok(renewed)
+    }
+
+    ///
+    /// Ends the lease, so the next `acquire` succeeds right away.
+    ///
+    /// Fails with `Conflict` if the lease expired or was acquired again meanwhile.
+    /// The item is kept, so the tokens of later acquisitions still increase.
+    ///
+    pub async fn release(&self, lease: &Lease) -> Result<(), RepoError> {
+        let released = Lease {
+            until: 0,
+            ..lease.clone()
+        };
+        self.write_until(&released, lease.token).await
+    }
+
+    /// Sets the end of the lease if it is still held with `token`.
+    async fn write_until(&self, lease: &Lease, token: i64) -> Result<(), RepoError> {
+        let mut values: Item = lease_attributes(lease)
+            .into_iter()
+            .filter(|(name, _)| name != "owner" && name != "last_write")
+            .map(|(name, value)| (format!(":{}", name), value))
+            .collect();
+        values.insert(":token".to_string(), AttributeValue::N(token.to_string()));
+        values.insert(
+            ":now".to_string(),
+            AttributeValue::N(chrono::Utc::now().timestamp_millis().to_string()),
+        );
+
+        let result = self
+            .storage
+            .update(UpdateItem {
+                table: self.table_name.clone(),
+                key: key(&lease.name),
+                update: "SET lease_until = :lease_until, expires_at = :expires_at".to_string(),
+                condition: Some("last_write = :token AND lease_until > :now".to_string()),
+                values,
+                ..Default::default()
+            })
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(StorageError::ConditionFailed(_)) => Err(RepoError::Conflict),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn key(name: &str) -> Item {
+    HashMap::from([(PK.to_string(), AttributeValue::S(name.to_string()))])
+}
+
+fn lease_attributes(lease: &Lease) -> Item {
+    let expires_at = lease.until.max(chrono::Utc::now().timestamp_millis()) / 1000 + RETENTION;
+    HashMap::from([
+        ("owner".to_string(), AttributeValue::S(lease.owner.clone())),
+        (
+            "last_write".to_string(),
+            AttributeValue::N(lease.token.to_string()),
+        ),
+        (
+            "lease_until".to_string(),
+            AttributeValue::N(lease.until.to_string()),
+        ),
+        (
+            "expires_at".to_string(),
+            AttributeValue::N(expires_at.to_string()),
+        ),
+    ])
+}
+
+fn lease_until(item: &Item) -> i64 {
+    match item.get("lease_until") {
+        Some(AttributeValue::N(n)) => n.parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::MemoryStorage;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn leases(storage: &MemoryStorage) -> Leases {
+        storage.create_table("leases", &[]);
+        Leases::from_storage(Arc::new(storage.clone()), "leases".to_string())
+    }
+
+    #[tokio::test]
+    async fn only_one_owner_holds_a_lease() {
+        let leases = leases(&MemoryStorage::new());
+
+        let lease = leases.acquire("job", "a", TTL).await.unwrap().unwrap();
+        assert_eq!(lease.owner, "a");
+        assert!(leases.acquire("job", "b", TTL).await.unwrap().is_none());
+        assert!(leases.acquire("other", "b", TTL).await.unwrap().is_some());
+
+        let renewed = leases.renew(&lease, TTL).await.unwrap();
+        assert_eq!(renewed.token, lease.token);
+        leases.release(&renewed).await.unwrap();
+
+        let next = leases.acquire("job", "b", TTL).await.unwrap().unwrap();
+        assert!(next.token > lease.token);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_taken_over() {
+        let leases = leases(&MemoryStorage::new());
+
+        let lost = leases
+            .acquire("job", "a", Duration::ZERO)
+            .await
+            .unwrap()
+            .unwrap();
+        let taken = leases.acquire("job", "b", TTL).await.unwrap().unwrap();
+        assert!(taken.token > lost.token);
+
+        // the former holder must stop
+        assert!(matches!(
+            leases.renew(&lost, TTL).await,
+            Err(RepoError::Conflict)
+        ));
+        assert!(matches!(
+            leases.release(&lost).await,
+            Err(RepoError::Conflict)
+        ));
+        assert!(leases.renew(&taken, TTL).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn concurrent_acquisitions_have_one_winner() {
+        let leases = leases(&MemoryStorage::new());
+        leases.acquire("job", "a", Duration::ZERO).await.unwrap();
+
+        let (b, c) = tokio::join!(
+            leases.acquire("job", "b", TTL),
+            leases.acquire("job", "c", TTL)
+        );
+        let winners = [b.unwrap(), c.unwrap()].into_iter().flatten().count();
+        assert_eq!(winners, 1);
+    }
+}
@@ -0,0 +1,715 @@
+use crate::shared::dynamodb::expression::{compare, Condition, Placeholders, Update};
+use crate::shared::dynamodb::key::{PK, SK};
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{
+    BatchWrite, ConditionCheck, Delete, Entity, Index, ItemPage, Projection, Put, Query, RepoError,
+    Scan, Storage, StorageError, TransactWrite, UpdateItem, VersionedRepo,
+};
+use async_trait::async_trait;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Maximum number of keys of a `BatchGetItem` request.
+const MAX_BATCH_GET: usize = 100;
+/// Maximum number of requests of a `BatchWriteItem` request.
+const MAX_BATCH_WRITE: usize = 25;
+/// Maximum number of items of a `TransactWriteItems` request.
+const MAX_TRANSACTION: usize = 100;
+
+///
+/// `Storage` keeping the tables in memory, for tests of handlers without DynamoDB or HTTP mocks.
+///
+/// Evaluates condition, update, key condition and filter expressions,
+/// maintains the global secondary indexes of `Entity::INDEXES` and pages like DynamoDB.
+/// Reads are always consistent. Clones share the tables.
+///
+/// ```ignore
+/// let storage = MemoryStorage::new();
+/// let repo: UserRepo = storage.repo("users");
+/// ```
+///
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    tables: Arc<Mutex<HashMap<String, MemoryTable>>>,
+}
+
+#[derive(Default)]
+struct MemoryTable {
+    indexes: Vec<Index>,
+    items: BTreeMap<TableKey, Item>,
+}
+
+/// The `pk` and `sk` values of an item.
+type TableKey = (String, Option<String>);
+
+/// A validated write of a single item or a transaction.
+struct Write {
+    table: String,
+    key: TableKey,
+    condition: Option<Condition>,
+    change: Change,
+}
+
+enum Change {
+    Put(Item),
+    Delete,
+    /// The update actions and the key of the item, resolved into a `Put` once the condition is checked.
+    Update(Update, Item),
+    None,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the table with the global secondary indexes, adding missing indexes to an existing one.
+    pub fn create_table(&self, name: &str, indexes: &[Index]) {
+        let mut tables = self.lock();
+        let table = tables.entry(name.to_string()).or_default();
+        for index in indexes {
+            if !table.indexes.iter().any(|i| i.name == index.name) {
+                table.indexes.push(*index);
+            }
+        }
+    }
+
+    /// A repository of `T` on the table, created with the indexes of `T`.
+    pub fn repo<T: Entity>(&self, table_name: &str) -> VersionedRepo<T> {
+        self.create_table(table_name, T::INDEXES);
+        VersionedRepo::from_storage(Arc::new(self.clone()), table_name.to_string())
+    }
+
+    /// The items of the table as stored, ordered by key.
+    pub fn items(&self, table: &str) -> Vec<Item> {
+        self.lock()
+            .get(table)
+            .map(|table| table.items.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, MemoryTable>> {
+        self.tables.lock().expect("no panic while locked")
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(
+        &self,
+        table: &str,
+        key: Item,
+        _consistent: bool,
+    ) -> Result<Option<Item>, StorageError> {
+        let key = table_key(&key)?;
+        let tables = self.lock();
+        Ok(self::table(&tables, table)?.items.get(&key).cloned())
+    }
+
+    async fn put(&self, put: Put) -> Result<(), StorageError> {
+        let write = prepare_put(put)?;
+        let mut tables = self.lock();
+        check(&tables, &write)?;
+        apply(&mut tables, write);
+        Ok(())
+    }
+
+    async fn delete(&self, delete: Delete) -> Result<(), StorageError> {
+        let write = prepare_delete(delete)?;
+        let mut tables = self.lock();
+        check(&tables, &write)?;
+        apply(&mut tables, write);
+        Ok(())
+    }
+
+    async fn update(&self, update: UpdateItem) -> Result<Item, StorageError> {
+        let write = prepare_update(update)?;
+        let mut tables = self.lock();
+        check(&tables, &write)?;
+        let write = resolve(&tables, write)?;
+        let Change::Put(item) = &write.change else {
+            unreachable!("updates resolve into puts");
+        };
+        let item = item.clone();
+        apply(&mut tables, write);
+        Ok(item)
+    }
+
+    async fn query(&self, query: Query) -> Result<ItemPage, StorageError> {
+        let mut placeholders = Placeholders::new(&query.names, &query.values);
+        let key_condition = placeholders
+            .condition(&query.key_condition)
+            .map_err(validation)?;
+        let filter = parse(&mut placeholders, query.filter.as_deref())?;
+        placeholders.all_used().map_err(validation)?;
+
+        let tables = self.lock();
+        let table = table(&tables, &query.table)?;
+        let index = match &query.index {
+            Some(name) => Some(
+                table
+                    .indexes
+                    .iter()
+                    .find(|index| index.name == name)
+                    .ok_or_else(|| {
+                        validation(format!(
+                            "The table does not have the specified index: {}",
+                            name
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let mut candidates: Vec<&Item> = table
+            .items
+            .values()
+            .filter(|item| index.is_none_or(|index| in_index(index, item)))
+            .filter(|item| key_condition.evaluate(item))
+            .collect();
+        if let Some(index) = index {
+            candidates.sort_by(|a, b| index_order(index, a, b));
+        }
+        let following = if query.descending {
+            candidates.reverse();
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+        let after_start = |item: &&Item| match (&query.start, index) {
+            (None, _) => true,
+            (Some(start), Some(index)) => index_order(index, item, start) == following,
+            (Some(start), None) => table_order(item, start) == following,
+        };
+
+        let page = page(
+            candidates.into_iter().filter(after_start),
+            query.limit,
+            filter.as_ref(),
+            |item| key_attributes(item, index),
+        );
+        Ok(match index {
+            Some(index) => ItemPage {
+                items: page.items.iter().map(|item| project(index, item)).collect(),
+                ..page
+            },
+            None => page,
+        })
+    }
+
+    async fn scan(&self, scan: Scan) -> Result<ItemPage, StorageError> {
+        let mut placeholders = Placeholders::new(&scan.names, &scan.values);
+        let filter = parse(&mut placeholders, scan.filter.as_deref())?;
+        placeholders.all_used().map_err(validation)?;
+        let start = scan.start.as_ref().map(table_key).transpose()?;
+
+        let tables = self.lock();
+        let table = table(&tables, &scan.table)?;
+        let candidates = table
+            .items
+            .iter()
+            .filter(|(key, _)| start.as_ref().is_none_or(|start| *key > start))
+            .filter(|(key, _)| {
+                scan.segment
+                    .is_none_or(|(segment, total)| segment_of(key, total) == segment)
+            })
+            .map(|(_, item)| item);
+
+        Ok(page(candidates, scan.limit, filter.as_ref(), |item| {
+            key_attributes(item, None)
+        }))
+    }
+
+    async fn batch_get(
+        &self,
+        table: &str,
+        keys: Vec<Item>,
+    ) -> Result<(Vec<Item>, Vec<Item>), StorageError> {
+        if keys.len() > MAX_BATCH_GET {
+            return Err(validation(
+                "Too many items requested for the BatchGetItem call",
+            ));
+        }
+        let keys = keys.iter().map(table_key).collect::<Result<Vec<_>, _>>()?;
+        if keys.iter().collect::<HashSet<_>>().len() < keys.len() {
+            return Err(validation("Provided list of item keys contains duplicates"));
+        }
+
+        let tables = self.lock();
+        let table = self::table(&tables, table)?;
+        let items = keys
+            .iter()
+            .filter_map(|key| table.items.get(key).cloned())
+            .collect();
+        Ok((items, Vec::new()))
+    }
+
+    async fn batch_write(
+        &self,
+        table: &str,
+        writes: Vec<BatchWrite>,
+    ) -> Result<Vec<BatchWrite>, StorageError> {
+        if writes.len() > MAX_BATCH_WRITE {
+            return Err(validation(
+                "Too many items requested for the BatchWriteItem call",
+            ));
+        }
+        let writes = writes
+            .into_iter()
+            .map(|write| {
+                let (key, change) = match write {
+                    BatchWrite::Put(item) => (table_key(&item)?, Change::Put(item)),
+                    BatchWrite::Delete(key) => (table_key(&key)?, Change::Delete),
+                };
+                Ok(Write {
+                    table: table.to_string(),
+                    key,
+                    condition: None,
+                    change,
+                })
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        if writes.iter().map(|w| &w.key).collect::<HashSet<_>>().len() < writes.len() {
+            return Err(validation("Provided list of item keys contains duplicates"));
+        }
+
+        let mut tables = self.lock();
+        table_mut(&mut tables, table)?;
+        for write in writes {
+            apply(&mut tables, write);
+        }
+        Ok(Vec::new())
+    }
+
+    async fn transact_write(&self, items: Vec<TransactWrite>) -> Result<(), StorageError> {
+        if items.len() > MAX_TRANSACTION {
+            return Err(validation(format!(
+                "Member must have length less than or equal to {}",
+                MAX_TRANSACTION
+            )));
+        }
+        let writes = items
+            .into_iter()
+            .map(|item| match item {
+                TransactWrite::Put(put) => prepare_put(put),
+                TransactWrite::Delete(delete) => prepare_delete(delete),
+                TransactWrite::Update(update) => prepare_update(update),
+                TransactWrite::Check(check) => prepare_check(check),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut written = HashSet::new();
+        if !writes.iter().all(|w| written.insert((&w.table, &w.key))) {
+            return Err(validation(
+                "Transaction request cannot include multiple operations on one item",
+            ));
+        }
+
+        let mut tables = self.lock();
+        let mut reasons = Vec::with_capacity(writes.len());
+        let mut resolved = Vec::with_capacity(writes.len());
+        for write in writes {
+            match check(&tables, &write) {
+                Ok(()) => reasons.push(None),
+                Err(e @ StorageError::ConditionFailed(_)) => reasons.push(Some(e)),
+                Err(e) => return Err(e),
+            }
+            resolved.push(resolve(&tables, write)?);
+        }
+        if reasons.iter().any(Option::is_some) {
+            return Err(StorageError::TransactionCanceled(reasons));
+        }
+
+        for write in resolved {
+            apply(&mut tables, write);
+        }
+        Ok(())
+    }
+}
+
+fn validation(message: impl std::fmt::Display) -> StorageError {
+    RepoError::Transport(format!("ValidationException: {}", message).into()).into()
+}
+
+fn table<'t>(
+    tables: &'t HashMap<String, MemoryTable>,
+    name: &str,
+) -> Result<&'t MemoryTable, StorageError> {
+    tables.get(name).ok_or_else(resource_not_found)
+}
+
+fn table_mut<'t>(
+    tables: &'t mut HashMap<String, MemoryTable>,
+    name: &str,
+) -> Result<&'t mut MemoryTable, StorageError> {
+    tables.get_mut(name).ok_or_else(resource_not_found)
+}
+
+fn resource_not_found() -> StorageError {
+    RepoError::Transport("ResourceNotFoundException: Requested resource not found".into()).into()
+}
+
+fn table_key(item: &Item) -> Result<TableKey, StorageError> {
+    let pk = match item.get(PK) {
+        Some(value) => value
+            .as_s()
+            .map_err(|_| validation("Type mismatch for key pk"))?,
+        None => return Err(validation("One of the required keys was not given a value")),
+    };
+    let sk = match item.get(SK) {
+        Some(value) => Some(
+            value
+                .as_s()
+                .map_err(|_| validation("Type mismatch for key sk"))?
+                .clone(),
+        ),
+        None => None,
+    };
+    Ok((pk.clone(), sk))
+}
+
+fn parse(
+    placeholders: &mut Placeholders,
+    expression: Option<&str>,
+) -> Result<Option<Condition>, StorageError> {
+    expression
+        .map(|expression| placeholders.condition(expression))
+        .transpose()
+        .map_err(validation)
+}
+
+fn prepare_put(put: Put) -> Result<Write, StorageError> {
+    let mut placeholders = Placeholders::new(&put.names, &put.values);
+    let condition = parse(&mut placeholders, put.condition.as_deref())?;
+    placeholders.all_used().map_err(validation)?;
+    Ok(Write {
+        table: put.table,
+        key: table_key(&put.item)?,
+        condition,
+        change: Change::Put(put.item),
+    })
+}
+
+fn prepare_delete(delete: Delete) -> Result<Write, StorageError> {
+    let mut placeholders = Placeholders::new(&delete.names, &delete.values);
+    let condition = parse(&mut placeholders, delete.condition.as_deref())?;
+    placeholders.all_used().map_err(validation)?;
+    Ok(Write {
+        table: delete.table,
+        key: table_key(&delete.key)?,
+        condition,
+        change: Change::Delete,
+    })
+}
+
+fn prepare_update(update: UpdateItem) -> Result<Write, StorageError> {
+    let mut placeholders = Placeholders::new(&update.names, &update.values);
+    let actions = placeholders.update(&update.update).map_err(validation)?;
+    let condition = parse(&mut placeholders, update.condition.as_deref())?;
+    placeholders.all_used().map_err(validation)?;
+    Ok(Write {
+        table: update.table,
+        key: table_key(&update.key)?,
+        condition,
+        change: Change::Update(actions, update.key),
+    })
+}
+
+fn prepare_check(check: ConditionCheck) -> Result<Write, StorageError> {
+    let mut placeholders = Placeholders::new(&check.names, &check.values);
+    let condition = parse(&mut placeholders, Some(&check.condition))?;
+    placeholders.all_used().map_err(validation)?;
+    Ok(Write {
+        table: check.table,
+        key: table_key(&check.key)?,
+        condition,
+        change: Change::None,
+    })
+}
+
+fn holds(condition: Option<&Condition>, current: Option<&Item>) -> bool {
+    condition.is_none_or(|condition| condition.evaluate(current.unwrap_or(&Item::new())))
+}
+
+/// Fails with `ConditionFailed` holding the current item if the condition of the write does not hold.
+fn check(tables: &HashMap<String, MemoryTable>, write: &Write) -> Result<(), StorageError> {
+    let current = table(tables, &write.table)?.items.get(&write.key);
+    if holds(write.condition.as_ref(), current) {
+        Ok(())
+    } else {
+        Err(StorageError::ConditionFailed(current.cloned()))
+    }
+}
+
+/// Turns an update into the put of the updated item.
+fn resolve(tables: &HashMap<String, MemoryTable>, write: Write) -> Result<Write, StorageError> {
+    let Change::Update(actions, key) = write.change else {
+        return Ok(write);
+    };
+    let current = table(tables, &write.table)?.items.get(&write.key);
+
+    // like DynamoDB, updates of missing items create them
+    let mut item = current.cloned().unwrap_or_else(|| key.clone());
+    actions.apply(&mut item).map_err(validation)?;
+    for attribute in [PK, SK] {
+        if item.get(attribute) != key.get(attribute) {
+            return Err(validation(format!(
+                "Cannot update attribute {}. This attribute is part of the key",
+                attribute
+            )));
+        }
+    }
+    Ok(Write {
+        change: Change::Put(item),
+        ..write
+    })
+}
+
+/// Applies a checked write.
+fn apply(tables: &mut HashMap<String, MemoryTable>, write: Write) {
+    let Some(table) = tables.get_mut(&write.table) else {
+        return;
+    };
+    match write.change {
+        Change::Put(item) => {
+            table.items.insert(write.key, item);
+        }
+        Change::Delete => {
+            table.items.remove(&write.key);
+        }
+        Change::Update(..) | Change::None => {}
+    }
+}
+
+/// Items without the key attributes of a global secondary index are not in it.
+fn in_index(index: &Index, item: &Item) -> bool {
+    item.contains_key(index.partition_key.0)
+        && index
+            .sort_key
+            .is_none_or(|(sort_key, _)| item.contains_key(sort_key))
+}
+
+/// Order of the items of a partition of an index: by sort key, then by table key.
+fn index_order(index: &Index, a: &Item, b: &Item) -> Ordering {
+    let by_sort_key = index
+        .sort_key
+        .and_then(|(sort_key, _)| compare(a.get(sort_key)?, b.get(sort_key)?))
+        .unwrap_or(Ordering::Equal);
+    by_sort_key.then_with(|| table_order(a, b))
+}
+
+fn table_order(a: &Item, b: &Item) -> Ordering {
+    match (table_key(a), table_key(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => Ordering::Equal,
+    }
+}
+
+/// The `LastEvaluatedKey` of an item: its table key and the key of the index.
+fn key_attributes(item: &Item, index: Option<&Index>) -> Item {
+    let mut attributes: Vec<&str> = vec![PK, SK];
+    if let Some(index) = index {
+        attributes.push(index.partition_key.0);
+        attributes.extend(index.sort_key.map(|(sort_key, _)| sort_key));
+    }
+    attributes
+        .into_iter()
+        .filter_map(|attribute| Some((attribute.to_string(), item.get(attribute)?.clone())))
+        .collect()
+}
+
+/// The attributes of an item copied into the index.
+fn project(index: &Index, item: &Item) -> Item {
+    let mut projected = key_attributes(item, Some(index));
+    match index.projection {
+        Projection::All => return item.clone(),
+        Projection::KeysOnly => {}
+        Projection::Include(attributes) => {
+            for attribute in attributes {
+                if let Some(value) = item.get(*attribute) {
+                    projected.insert(attribute.to_string(), value.clone());
+                }
+            }
+        }
+    }
+    projected
+}
+
+/// The segment of a parallel scan an item belongs to.
+fn segment_of(key: &TableKey, total_segments: u32) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    key.0.hash(&mut hasher);
+    (hasher.finish() % total_segments.max(1) as u64) as u32
+}
+
+/// Evaluates up to `limit` candidates, then applies the filter like DynamoDB.
+fn page<'i>(
+    mut candidates: impl Iterator<Item = &'i Item>,
+    limit: Option<u32>,
+    filter: Option<&Condition>,
+    last_key: impl Fn(&Item) -> Item,
+) -> ItemPage {
+    let limit = limit.map_or(usize::MAX, |limit| limit as usize);
+    let evaluated: Vec<&Item> = candidates.by_ref().take(limit).collect();
+    let more = evaluated.len() == limit && candidates.next().is_some();
+    ItemPage {
+        last_key: more.then(|| last_key(evaluated.last().expect("limit is positive"))),
+        scanned: evaluated.len(),
+        items: evaluated
+            .into_iter()
+            .filter(|item| filter.is_none_or(|filter| filter.evaluate(item)))
+            .cloned()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity_field;
+    use crate::shared::dynamodb::{
+        Indexes, KeyCondition, PageRequest, Transaction, Update, Versioned,
+    };
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Indexes)]
+    #[index(name = "team-index", partition = team, sort = joined)]
+    #[index(name = "city-index", partition = city, keys_only)]
+    struct Member {
+        id: String,
+        team: String,
+        joined: i64,
+        city: String,
+        logins: u32,
+    }
+
+    impl Entity for Member {
+        const NAME: &'static str = "member";
+        const INDEXES: &'static [Index] = &[Self::TEAM_INDEX, Self::CITY_INDEX];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn member(id: &str, team: &str, joined: i64) -> Member {
+        Member {
+            id: id.to_string(),
+            team: team.to_string(),
+            joined,
+            city: "Berlin".to_string(),
+            logins: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn honours_write_conditions() {
+        let repo: VersionedRepo<Member> = MemoryStorage::new().repo("members");
+        repo.insert(member("1", "red", 2020)).await.unwrap();
+        assert!(matches!(
+            repo.insert(member("1", "blue", 2021)).await,
+            Err(RepoError::AlreadyExists)
+        ));
+
+        let read = repo.read("1").await.unwrap().unwrap();
+        let updated = repo.update(&read).await.unwrap();
+        assert!(matches!(repo.update(&read).await, Err(RepoError::Conflict)));
+        assert!(matches!(
+            repo.delete("1", read.last_write).await,
+            Err(RepoError::Conflict)
+        ));
+        repo.delete("1", updated.last_write).await.unwrap();
+        assert!(matches!(
+            repo.delete("1", updated.last_write).await,
+            Err(RepoError::NotFound)
+        ));
+        assert!(repo.read("1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn applies_partial_updates() {
+        let repo: VersionedRepo<Member> = MemoryStorage::new().repo("members");
+        repo.insert(member("1", "red", 2020)).await.unwrap();
+
+        let update = Update::new()
+            .set(entity_field!(Member, team), "blue".to_string())
+            .add(entity_field!(Member, logins), 2);
+        let patched = repo.patch("1", update).await.unwrap();
+        assert_eq!(patched.data.team, "blue");
+        assert_eq!(patched.data.logins, 2);
+        assert_eq!(repo.read("1").await.unwrap().unwrap().data.logins, 2);
+
+        let missing = repo.patch("2", Update::new()).await;
+        assert!(matches!(missing, Err(RepoError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn queries_indexes_in_sort_key_order() {
+        let repo: VersionedRepo<Member> = MemoryStorage::new().repo("members");
+        repo.batch_put(vec![
+            member("1", "red", 2022),
+            member("2", "red", 2020),
+            member("3", "blue", 2021),
+            member("4", "red", 2021),
+        ])
+        .await
+        .unwrap();
+
+        let page = repo
+            .query_by_team("red", Some(KeyCondition::Ge(2021)), PageRequest::first(1))
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].data.id, "4");
+        let page = repo
+            .query_by_team(
+                "red",
+                Some(KeyCondition::Ge(2021)),
+                PageRequest::after(1, page.next.unwrap()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(page.items[0].data.id, "1");
+        assert!(page.next.is_none());
+
+        let page = repo
+            .query_by_city("Berlin", PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 4);
+        assert_eq!(page.items[0].data.team, "red");
+    }
+
+    #[tokio::test]
+    async fn cancels_transactions_atomically() {
+        let storage = MemoryStorage::new();
+        let repo: VersionedRepo<Member> = storage.repo("members");
+        repo.insert(member("1", "red", 2020)).await.unwrap();
+        let stale = repo.read("1").await.unwrap().unwrap();
+        repo.update(&stale).await.unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction.insert(&repo, member("2", "red", 2020)).unwrap();
+        transaction.update(&repo, &stale).unwrap();
+        let Err(RepoError::TransactionCanceled(reasons)) = transaction.commit().await else {
+            panic!("transaction not canceled");
+        };
+        assert!(reasons[0].is_none());
+        assert!(matches!(reasons[1], Some(RepoError::Conflict)));
+        assert_eq!(storage.items("members").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stores_versioned_items() {
+        let storage = MemoryStorage::new();
+        let repo: VersionedRepo<Member> = storage.repo("members");
+        repo.insert(member("1", "red", 2020)).await.unwrap();
+
+        let item = storage.items("members").remove(0);
+        assert_eq!(item[PK].as_s().unwrap(), "1");
+        let stored: Versioned<Member> = serde_dynamo::from_item(item).unwrap();
+        assert_eq!(stored.data_version, 1);
+    }
+}
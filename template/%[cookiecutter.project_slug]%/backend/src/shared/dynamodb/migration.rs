@@ -0,0 +1,186 @@
+use crate::shared::dynamodb::{from_item, Entity, RepoError, Versioned};
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde_json::Value;
+use std::collections::HashMap;
+
+///
+/// Transforms the JSON of an item from one `data_version` to the next.
+///
+/// The value is the flat item including `pk`, `data_version` and `last_write`.
+/// The migration only has to change the shape of the data; `data_version` is set by the caller.
+///
+pub type Migration = fn(Value) -> Result<Value, anyhow::Error>;
+
+/// The `data_version` of items in the current shape of `T`.
+pub fn current_version<T: Entity>() -> u16 {
+    T::MIGRATIONS.len() as u16 + 1
+}
+
+/// The `data_version` an item was written with. Items without one predate versioning.
+pub fn item_version(item: &HashMap<String, AttributeValue>) -> u16 {
+    item.get("data_version")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(1)
+}
+
+/// The `last_write` token of an item, 0 if it has none.
+pub(super) fn item_last_write(item: &HashMap<String, AttributeValue>) -> i64 {
+    item.get("last_write")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Converts a stored item into the entity, migrating older shapes.
+pub(super) fn decode<T: Entity>(
+    item: HashMap<String, AttributeValue>,
+) -> Result<Versioned<T>, RepoError> {
+    let migrated = migrate::<T>(&item)?.unwrap_or(item);
+    Ok(from_item(migrated)?)
+}
+
+///
+/// Upgrades an item written with an older `data_version` to the current shape of `T`
+/// by applying the chain of `T::MIGRATIONS`.
+///
+/// Returns `None` if the item is current already.
+/// Items written by newer code (higher `data_version`) are left as they are.
+///
+pub fn migrate<T: Entity>(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<Option<HashMap<String, AttributeValue>>, RepoError> {
+    migrate_to::<T>(item, current_version::<T>())
+}
+
+///
+/// Upgrades an item written with an older `data_version` to the `target` version
+/// by applying the chain of `T::MIGRATIONS` up to it.
+///
+/// Returns `None` if the item is at or above the target already.
+///
+pub fn migrate_to<T: Entity>(
+    item: &HashMap<String, AttributeValue>,
+    target: u16,
+) -> Result<Option<HashMap<String, AttributeValue>>, RepoError> {
+    let version = item_version(item);
+    let target = target.min(current_version::<T>());
+    if version >= target {
+        return Ok(None);
+    }
+
+    let mut value: Value = serde_dynamo::from_item(item.clone())?;
+    for (from, migration) in T::MIGRATIONS
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (i as u16 + 1, m))
+        .skip_while(|(from, _)| *from < version)
+        .take_while(|(from, _)| *from < target)
+    {
+        value = migration(value).map_err(|source| RepoError::Migration {
+            version: from,
+            source,
+        })?;
+    }
+    value["data_version"] = target.into();
+
+    Ok(Some(serde_dynamo::to_item(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Person {
+        id: String,
+        full_name: String,
+        age: u32,
+    }
+
+    impl Entity for Person {
+        const NAME: &'static str = "person";
+        // v1: name, v2: full_name, v3: + age
+        const MIGRATIONS: &'static [Migration] = &[rename_name, add_age];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn rename_name(mut value: Value) -> Result<Value, anyhow::Error> {
+        let name = value
+            .as_object_mut()
+            .and_then(|o| o.remove("name"))
+            .ok_or_else(|| anyhow::anyhow!("name missing"))?;
+        value["full_name"] = name;
+        Ok(value)
+    }
+
+    fn add_age(mut value: Value) -> Result<Value, anyhow::Error> {
+        value["age"] = 0.into();
+        Ok(value)
+    }
+
+    fn item(value: Value) -> HashMap<String, AttributeValue> {
+        serde_dynamo::to_item(value).unwrap()
+    }
+
+    #[test]
+    fn current_version_follows_migrations() {
+        assert_eq!(current_version::<Person>(), 3);
+    }
+
+    #[test]
+    fn applies_chain_from_item_version() {
+        let v1 = item(serde_json::json!({
+            "pk": "1", "id": "1", "name": "Jane", "data_version": 1, "last_write": 1
+        }));
+        let migrated = migrate::<Person>(&v1).unwrap().unwrap();
+        let person: crate::shared::dynamodb::Versioned<Person> =
+            serde_dynamo::from_item(migrated).unwrap();
+        assert_eq!(person.data.full_name, "Jane");
+        assert_eq!(person.data.age, 0);
+        assert_eq!(person.data_version, 3);
+        assert_eq!(person.last_write, 1);
+
+        let v2 = item(serde_json::json!({
+            "pk": "1", "id": "1", "full_name": "Jane", "data_version": 2, "last_write": 1
+        }));
+        let migrated = migrate::<Person>(&v2).unwrap().unwrap();
+        assert_eq!(migrated["age"], AttributeValue::N("0".to_string()));
+    }
+
+    #[test]
+    fn stops_at_target_version() {
+        let v1 = item(serde_json::json!({
+            "pk": "1", "id": "1", "name": "Jane", "data_version": 1, "last_write": 1
+        }));
+        let migrated = migrate_to::<Person>(&v1, 2).unwrap().unwrap();
+        assert_eq!(migrated["full_name"], AttributeValue::S("Jane".to_string()));
+        assert_eq!(migrated["data_version"], AttributeValue::N("2".to_string()));
+        assert!(!migrated.contains_key("age"));
+    }
+
+    #[test]
+    fn leaves_current_items() {
+        let v3 = item(serde_json::json!({
+            "pk": "1", "id": "1", "full_name": "Jane", "age": 42, "data_version": 3, "last_write": 1
+        }));
+        assert!(migrate::<Person>(&v3).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_failing_migration() {
+        let broken = item(serde_json::json!({
+            "pk": "1", "id": "1", "data_version": 1, "last_write": 1
+        }));
+        let result = migrate::<Person>(&broken);
+        assert!(matches!(
+            result,
+            Err(RepoError::Migration { version: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,107 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+mod backfill;
+mod batch;
+mod counter;
+mod error;
+#[cfg(any(debug_assertions, test))]
+mod expression;
+mod history;
+mod index;
+mod key;
+mod lease;
+#[cfg(any(debug_assertions, test))]
+mod memory;
+mod migration;
+mod outbox;
+mod page;
+#[cfg(any(debug_assertions, test))]
+mod provision;
+mod repo;
+mod schema;
+mod soft_delete;
+mod storage;
+mod stream;
+mod tenant;
+mod transaction;
+mod unique;
+mod update;
+
+pub use backfill::{BackfillOptions, BackfillReport, ResumeToken};
+pub use counter::{Counters, RateLimit};
+pub use entity_macro::Indexes;
+pub use error::RepoError;
+pub use history::{Revision, Revisions, WriteOperation};
+pub use index::{Index, IndexKey, KeyCondition, KeyType, Projection};
+pub use key::Key;
+pub use lease::{Lease, Leases};
+#[cfg(any(debug_assertions, test))]
+pub use memory::MemoryStorage;
+pub use migration::{current_version, migrate, migrate_to, Migration};
+pub use outbox::OutboxEvent;
+pub use page::{Page, PageRequest};
+#[cfg(any(debug_assertions, test))]
+pub use provision::{provision_tables, LocalTable};
+pub use repo::{Entity, VersionedRepo};
+#[cfg(test)]
+pub use schema::{assert_indexes_unchanged, assert_schema_unchanged};
+pub use schema::{entity_indexes, entity_schema};
+pub use storage::{
+    BatchWrite, ConditionCheck, Delete, DynamoDbStorage, Item, ItemPage, Put, Query, Scan, Storage,
+    StorageError, TransactWrite, UpdateItem,
+};
+pub use stream::{handle_stream, Change, StreamHandler};
+pub use tenant::Tenant;
+pub use transaction::Transaction;
+pub use unique::Unique;
+pub use update::{Field, Number, Update};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Versioned<T> {
+    #[serde(flatten)]
+    pub data: T,
+    pub data_version: u16,
+    pub last_write: i64,
+    /// Epoch millis of the soft delete, see `VersionedRepo::soft_delete`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    /// Epoch seconds after which DynamoDB TTL deletes the item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl<T: Entity> Versioned<T> {
+    /// Wraps new data in the current `data_version` of the entity.
+    pub fn new(data: T) -> Self {
+        Self {
+            data,
+            data_version: current_version::<T>(),
+            last_write: chrono::Utc::now().timestamp_millis(),
+            deleted_at: None,
+            expires_at: None,
+        }
+    }
+
+    /// Soft deleted or expired, but not yet removed by DynamoDB TTL.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+            || self
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= chrono::Utc::now().timestamp())
+    }
+}
+
+pub fn to_item<T: Serialize>(
+    item: &T,
+) -> Result<HashMap<String, AttributeValue>, serde_dynamo::Error> {
+    serde_dynamo::to_item(item)
+}
+
+pub fn from_item<T: for<'a> Deserialize<'a>>(
+    item: HashMap<String, AttributeValue>,
+) -> Result<T, serde_dynamo::Error> {
+    serde_dynamo::from_item(item)
+}
@@ -0,0 +1,309 @@
+use crate::shared::dynamodb::key::PK;
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{Entity, Key, Put, RepoError, TransactWrite, VersionedRepo};
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::AttributeValue;
+use prost::{Message, Name};
+use std::collections::HashMap;
+
+/// Seconds an event stays in the outbox table before DynamoDB TTL removes it.
+const RETENTION: i64 = 7 * 24 * 3600;
+
+/// An event of `VersionedRepo::publishing`, not yet assigned to a write.
+#[derive(Clone, Debug)]
+pub(super) struct PendingEvent {
+    event_type: String,
+    payload: Vec<u8>,
+}
+
+///
+/// A domain event written to the outbox table together with the entity it is about.
+///
+/// The relay publishes it from the stream of the outbox table, see `shared::events`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxEvent {
+    /// Unique id of the event, the deduplication id of its message.
+    pub id: String,
+    /// Full name of the protobuf message, e.g. `user_events.UserCreated`.
+    pub event_type: String,
+    /// The protobuf encoded message.
+    pub payload: Vec<u8>,
+    /// `{entity}#{key}` of the written entity. Events of the same source are published in order.
+    pub source: String,
+    /// Epoch millis of the write.
+    pub created_at: i64,
+}
+
+impl OutboxEvent {
+    /// Reads an event from an item of the outbox table, e.g. the new image of a stream record.
+    pub fn from_item(mut item: Item) -> Result<Self, RepoError> {
+        let invalid = || RepoError::Transport("Invalid outbox event".into());
+        let mut string = |name: &str| match item.remove(name) {
+            Some(AttributeValue::S(value)) => Ok(value),
+            _ => Err(invalid()),
+        };
+
+        let id = string(PK)?;
+        let event_type = string("event_type")?;
+        let source = string("source")?;
+        let created_at = match item.remove("created_at") {
+            Some(AttributeValue::N(n)) => n.parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        let Some(AttributeValue::B(payload)) = item.remove("payload") else {
+            return Err(invalid());
+        };
+
+        Ok(Self {
+            id,
+            event_type,
+            payload: payload.into_inner(),
+            source,
+            created_at,
+        })
+    }
+
+    fn to_item(&self) -> Item {
+        HashMap::from([
+            (PK.to_string(), AttributeValue::S(self.id.clone())),
+            (
+                "event_type".to_string(),
+                AttributeValue::S(self.event_type.clone()),
+            ),
+            (
+                "payload".to_string(),
+                AttributeValue::B(Blob::new(self.payload.clone())),
+            ),
+            ("source".to_string(), AttributeValue::S(self.source.clone())),
+            (
+                "created_at".to_string(),
+                AttributeValue::N(self.created_at.to_string()),
+            ),
+            (
+                "expires_at".to_string(),
+                AttributeValue::N((self.created_at / 1000 + RETENTION).to_string()),
+            ),
+        ])
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Writes the events of `publishing` into the outbox table, in one transaction with the entity.
+    ///
+    /// The outbox table has the key `pk`, TTL on `expires_at` and a stream consumed by the relay.
+    /// It can be shared by all entities.
+    ///
+    pub fn with_outbox(mut self, outbox_table: impl Into<String>) -> Self {
+        self.outbox_table = Some(outbox_table.into());
+        self
+    }
+
+    ///
+    /// The repository publishing `event` with its writes, e.g. `UserCreated` with the insert of a user.
+    ///
+    /// The event is only published if the write succeeds. Each write of the returned repository
+    /// publishes it again, so use it for a single insert, update, patch, delete or `Transaction` operation.
+    /// Batch writes reject it.
+    ///
+    pub fn publishing<M: Message + Name>(&self, event: &M) -> Self {
+        let mut repo = self.clone();
+        repo.events.push(PendingEvent {
+            event_type: M::full_name(),
+            payload: event.encode_to_vec(),
+        });
+        repo
+    }
+
+    /// The puts of the pending events into the outbox for a write of `item`, or of the item with this key.
+    pub(super) fn outbox_records(&self, item: &Item) -> Result<Vec<TransactWrite>, RepoError> {
+        if self.events.is_empty() {
+            return Ok(Vec::new());
+        }
+        let Some(outbox_table) = &self.outbox_table else {
+            return Err(RepoError::Transport(
+                format!("The repository of {} has no outbox", T::NAME).into(),
+            ));
+        };
+        let Some(key) = Key::from_item::<T>(item) else {
+            return Err(RepoError::Transport(
+                format!("The item is no {}", T::NAME).into(),
+            ));
+        };
+
+        let source = match &key.sk {
+            Some(sk) => format!("{}#{}#{}", T::NAME, key.pk, sk),
+            None => format!("{}#{}", T::NAME, key.pk),
+        };
+        let created_at = chrono::Utc::now().timestamp_millis();
+        Ok(self
+            .events
+            .iter()
+            .map(|event| {
+                let event = OutboxEvent {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    event_type: event.event_type.clone(),
+                    payload: event.payload.clone(),
+                    source: source.clone(),
+                    created_at,
+                };
+                TransactWrite::Put(Put {
+                    table: outbox_table.clone(),
+                    item: event.to_item(),
+                    condition: Some("attribute_not_exists(pk)".to_string()),
+                    ..Default::default()
+                })
+            })
+            .collect())
+    }
+
+    /// Fails if events are pending for a write that cannot be transactional.
+    pub(super) fn no_events(&self) -> Result<(), RepoError> {
+        if self.events.is_empty() {
+            Ok(())
+        } else {
+            Err(RepoError::Transport(
+                "Events cannot be published with batch writes".into(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity_field;
+    use crate::shared::dynamodb::{MemoryStorage, Transaction, Update, Versioned};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Account {
+        id: String,
+        logins: u32,
+    }
+
+    impl Entity for Account {
+        const NAME: &'static str = "account";
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    /// Stand-in for a message generated from `protocols/`.
+    #[derive(Clone, PartialEq, Message)]
+    struct AccountChanged {
+        #[prost(string, tag = "1")]
+        id: String,
+    }
+
+    impl Name for AccountChanged {
+        const NAME: &'static str = "AccountChanged";
+        const PACKAGE: &'static str = "account_events";
+    }
+
+    fn repo(storage: &MemoryStorage) -> VersionedRepo<Account> {
+        storage.create_table("outbox", &[]);
+        storage.repo::<Account>("accounts").with_outbox("outbox")
+    }
+
+    fn account(id: &str) -> Account {
+        Account {
+            id: id.to_string(),
+            logins: 0,
+        }
+    }
+
+    fn changed(id: &str) -> AccountChanged {
+        AccountChanged { id: id.to_string() }
+    }
+
+    fn events(storage: &MemoryStorage) -> Vec<OutboxEvent> {
+        let mut events: Vec<_> = storage
+            .items("outbox")
+            .into_iter()
+            .map(|item| OutboxEvent::from_item(item).unwrap())
+            .collect();
+        events.sort_by_key(|event| AccountChanged::decode(&*event.payload).unwrap().id);
+        events
+    }
+
+    #[tokio::test]
+    async fn writes_events_with_the_entity() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage);
+
+        repo.publishing(&changed("1"))
+            .insert(account("1"))
+            .await
+            .unwrap();
+        repo.publishing(&changed("2"))
+            .patch("1", Update::new().add(entity_field!(Account, logins), 1))
+            .await
+            .unwrap();
+        repo.insert(account("3")).await.unwrap();
+
+        let events = events(&storage);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event_type, "account_events.AccountChanged");
+        assert_eq!(events[0].source, "account#1");
+        assert_ne!(events[0].id, events[1].id);
+    }
+
+    #[tokio::test]
+    async fn failed_writes_publish_nothing() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage);
+        repo.insert(account("1")).await.unwrap();
+        let stored = repo.read("1").await.unwrap().unwrap();
+
+        let publishing = repo.publishing(&changed("1"));
+        assert!(matches!(
+            publishing.insert(account("1")).await,
+            Err(RepoError::AlreadyExists)
+        ));
+        let stale = Versioned {
+            last_write: stored.last_write - 1,
+            ..stored.clone()
+        };
+        assert!(matches!(
+            publishing.update(&stale).await,
+            Err(RepoError::Conflict)
+        ));
+        assert!(publishing.batch_put(vec![account("2")]).await.is_err());
+
+        assert!(storage.items("outbox").is_empty());
+    }
+
+    #[tokio::test]
+    async fn transactions_write_the_events_of_their_repositories() {
+        let storage = MemoryStorage::new();
+        let repo = repo(&storage);
+        repo.insert(account("1")).await.unwrap();
+        let stored = repo.read("1").await.unwrap().unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction
+            .insert(&repo.publishing(&changed("2")), account("2"))
+            .unwrap();
+        transaction.delete(&repo.publishing(&changed("1")), "1", stored.last_write);
+        transaction.commit().await.unwrap();
+
+        let sources: Vec<_> = events(&storage).into_iter().map(|e| e.source).collect();
+        assert_eq!(sources, vec!["account#1", "account#2"]);
+    }
+
+    #[tokio::test]
+    async fn events_need_an_outbox() {
+        let storage = MemoryStorage::new();
+        let repo = storage.repo::<Account>("accounts");
+        assert!(repo
+            .publishing(&changed("1"))
+            .insert(account("1"))
+            .await
+            .is_err());
+        assert!(repo.read("1").await.unwrap().is_none());
+    }
+}
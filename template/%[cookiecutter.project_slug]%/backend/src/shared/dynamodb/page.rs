@@ -0,0 +1,421 @@
+use crate::shared::dynamodb::key::entity_filter;
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{
+    from_item, to_item, Entity, Query, RepoError, Scan, Versioned, VersionedRepo,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use base64::{engine::general_purpose, Engine as _};
+use futures::stream::{self, Stream, TryStreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::future::Future;
+
+/// Requests a page of a list or query.
+#[derive(Clone, Debug, Default)]
+pub struct PageRequest {
+    /// Maximum number of items to evaluate for the page.
+    pub limit: Option<u32>,
+    /// The `next` cursor of the previous page.
+    pub cursor: Option<String>,
+}
+
+impl PageRequest {
+    pub fn first(limit: u32) -> Self {
+        Self {
+            limit: Some(limit),
+            cursor: None,
+        }
+    }
+
+    pub fn after(limit: u32, cursor: impl Into<String>) -> Self {
+        Self {
+            limit: Some(limit),
+            cursor: Some(cursor.into()),
+        }
+    }
+}
+
+/// A page of items and the opaque cursor to request the next one.
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<Versioned<T>>,
+    /// `None` on the last page.
+    pub next: Option<String>,
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    /// Lists a page of all items of the table.
+    pub async fn list(&self, page: PageRequest) -> Result<Page<T>, RepoError> {
+        let context = self.table_name.clone();
+        let start = self.decode_cursor(&context, page.cursor.as_deref())?;
+        let (items, last) = self.scan_page(page.limit, start).await?;
+        Ok(Page {
+            items,
+            next: self.encode_cursor(&context, last)?,
+        })
+    }
+
+    /// Streams all items of the table, requesting page by page.
+    pub fn list_all(&self) -> impl Stream<Item = Result<Versioned<T>, RepoError>> + '_ {
+        exhaust(move |start| self.scan_page(None, start))
+    }
+
+    /// Queries a page of the items of a global secondary index with the given partition key value.
+    pub async fn query_index(
+        &self,
+        index_name: &str,
+        attribute: &str,
+        value: &str,
+        page: PageRequest,
+    ) -> Result<Page<T>, RepoError> {
+        // a cursor is only valid for the query it was issued for
+        let context = format!("{}/{}/{}={}", self.table_name, index_name, attribute, value);
+        let start = self.decode_cursor(&context, page.cursor.as_deref())?;
+        let (items, last) = self
+            .query_page(index_name, attribute, value, page.limit, start)
+            .await?;
+        Ok(Page {
+            items,
+            next: self.encode_cursor(&context, last)?,
+        })
+    }
+
+    /// Streams all items of a global secondary index with the given partition key value.
+    pub fn query_index_all<'a>(
+        &'a self,
+        index_name: &'a str,
+        attribute: &'a str,
+        value: &'a str,
+    ) -> impl Stream<Item = Result<Versioned<T>, RepoError>> + 'a {
+        exhaust(move |start| self.query_page(index_name, attribute, value, None, start))
+    }
+
+    ///
+    /// Runs a page of the query and returns the raw items with the cursor of the next page.
+    ///
+    /// `context` identifies the query, cursors of other queries are rejected.
+    ///
+    pub(super) async fn query_items(
+        &self,
+        query: Query,
+        context: &str,
+        page: PageRequest,
+    ) -> Result<(Vec<Item>, Option<String>), RepoError> {
+        let start = self.decode_cursor(context, page.cursor.as_deref())?;
+        let resp = self
+            .storage
+            .query(Query {
+                limit: page.limit,
+                start,
+                ..query
+            })
+            .await?;
+        let next = self.encode_cursor(context, resp.last_key)?;
+        Ok((resp.items, next))
+    }
+
+    async fn scan_page(
+        &self,
+        limit: Option<u32>,
+        start: Option<Item>,
+    ) -> Result<(Vec<Versioned<T>>, Option<Item>), RepoError> {
+        let (mut filter, values) = entity_filter::<T>().unzip();
+        let mut values = values.unwrap_or_default();
+        self.filter_tenant(&mut filter, &mut values)?;
+        let resp = self
+            .storage
+            .scan(Scan {
+                table: self.table_name.clone(),
+                filter,
+                values,
+                limit,
+                start,
+                ..Default::default()
+            })
+            .await?;
+
+        let mut items = Vec::new();
+        for item in resp.items {
+            items.extend(self.decode_visible(item).await?);
+        }
+        Ok((items, resp.last_key))
+    }
+
+    async fn query_page(
+        &self,
+        index_name: &str,
+        attribute: &str,
+        value: &str,
+        limit: Option<u32>,
+        start: Option<Item>,
+    ) -> Result<(Vec<Versioned<T>>, Option<Item>), RepoError> {
+        let mut filter = None;
+        let mut values =
+            HashMap::from([(":value".to_string(), AttributeValue::S(value.to_string()))]);
+        self.filter_tenant(&mut filter, &mut values)?;
+        let resp = self
+            .storage
+            .query(Query {
+                table: self.table_name.clone(),
+                index: Some(index_name.to_string()),
+                key_condition: "#attribute = :value".to_string(),
+                filter,
+                names: HashMap::from([("#attribute".to_string(), attribute.to_string())]),
+                values,
+                limit,
+                start,
+                ..Default::default()
+            })
+            .await?;
+
+        let mut items = Vec::new();
+        for item in resp.items {
+            items.extend(self.decode_visible(item).await?);
+        }
+        Ok((items, resp.last_key))
+    }
+
+    fn encode_cursor(&self, context: &str, key: Option<Item>) -> Result<Option<String>, RepoError> {
+        let Some(key) = key else {
+            return Ok(None);
+        };
+        let json: serde_json::Value = from_item(key)?;
+        let payload = general_purpose::URL_SAFE_NO_PAD.encode(json.to_string());
+        let signature = self.cursor_mac(context, &payload).finalize().into_bytes();
+        Ok(Some(format!(
+            "{}.{}",
+            payload,
+            general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )))
+    }
+
+    fn decode_cursor(
+        &self,
+        context: &str,
+        cursor: Option<&str>,
+    ) -> Result<Option<Item>, RepoError> {
+        let Some(cursor) = cursor else {
+            return Ok(None);
+        };
+        let (payload, signature) = cursor.split_once('.').ok_or(RepoError::InvalidCursor)?;
+        let signature = general_purpose::URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| RepoError::InvalidCursor)?;
+        self.cursor_mac(context, payload)
+            .verify_slice(&signature)
+            .map_err(|_| RepoError::InvalidCursor)?;
+
+        let json = general_purpose::URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| RepoError::InvalidCursor)?;
+        let key: serde_json::Value =
+            serde_json::from_slice(&json).map_err(|_| RepoError::InvalidCursor)?;
+        Ok(Some(to_item(&key)?))
+    }
+
+    fn cursor_mac(&self, context: &str, payload: &str) -> Hmac<Sha256> {
+        let secret = match &self.cursor_secret {
+            Some(secret) => secret.to_vec(),
+            None => cursor_secret(),
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret).expect("HMAC accepts any key size");
+        // a cursor of one tenant is not valid for another
+        if let Ok(Some(tenant)) = self.scope() {
+            mac.update(tenant.id().as_bytes());
+            mac.update(b"\n");
+        }
+        mac.update(context.as_bytes());
+        mac.update(b"\n");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// Streams the items of all pages fetched by `fetch` from the start key of the previous page.
+fn exhaust<'a, T, F, Fut>(fetch: F) -> impl Stream<Item = Result<Versioned<T>, RepoError>> + 'a
+where
+    T: 'a,
+    F: Fn(Option<Item>) -> Fut + 'a,
+    Fut: Future<Output = Result<(Vec<Versioned<T>>, Option<Item>), RepoError>> + 'a,
+{
+    // state: None if done, Some(start key) otherwise
+    stream::try_unfold(Some(None), move |state: Option<Option<Item>>| {
+        let page = state.map(&fetch);
+        async move {
+            let Some(page) = page else {
+                return Ok(None);
+            };
+            let (items, last) = page.await?;
+            let items = stream::iter(items.into_iter().map(Ok));
+            Ok::<_, RepoError>(Some((items, last.map(Some))))
+        }
+    })
+    .try_flatten()
+}
+
+// Secret to sign cursors so that clients cannot forge start keys
+#[cfg(any(debug_assertions, test))]
+fn cursor_secret() -> Vec<u8> {
+    std::env::var("CURSOR_SECRET")
+        .unwrap_or_else(|_| "local-cursor-secret".to_string())
+        .into_bytes()
+}
+
+#[cfg(not(any(debug_assertions, test)))]
+fn cursor_secret() -> Vec<u8> {
+    std::env::var("CURSOR_SECRET")
+        .expect("CURSOR_SECRET must be set")
+        .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::Client;
+    use futures::TryStreamExt;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Thing {
+        id: String,
+    }
+
+    impl Entity for Thing {
+        const NAME: &'static str = "thing";
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Thing> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "things".to_string())
+            .with_cursor_secret("test-secret")
+    }
+
+    fn item(id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "pk": {"S": id},
+            "id": {"S": id},
+            "data_version": {"N": "1"},
+            "last_write": {"N": "1"}
+        })
+    }
+
+    /// Two pages: 1, 2 and 3
+    async fn mock_pages(server: &MockServer, target: &str) {
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", target))
+            .and(body_partial_json(serde_json::json!({
+                "ExclusiveStartKey": {"pk": {"S": "2"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [item("3")],
+                "Count": 1
+            })))
+            .with_priority(1)
+            .mount(server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", target))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Items": [item("1"), item("2")],
+                "Count": 2,
+                "LastEvaluatedKey": {"pk": {"S": "2"}}
+            })))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn list_continues_with_cursor() {
+        let server = MockServer::start().await;
+        mock_pages(&server, "DynamoDB_20120810.Scan").await;
+        let repo = repo(&server).await;
+
+        let first = repo.list(PageRequest::first(2)).await.unwrap();
+        assert_eq!(first.items.len(), 2);
+        let cursor = first.next.unwrap();
+
+        let second = repo.list(PageRequest::after(2, cursor)).await.unwrap();
+        assert_eq!(second.items[0].data.id, "3");
+        assert!(second.next.is_none());
+    }
+
+    #[tokio::test]
+    async fn list_all_streams_all_pages() {
+        let server = MockServer::start().await;
+        mock_pages(&server, "DynamoDB_20120810.Scan").await;
+        let repo = repo(&server).await;
+
+        let ids: Vec<String> = repo
+            .list_all()
+            .map_ok(|thing| thing.data.id)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(ids, vec!["1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn query_index_all_streams_all_pages() {
+        let server = MockServer::start().await;
+        mock_pages(&server, "DynamoDB_20120810.Query").await;
+        let repo = repo(&server).await;
+
+        let things: Vec<_> = repo
+            .query_index_all("some-index", "attr", "value")
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(things.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_cursors() {
+        let server = MockServer::start().await;
+        mock_pages(&server, "DynamoDB_20120810.Query").await;
+        let repo = repo(&server).await;
+
+        let first = repo
+            .query_index("some-index", "attr", "value", PageRequest::first(2))
+            .await
+            .unwrap();
+        let cursor = first.next.unwrap();
+
+        // forged start key with the original signature
+        let (_, signature) = cursor.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(r#"{"pk":"1"}"#),
+            signature
+        );
+        let result = repo
+            .query_index("some-index", "attr", "value", PageRequest::after(2, forged))
+            .await;
+        assert!(matches!(result, Err(RepoError::InvalidCursor)));
+
+        // valid cursor of another query
+        let result = repo
+            .query_index(
+                "some-index",
+                "attr",
+                "other",
+                PageRequest::after(2, &cursor),
+            )
+            .await;
+        assert!(matches!(result, Err(RepoError::InvalidCursor)));
+
+        // signed with another secret
+        let other = repo.clone().with_cursor_secret("other-secret");
+        let result = other
+            .query_index("some-index", "attr", "value", PageRequest::after(2, cursor))
+            .await;
+        assert!(matches!(result, Err(RepoError::InvalidCursor)));
+    }
+}
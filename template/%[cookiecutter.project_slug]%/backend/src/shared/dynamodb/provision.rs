@@ -0,0 +1,423 @@
+use crate::shared::dynamodb::key::{PK, SK};
+use crate::shared::dynamodb::{Entity, Index, KeyType, Projection, RepoError};
+use aws_sdk_dynamodb::types::{
+    self, AttributeDefinition, BillingMode, CreateGlobalSecondaryIndexAction, GlobalSecondaryIndex,
+    GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, ProjectionType, ScalarAttributeType,
+    TableDescription, TableStatus,
+};
+use aws_sdk_dynamodb::Client;
+use std::time::Duration;
+
+/// Delay between two checks whether a table is active.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Checks until waiting for a table gives up (one minute).
+const MAX_POLLS: u32 = 300;
+
+///
+/// A table to create locally, with the same keys and indexes the CDK `VersionedTable` creates in AWS.
+///
+/// ```ignore
+/// let orders = LocalTable::new("orders")
+///     .with_sort_key()
+///     .entity::<UserData>()
+///     .entity::<OrderData>();
+/// provision_tables(&client, &[orders]).await?;
+/// ```
+///
+#[derive(Clone, Debug)]
+pub struct LocalTable {
+    name: String,
+    sort_key: bool,
+    indexes: Vec<Index>,
+}
+
+impl LocalTable {
+    /// A table with the partition key `pk` and no indexes.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            sort_key: false,
+            indexes: Vec::new(),
+        }
+    }
+
+    /// Adds the sort key `sk` of tables with composite keys.
+    pub fn with_sort_key(mut self) -> Self {
+        self.sort_key = true;
+        self
+    }
+
+    /// Adds the `Entity::INDEXES` of an entity stored in the table.
+    pub fn entity<T: Entity>(mut self) -> Self {
+        for index in T::INDEXES {
+            if !self.indexes.iter().any(|i| i.name == index.name) {
+                self.indexes.push(*index);
+            }
+        }
+        self
+    }
+
+    fn key_schema(&self) -> Vec<KeySchemaElement> {
+        let sort_key = self.sort_key.then_some((SK, KeyType::S));
+        key_schema((PK, KeyType::S), sort_key)
+    }
+
+    /// The definitions of the key attributes of the table and the given indexes.
+    fn attribute_definitions(
+        &self,
+        indexes: &[&Index],
+    ) -> Result<Vec<AttributeDefinition>, RepoError> {
+        let mut keys = vec![(PK, KeyType::S)];
+        if self.sort_key {
+            keys.push((SK, KeyType::S));
+        }
+        for index in indexes {
+            keys.push(index.partition_key);
+            keys.extend(index.sort_key);
+        }
+
+        let mut definitions: Vec<AttributeDefinition> = Vec::new();
+        for (name, key_type) in keys {
+            let key_type = attribute_type(key_type);
+            match definitions.iter().find(|d| d.attribute_name() == name) {
+                Some(d) if *d.attribute_type() != key_type => {
+                    return Err(RepoError::Transport(
+                        format!(
+                            "Key attribute {} of table {} is declared with different types",
+                            name, self.name
+                        )
+                        .into(),
+                    ));
+                }
+                Some(_) => {}
+                None => definitions.push(
+                    AttributeDefinition::builder()
+                        .attribute_name(name)
+                        .attribute_type(key_type)
+                        .build()
+                        .expect("name and type are set"),
+                ),
+            }
+        }
+        Ok(definitions)
+    }
+}
+
+///
+/// Creates the tables and global secondary indexes that are missing, e.g. on a fresh LocalStack.
+///
+/// Existing tables and indexes are left as they are, so it is safe to call on every start
+/// and from integration tests. Returns once the tables and their indexes are active.
+/// In AWS the tables are created by the CDK, so this is only compiled in debug builds and tests.
+///
+pub async fn provision_tables(client: &Client, tables: &[LocalTable]) -> Result<(), RepoError> {
+    for table in tables {
+        let description = match describe(client, &table.name).await? {
+            Some(description) => description,
+            None => create(client, table).await?,
+        };
+
+        if description.key_schema() != table.key_schema().as_slice() {
+            return Err(RepoError::Transport(
+                format!(
+                    "Table {} exists with another key schema, delete it to recreate it",
+                    table.name
+                )
+                .into(),
+            ));
+        }
+
+        let existing: Vec<&str> = description
+            .global_secondary_indexes()
+            .iter()
+            .filter_map(|index| index.index_name())
+            .collect();
+        let missing: Vec<&Index> = table
+            .indexes
+            .iter()
+            .filter(|index| !existing.contains(&index.name))
+            .collect();
+
+        // DynamoDB creates one index per request and table
+        for index in missing {
+            let create = CreateGlobalSecondaryIndexAction::builder()
+                .index_name(index.name)
+                .set_key_schema(Some(key_schema(index.partition_key, index.sort_key)))
+                .projection(projection(index))
+                .build()
+                .expect("name, key schema and projection are set");
+            let result = client
+                .update_table()
+                .table_name(&table.name)
+                .set_attribute_definitions(Some(table.attribute_definitions(&[index])?))
+                .global_secondary_index_updates(
+                    GlobalSecondaryIndexUpdate::builder().create(create).build(),
+                )
+                .send()
+                .await;
+            match result {
+                Ok(_) => {}
+                // another process is changing the table, e.g. a concurrently started lambda
+                Err(e)
+                    if e.as_service_error()
+                        .is_some_and(|e| e.is_resource_in_use_exception()) => {}
+                Err(e) => return Err(e.into()),
+            }
+            wait_until_active(client, &table.name).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn describe(client: &Client, table: &str) -> Result<Option<TableDescription>, RepoError> {
+    match client.describe_table().table_name(table).send().await {
+        Ok(output) => Ok(output.table),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_resource_not_found_exception()) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn create(client: &Client, table: &LocalTable) -> Result<TableDescription, RepoError> {
+    let indexes: Vec<&Index> = table.indexes.iter().collect();
+    let global_secondary_indexes = indexes
+        .iter()
+        .map(|index| {
+            GlobalSecondaryIndex::builder()
+                .index_name(index.name)
+                .set_key_schema(Some(key_schema(index.partition_key, index.sort_key)))
+                .projection(projection(index))
+                .build()
+                .expect("name and key schema are set")
+        })
+        .collect::<Vec<_>>();
+
+    let result = client
+        .create_table()
+        .table_name(&table.name)
+        .set_key_schema(Some(table.key_schema()))
+        .set_attribute_definitions(Some(table.attribute_definitions(&indexes)?))
+        .set_global_secondary_indexes(
+            (!global_secondary_indexes.is_empty()).then_some(global_secondary_indexes),
+        )
+        .billing_mode(BillingMode::PayPerRequest)
+        .send()
+        .await;
+    match result {
+        Ok(_) => {}
+        // created concurrently, e.g. by another lambda
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_resource_in_use_exception()) => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    wait_until_active(client, &table.name).await
+}
+
+/// Polls the table until it and all of its indexes are active.
+async fn wait_until_active(client: &Client, table: &str) -> Result<TableDescription, RepoError> {
+    for _ in 0..MAX_POLLS {
+        if let Some(description) = describe(client, table).await? {
+            let indexes_active = description.global_secondary_indexes().iter().all(|index| {
+                index
+                    .index_status()
+                    .is_none_or(|s| *s == IndexStatus::Active)
+            });
+            if description.table_status() == Some(&TableStatus::Active) && indexes_active {
+                return Ok(description);
+            }
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    Err(RepoError::Transport(
+        format!("Table {} did not become active", table).into(),
+    ))
+}
+
+fn key_schema(
+    partition_key: (&str, KeyType),
+    sort_key: Option<(&str, KeyType)>,
+) -> Vec<KeySchemaElement> {
+    let element = |name: &str, key_type| {
+        KeySchemaElement::builder()
+            .attribute_name(name)
+            .key_type(key_type)
+            .build()
+            .expect("name and key type are set")
+    };
+    let mut schema = vec![element(partition_key.0, types::KeyType::Hash)];
+    if let Some((name, _)) = sort_key {
+        schema.push(element(name, types::KeyType::Range));
+    }
+    schema
+}
+
+fn projection(index: &Index) -> types::Projection {
+    match index.projection {
+        Projection::All => types::Projection::builder()
+            .projection_type(ProjectionType::All)
+            .build(),
+        Projection::KeysOnly => types::Projection::builder()
+            .projection_type(ProjectionType::KeysOnly)
+            .build(),
+        Projection::Include(attributes) => types::Projection::builder()
+            .projection_type(ProjectionType::Include)
+            .set_non_key_attributes(Some(attributes.iter().map(|a| a.to_string()).collect()))
+            .build(),
+    }
+}
+
+fn attribute_type(key_type: KeyType) -> ScalarAttributeType {
+    match key_type {
+        KeyType::S => ScalarAttributeType::S,
+        KeyType::N => ScalarAttributeType::N,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::users::UserData;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn client(server: &MockServer) -> Client {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        Client::new(&shared_config)
+    }
+
+    fn users_table(indexes: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "Table": {
+                "TableName": "users",
+                "TableStatus": "ACTIVE",
+                "KeySchema": [{"AttributeName": "pk", "KeyType": "HASH"}],
+                "GlobalSecondaryIndexes": indexes
+            }
+        })
+    }
+
+    async fn mock_describe(server: &MockServer, response: ResponseTemplate, times: Option<u64>) {
+        let mock = Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.DescribeTable"))
+            .respond_with(response);
+        match times {
+            Some(times) => mock.up_to_n_times(times).mount(server).await,
+            None => mock.mount(server).await,
+        }
+    }
+
+    #[tokio::test]
+    async fn creates_missing_table_with_indexes() {
+        let server = MockServer::start().await;
+        mock_describe(
+            &server,
+            ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ResourceNotFoundException",
+                "message": "Requested resource not found"
+            })),
+            Some(1),
+        )
+        .await;
+        mock_describe(
+            &server,
+            ResponseTemplate::new(200).set_body_json(users_table(serde_json::json!([
+                {"IndexName": "email-index", "IndexStatus": "ACTIVE"}
+            ]))),
+            None,
+        )
+        .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.CreateTable"))
+            .and(body_partial_json(serde_json::json!({
+                "TableName": "users",
+                "BillingMode": "PAY_PER_REQUEST",
+                "KeySchema": [{"AttributeName": "pk", "KeyType": "HASH"}],
+                "AttributeDefinitions": [
+                    {"AttributeName": "pk", "AttributeType": "S"},
+                    {"AttributeName": "email", "AttributeType": "S"}
+                ],
+                "GlobalSecondaryIndexes": [{
+                    "IndexName": "email-index",
+                    "KeySchema": [{"AttributeName": "email", "KeyType": "HASH"}],
+                    "Projection": {"ProjectionType": "ALL"}
+                }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let users = LocalTable::new("users").entity::<UserData>();
+        provision_tables(&client(&server).await, &[users])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn adds_missing_index_to_existing_table() {
+        let server = MockServer::start().await;
+        mock_describe(
+            &server,
+            ResponseTemplate::new(200).set_body_json(users_table(serde_json::json!([]))),
+            Some(1),
+        )
+        .await;
+        mock_describe(
+            &server,
+            ResponseTemplate::new(200).set_body_json(users_table(serde_json::json!([
+                {"IndexName": "email-index", "IndexStatus": "ACTIVE"}
+            ]))),
+            None,
+        )
+        .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateTable"))
+            .and(body_partial_json(serde_json::json!({
+                "TableName": "users",
+                "AttributeDefinitions": [
+                    {"AttributeName": "pk", "AttributeType": "S"},
+                    {"AttributeName": "email", "AttributeType": "S"}
+                ],
+                "GlobalSecondaryIndexUpdates": [{"Create": {"IndexName": "email-index"}}]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.CreateTable"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let users = LocalTable::new("users").entity::<UserData>();
+        provision_tables(&client(&server).await, &[users])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_table_with_other_key_schema() {
+        let server = MockServer::start().await;
+        mock_describe(
+            &server,
+            ResponseTemplate::new(200).set_body_json(users_table(serde_json::json!([]))),
+            None,
+        )
+        .await;
+
+        let orders = LocalTable::new("users").with_sort_key();
+        let result = provision_tables(&client(&server).await, &[orders]).await;
+        assert!(matches!(result, Err(RepoError::Transport(_))));
+    }
+}
@@ -0,0 +1,721 @@
+use crate::shared::dynamodb::outbox::PendingEvent;
+use crate::shared::dynamodb::{
+    from_item, migrate, Delete, DynamoDbStorage, Index, Key, Migration, Put, RepoError, Storage,
+    StorageError, Tenant, TransactWrite, Unique, Versioned, WriteOperation,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use futures::TryStreamExt;
+use lambda_http::tracing;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::pin::pin;
+use std::sync::Arc;
+
+///
+/// A business struct that is stored as `Versioned<Self>` in a table.
+///
+/// The implementation is the key-extraction strategy of the repository:
+/// it tells which values of the struct are used as partition and sort key.
+///
+/// Several entities can share a table (single-table design) if they all have a sort key.
+/// Their items are told apart by the key prefixes, e.g. `USER#` and `ORDER#`.
+///
+pub trait Entity:
+    Serialize + DeserializeOwned + JsonSchema + Clone + Send + Sync + 'static
+{
+    /// Snake case name of the entity, e.g. for its schema snapshot `schema/{NAME}.schema.json`.
+    const NAME: &'static str;
+
+    ///
+    /// Chain of migrations from older shapes of the stored JSON.
+    ///
+    /// `MIGRATIONS[0]` upgrades `data_version` 1 to 2, `MIGRATIONS[1]` 2 to 3 and so on.
+    /// The current `data_version` is the number of migrations + 1.
+    ///
+    const MIGRATIONS: &'static [Migration] = &[];
+
+    /// Global secondary indexes of the table, declared with `#[derive(Indexes)]`.
+    const INDEXES: &'static [Index] = &[];
+
+    /// Attributes whose values must be unique among all items of the entity, see `Unique`.
+    const UNIQUE: &'static [Unique<Self>] = &[];
+
+    /// Prefix of the stored partition key, e.g. `USER#`.
+    const PK_PREFIX: &'static str = "";
+
+    /// Prefix of the stored sort key, e.g. `ORDER#`.
+    const SK_PREFIX: &'static str = "";
+
+    ///
+    /// Items belong to a tenant: their partition keys are prefixed with its id, see `Tenant`.
+    ///
+    /// Repositories of the entity must be scoped with `for_tenant`, otherwise they fail with `Forbidden`.
+    ///
+    const TENANT_SCOPED: bool = false;
+
+    /// The partition key the entity is stored under, without `PK_PREFIX`.
+    fn pk(&self) -> String;
+
+    /// The sort key the entity is stored under, without `SK_PREFIX`. `None` for tables without sort key.
+    fn sk(&self) -> Option<String> {
+        None
+    }
+
+    fn key(&self) -> Key {
+        Key {
+            pk: self.pk(),
+            sk: self.sk(),
+        }
+    }
+}
+
+///
+/// Generic repository for a table storing `Versioned<T>` items.
+///
+/// Inserts fail if the key exists already.
+/// Updates and deletes are conditional on the `last_write` token (optimistic locking).
+/// Soft deleted and expired items are hidden from reads, see `soft_delete`.
+/// The `data_version` is left untouched by writes. It only marks schema changes.
+///
+/// Items of older `data_version`s are migrated lazily on read.
+/// Updates persist the upgraded shape, `with_persisted_migrations` writes it back right on read.
+///
+pub struct VersionedRepo<T> {
+    pub(super) storage: Arc<dyn Storage>,
+    pub(super) table_name: String,
+    persist_migrations: bool,
+    pub(super) cursor_secret: Option<Arc<[u8]>>,
+    pub(super) history_table: Option<String>,
+    pub(super) actor: Option<String>,
+    pub(super) include_deleted: bool,
+    pub(super) outbox_table: Option<String>,
+    pub(super) events: Vec<PendingEvent>,
+    pub(super) tenant: Option<Tenant>,
+    entity: PhantomData<fn() -> T>,
+}
+
+// derive(Clone) would require T: Clone
+impl<T> Clone for VersionedRepo<T> {
+    fn clone(&self) -> Self {
+        Self {
+            storage: self.storage.clone(),
+            table_name: self.table_name.clone(),
+            persist_migrations: self.persist_migrations,
+            cursor_secret: self.cursor_secret.clone(),
+            history_table: self.history_table.clone(),
+            actor: self.actor.clone(),
+            include_deleted: self.include_deleted,
+            outbox_table: self.outbox_table.clone(),
+            events: self.events.clone(),
+            tenant: self.tenant.clone(),
+            entity: PhantomData,
+        }
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self::from_storage(Arc::new(DynamoDbStorage::new(client)), table_name)
+    }
+
+    /// A repository on another storage than DynamoDB, e.g. `MemoryStorage` in tests.
+    pub fn from_storage(storage: Arc<dyn Storage>, table_name: String) -> Self {
+        Self {
+            storage,
+            table_name,
+            persist_migrations: false,
+            cursor_secret: None,
+            history_table: None,
+            actor: None,
+            include_deleted: false,
+            outbox_table: None,
+            events: Vec::new(),
+            tenant: None,
+            entity: PhantomData,
+        }
+    }
+
+    /// Writes migrated items back on read instead of waiting for the next update.
+    pub fn with_persisted_migrations(mut self) -> Self {
+        self.persist_migrations = true;
+        self
+    }
+
+    /// Signs page cursors with this secret instead of the `CURSOR_SECRET` environment variable.
+    pub fn with_cursor_secret(mut self, secret: impl AsRef<[u8]>) -> Self {
+        self.cursor_secret = Some(secret.as_ref().into());
+        self
+    }
+
+    ///
+    /// Fails with `AlreadyExists` if there is an item with the same key.
+    ///
+    /// Expired items not yet removed by DynamoDB TTL are replaced.
+    ///
+    pub async fn insert(&self, data: T) -> Result<(), RepoError> {
+        self.put_new(&Versioned::new(data)).await
+    }
+
+    pub(super) async fn put_new(&self, entity: &Versioned<T>) -> Result<(), RepoError> {
+        let put = Put {
+            table: self.table_name.clone(),
+            item: self.entity_item(entity)?,
+            condition: Some(INSERT_CONDITION.to_string()),
+            values: now_seconds(),
+            ..Default::default()
+        };
+
+        self.write_entity(TransactWrite::Put(put), Some(entity), None, |e| match e {
+            StorageError::ConditionFailed(_) => RepoError::AlreadyExists,
+            e => e.into(),
+        })
+        .await
+    }
+
+    /// Eventually consistent read.
+    pub async fn read(&self, key: impl Into<Key>) -> Result<Option<Versioned<T>>, RepoError> {
+        self.get(&key.into(), false).await
+    }
+
+    /// Strongly consistent read.
+    pub async fn read_strong(
+        &self,
+        key: impl Into<Key>,
+    ) -> Result<Option<Versioned<T>>, RepoError> {
+        self.get(&key.into(), true).await
+    }
+
+    async fn get(&self, key: &Key, consistent: bool) -> Result<Option<Versioned<T>>, RepoError> {
+        let item = self
+            .storage
+            .get(&self.table_name, self.stored_key(key)?, consistent)
+            .await?;
+
+        match item {
+            Some(item) => self.decode_visible(item).await,
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Returns the first item of a global secondary index with the given partition key value.
+    ///
+    /// Follows `LastEvaluatedKey` until an item is found, as a page may be empty.
+    ///
+    pub async fn find_by_index(
+        &self,
+        index_name: &str,
+        attribute: &str,
+        value: &str,
+    ) -> Result<Option<Versioned<T>>, RepoError> {
+        let mut items = pin!(self.query_index_all(index_name, attribute, value));
+        items.try_next().await
+    }
+
+    ///
+    /// Replaces the item if it was not written since the entity was read.
+    ///
+    /// The write is conditional on the `last_write` token of the given entity.
+    /// Returns the entity with its new `last_write` token.
+    /// Fails with `Conflict` if the item was written meanwhile and `NotFound` if it was deleted.
+    ///
+    pub async fn update(&self, entity: &Versioned<T>) -> Result<Versioned<T>, RepoError> {
+        let updated = Versioned {
+            last_write: next_write(entity.last_write),
+            ..entity.clone()
+        };
+        let put = Put {
+            table: self.table_name.clone(),
+            item: self.entity_item(&updated)?,
+            condition: Some("last_write = :expected".to_string()),
+            values: expected(entity.last_write),
+            ..Default::default()
+        };
+
+        let previous = self.previous_if_needed(&entity.data.key()).await?;
+        self.write_entity(
+            TransactWrite::Put(put),
+            Some(&updated),
+            previous
+                .as_ref()
+                .map(|previous| (previous, WriteOperation::Update, updated.last_write)),
+            not_found_if_missing,
+        )
+        .await?;
+
+        Ok(updated)
+    }
+
+    ///
+    /// Reads the item, applies `change` and updates it.
+    ///
+    /// If the item was written concurrently (or the eventually consistent read was stale)
+    /// the change is retried once on a strongly consistent read.
+    /// Returns `None` if there is no item for the key.
+    ///
+    pub async fn modify<F>(
+        &self,
+        key: impl Into<Key>,
+        mut change: F,
+    ) -> Result<Option<Versioned<T>>, RepoError>
+    where
+        F: FnMut(&mut T),
+    {
+        let key = key.into();
+        let Some(mut entity) = self.get(&key, false).await? else {
+            return Ok(None);
+        };
+        change(&mut entity.data);
+
+        match self.update(&entity).await {
+            Err(RepoError::Conflict) => {
+                let Some(mut entity) = self.get(&key, true).await? else {
+                    return Ok(None);
+                };
+                change(&mut entity.data);
+                self.update(&entity).await.map(Some)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    ///
+    /// Deletes the item if it was not written since `last_write`.
+    /// Fails with `Conflict` if the item was written meanwhile and `NotFound` if there is none.
+    ///
+    pub async fn delete(&self, key: impl Into<Key>, last_write: i64) -> Result<(), RepoError> {
+        let key = key.into();
+        let delete = Delete {
+            table: self.table_name.clone(),
+            key: self.stored_key(&key)?,
+            condition: Some("last_write = :expected".to_string()),
+            values: expected(last_write),
+            ..Default::default()
+        };
+
+        let previous = self.previous_if_needed(&key).await?;
+        let changed_at = chrono::Utc::now().timestamp_millis();
+        self.write_entity(
+            TransactWrite::Delete(delete),
+            None,
+            previous
+                .as_ref()
+                .map(|previous| (previous, WriteOperation::Delete, changed_at)),
+            not_found_if_missing,
+        )
+        .await
+    }
+
+    /// Converts a stored item into the entity, migrating older shapes.
+    pub(super) async fn decode(
+        &self,
+        item: HashMap<String, AttributeValue>,
+    ) -> Result<Versioned<T>, RepoError> {
+        let Some(migrated) = migrate::<T>(&item)? else {
+            return Ok(from_item(item)?);
+        };
+        if self.persist_migrations {
+            self.persist_migration(&item, &migrated).await;
+        }
+        Ok(from_item(migrated)?)
+    }
+
+    /// Writes a migrated item back on read, see `with_persisted_migrations`.
+    async fn persist_migration(
+        &self,
+        original: &HashMap<String, AttributeValue>,
+        migrated: &HashMap<String, AttributeValue>,
+    ) {
+        match self.put_migrated(original, migrated).await {
+            // written meanwhile in the current shape
+            Ok(_) | Err(RepoError::Conflict) => {}
+            Err(e) => tracing::warn!("Failed to persist migrated {}: {}", T::NAME, e),
+        }
+    }
+
+    ///
+    /// Writes a migrated item back unless it was written meanwhile (`Conflict`).
+    ///
+    /// The `last_write` token is kept: only the shape changed, not the data.
+    /// Readers holding the token can still update the item.
+    ///
+    pub(super) async fn put_migrated(
+        &self,
+        original: &HashMap<String, AttributeValue>,
+        migrated: &HashMap<String, AttributeValue>,
+    ) -> Result<(), RepoError> {
+        let last_write = original
+            .get("last_write")
+            .cloned()
+            .unwrap_or_else(|| AttributeValue::N("0".to_string()));
+
+        self.storage
+            .put(Put {
+                table: self.table_name.clone(),
+                item: migrated.clone(),
+                condition: Some("last_write = :expected".to_string()),
+                values: HashMap::from([(":expected".to_string(), last_write)]),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Condition of inserts: there is no item with the key or it expired.
+pub(super) const INSERT_CONDITION: &str = "attribute_not_exists(pk) OR expires_at <= :now";
+
+/// The `:now` value of `INSERT_CONDITION`.
+pub(super) fn now_seconds() -> HashMap<String, AttributeValue> {
+    HashMap::from([(
+        ":now".to_string(),
+        AttributeValue::N(chrono::Utc::now().timestamp().to_string()),
+    )])
+}
+
+/// The `last_write` token for a write following `previous`, strictly increasing even within a millisecond.
+pub(super) fn next_write(previous: i64) -> i64 {
+    chrono::Utc::now().timestamp_millis().max(previous + 1)
+}
+
+/// The `:expected` value of the `last_write = :expected` condition.
+pub(super) fn expected(last_write: i64) -> HashMap<String, AttributeValue> {
+    HashMap::from([(
+        ":expected".to_string(),
+        AttributeValue::N(last_write.to_string()),
+    )])
+}
+
+/// A failed `last_write` condition is a `Conflict` if the item exists and `NotFound` otherwise.
+pub(super) fn not_found_if_missing(e: StorageError) -> RepoError {
+    match e {
+        StorageError::ConditionFailed(None) => RepoError::NotFound,
+        e => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Thing {
+        id: String,
+        name: String,
+    }
+
+    impl Entity for Thing {
+        const NAME: &'static str = "thing";
+
+        fn pk(&self) -> String {
+            format!("thing-{}", self.id)
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Thing> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        let client = Client::new(&shared_config);
+        VersionedRepo::new(client, "things".to_string())
+    }
+
+    #[tokio::test]
+    async fn insert_injects_extracted_key() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "TableName": "things",
+                "ConditionExpression": "attribute_not_exists(pk) OR expires_at <= :now",
+                "Item": {
+                    "pk": {"S": "thing-1"},
+                    "id": {"S": "1"},
+                    "name": {"S": "One"},
+                    "data_version": {"N": "1"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let thing = Thing {
+            id: "1".to_string(),
+            name: "One".to_string(),
+        };
+
+        repo(&server).await.insert(thing).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_strong_is_consistent() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .and(body_partial_json(serde_json::json!({
+                "Key": {"pk": {"S": "thing-1"}},
+                "ConsistentRead": true
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Item": {
+                    "pk": {"S": "thing-1"},
+                    "id": {"S": "1"},
+                    "name": {"S": "One"},
+                    "data_version": {"N": "1"},
+                    "last_write": {"N": "1234567890"}
+                }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let thing = repo(&server).await.read_strong("thing-1").await.unwrap();
+        assert_eq!(thing.unwrap().data.name, "One");
+    }
+
+    fn item(last_write: i64) -> serde_json::Value {
+        serde_json::json!({
+            "Item": {
+                "pk": {"S": "thing-1"},
+                "id": {"S": "1"},
+                "name": {"S": "One"},
+                "data_version": {"N": "1"},
+                "last_write": {"N": last_write.to_string()}
+            }
+        })
+    }
+
+    fn conditional_check_failed() -> ResponseTemplate {
+        ResponseTemplate::new(400).set_body_json(serde_json::json!({
+            "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+            "message": "The conditional request failed"
+        }))
+    }
+
+    /// Conditional check failure returning the current item (ALL_OLD)
+    fn conflict(last_write: i64) -> ResponseTemplate {
+        let mut body = item(last_write);
+        body["__type"] = "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException".into();
+        body["message"] = "The conditional request failed".into();
+        ResponseTemplate::new(400).set_body_json(body)
+    }
+
+    #[tokio::test]
+    async fn update_is_conditional_on_last_write() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ConditionExpression": "last_write = :expected",
+                "ExpressionAttributeValues": {":expected": {"N": "1234567890"}},
+                "Item": {"data_version": {"N": "3"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let thing = Versioned {
+            data: Thing {
+                id: "1".to_string(),
+                name: "One".to_string(),
+            },
+            data_version: 3,
+            last_write: 1234567890,
+            deleted_at: None,
+            expires_at: None,
+        };
+
+        let updated = repo(&server).await.update(&thing).await.unwrap();
+        assert_eq!(updated.data_version, 3);
+        assert!(updated.last_write > thing.last_write);
+    }
+
+    #[tokio::test]
+    async fn modify_retries_once_after_strong_read() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .and(body_partial_json(
+                serde_json::json!({"ConsistentRead": false}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(item(1)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .and(body_partial_json(
+                serde_json::json!({"ConsistentRead": true}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(item(2)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ExpressionAttributeValues": {":expected": {"N": "1"}}
+            })))
+            .respond_with(conflict(2))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ExpressionAttributeValues": {":expected": {"N": "2"}},
+                "Item": {"name": {"S": "Changed"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let modified = repo(&server)
+            .await
+            .modify("thing-1", |thing| thing.name = "Changed".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(modified.data.name, "Changed");
+    }
+
+    #[tokio::test]
+    async fn modify_gives_up_after_second_conflict() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(item(1)))
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .respond_with(conflict(1))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let result = repo(&server).await.modify("thing-1", |_| {}).await;
+        assert!(matches!(result, Err(RepoError::Conflict)));
+    }
+
+    #[tokio::test]
+    async fn insert_of_existing_key_already_exists() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .respond_with(conditional_check_failed())
+            .mount(&server)
+            .await;
+
+        let thing = Thing {
+            id: "1".to_string(),
+            name: "One".to_string(),
+        };
+
+        let result = repo(&server).await.insert(thing).await;
+        assert!(matches!(result, Err(RepoError::AlreadyExists)));
+    }
+
+    #[tokio::test]
+    async fn delete_of_missing_item_not_found() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.DeleteItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ReturnValuesOnConditionCheckFailure": "ALL_OLD"
+            })))
+            .respond_with(conditional_check_failed())
+            .mount(&server)
+            .await;
+
+        let result = repo(&server).await.delete("thing-1", 1).await;
+        assert!(matches!(result, Err(RepoError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn throttling_is_typed() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException",
+                "message": "Rate exceeded"
+            })))
+            .mount(&server)
+            .await;
+
+        let result = repo(&server).await.read("thing-1").await;
+        assert!(matches!(result, Err(RepoError::Throttled)));
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Labeled {
+        id: String,
+        label: String,
+    }
+
+    impl Entity for Labeled {
+        const NAME: &'static str = "labeled";
+        const MIGRATIONS: &'static [Migration] = &[|mut value| {
+            value["label"] = value["name"].take();
+            Ok(value)
+        }];
+
+        fn pk(&self) -> String {
+            format!("thing-{}", self.id)
+        }
+    }
+
+    #[tokio::test]
+    async fn read_persists_migrated_item() {
+        let server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.GetItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(item(1234567890)))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "ConditionExpression": "last_write = :expected",
+                "ExpressionAttributeValues": {":expected": {"N": "1234567890"}},
+                "Item": {
+                    "label": {"S": "One"},
+                    "data_version": {"N": "2"},
+                    "last_write": {"N": "1234567890"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(&server).await;
+        let repo: VersionedRepo<Labeled> =
+            VersionedRepo::new(Client::new(&shared_config), "things".to_string())
+                .with_persisted_migrations();
+
+        let labeled = repo.read("thing-1").await.unwrap().unwrap();
+        assert_eq!(labeled.data.label, "One");
+        assert_eq!(labeled.data_version, 2);
+        assert_eq!(labeled.last_write, 1234567890);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::shared::dynamodb::{Entity, Index};
+
+/// Pretty printed JSON Schema of an entity as checked into `schema/{entity}.schema.json`.
+pub fn entity_schema<T: Entity>() -> String {
+    let schema = schemars::schema_for!(T);
+    serde_json::to_string_pretty(&schema).expect("JSON Schema is serializable") + "\n"
+}
+
+/// Pretty printed index declarations of an entity as checked into `schema/{entity}.indexes.json`.
+pub fn entity_indexes<T: Entity>() -> String {
+    let indexes: Vec<_> = T::INDEXES.iter().map(Index::to_json).collect();
+    serde_json::to_string_pretty(&indexes).expect("indexes are serializable") + "\n"
+}
+
+///
+/// Compares the JSON Schema of an entity with its snapshot in `schema/{entity}.schema.json`.
+///
+/// Fails if the shape of the stored JSON changed.
+/// Run the tests with `EXPECTORATE=overwrite` to update the snapshot after handling the change.
+///
+#[cfg(test)]
+pub fn assert_schema_unchanged<T: Entity>() {
+    let path = format!(
+        "{}/schema/{}.schema.json",
+        env!("CARGO_MANIFEST_DIR"),
+        T::NAME
+    );
+    let current = entity_schema::<T>();
+
+    // expectorate prints the diff to stderr
+    if std::panic::catch_unwind(|| expectorate::assert_contents(&path, &current)).is_err() {
+        panic!(
+            "\n\nSchema of entity `{}` changed!\n\
+             → Increment data_version by appending a migration to `{}::MIGRATIONS`.\n\
+             → The migration may return the value as it is if the change is backward compatible.\n\
+             → Update the snapshot with `EXPECTORATE=overwrite cargo test`.\n",
+            T::NAME,
+            std::any::type_name::<T>()
+        );
+    }
+}
+
+///
+/// Compares the index declarations of an entity with `schema/{entity}.indexes.json`, read by the CDK.
+///
+/// Entities without indexes have no file.
+/// Run the tests with `EXPECTORATE=overwrite` to update the file, then deploy the infrastructure.
+///
+#[cfg(test)]
+pub fn assert_indexes_unchanged<T: Entity>() {
+    let path = format!(
+        "{}/schema/{}.indexes.json",
+        env!("CARGO_MANIFEST_DIR"),
+        T::NAME
+    );
+    if T::INDEXES.is_empty() {
+        assert!(
+            !std::path::Path::new(&path).exists(),
+            "Entity `{}` has no indexes, remove {}",
+            T::NAME,
+            path
+        );
+        return;
+    }
+    expectorate::assert_contents(&path, &entity_indexes::<T>());
+}
+
+///
+/// Generates tests that guard the JSON Schema snapshot and the index declarations of an entity.
+///
+/// Add it to the tests of every module defining an `Entity`:
+///
+/// ```ignore
+/// #[cfg(test)]
+/// mod tests {
+///     backend::entity_schema_test!(AccountData);
+/// }
+/// ```
+///
+#[macro_export]
+macro_rules! entity_schema_test {
+    ($entity:ty) => {
+        #[test]
+        fn schema_has_not_changed() {
+            $crate::shared::dynamodb::assert_schema_unchanged::<$entity>();
+        }
+
+        #[test]
+        fn indexes_have_not_changed() {
+            $crate::shared::dynamodb::assert_indexes_unchanged::<$entity>();
+        }
+    };
+}
@@ -0,0 +1,174 @@
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{Entity, Key, RepoError, Update, Versioned, VersionedRepo};
+use std::time::Duration;
+
+impl<T: Entity> VersionedRepo<T> {
+    /// The repository returning soft deleted and expired items as well, e.g. to restore them.
+    pub fn including_deleted(&self) -> Self {
+        let mut repo = self.clone();
+        repo.include_deleted = true;
+        repo
+    }
+
+    ///
+    /// Inserts an item that DynamoDB TTL removes after `ttl`, e.g. an invitation.
+    ///
+    /// The item is hidden from reads as soon as it expired.
+    ///
+    pub async fn insert_expiring(&self, data: T, ttl: Duration) -> Result<(), RepoError> {
+        let mut entity = Versioned::new(data);
+        entity.expires_at = Some(expires_at(ttl));
+        self.put_new(&entity).await
+    }
+
+    ///
+    /// Marks the item as deleted if it was not written since `last_write`.
+    ///
+    /// The item is hidden from reads right away and removed by DynamoDB TTL after `retention`,
+    /// until then `restore` brings it back.
+    /// Returns the tombstoned entity. Fails like `patch` with `Conflict` or `NotFound`.
+    ///
+    pub async fn soft_delete(
+        &self,
+        key: impl Into<Key>,
+        last_write: i64,
+        retention: Duration,
+    ) -> Result<Versioned<T>, RepoError> {
+        let update = Update::new()
+            .set_attribute("deleted_at", chrono::Utc::now().timestamp_millis())
+            .set_attribute("expires_at", expires_at(retention))
+            .if_unchanged_since(last_write);
+        self.patch(key, update).await
+    }
+
+    ///
+    /// Removes the tombstone and expiry of the item if it was not written since `last_write`.
+    ///
+    /// Read the item with `including_deleted` to get its `last_write` token.
+    ///
+    pub async fn restore(
+        &self,
+        key: impl Into<Key>,
+        last_write: i64,
+    ) -> Result<Versioned<T>, RepoError> {
+        let update = Update::new()
+            .remove_attribute("deleted_at")
+            .remove_attribute("expires_at")
+            .if_unchanged_since(last_write);
+        self.patch(key, update).await
+    }
+
+    /// Decodes a stored item, `None` if it is hidden as deleted. Fails with `Forbidden` for items of other tenants.
+    pub(super) async fn decode_visible(
+        &self,
+        item: Item,
+    ) -> Result<Option<Versioned<T>>, RepoError> {
+        self.check_tenant(&item)?;
+        let entity = self.decode(item).await?;
+        Ok((self.include_deleted || !entity.is_deleted()).then_some(entity))
+    }
+}
+
+/// The `expires_at` epoch seconds of an item expiring after `ttl`.
+fn expires_at(ttl: Duration) -> i64 {
+    chrono::Utc::now().timestamp() + ttl.as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::{MemoryStorage, PageRequest};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Invitation {
+        code: String,
+    }
+
+    impl Entity for Invitation {
+        const NAME: &'static str = "invitation";
+
+        fn pk(&self) -> String {
+            self.code.clone()
+        }
+    }
+
+    fn invitation(code: &str) -> Invitation {
+        Invitation {
+            code: code.to_string(),
+        }
+    }
+
+    const RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+    #[tokio::test]
+    async fn soft_deleted_items_are_hidden_until_restored() {
+        let repo = MemoryStorage::new().repo::<Invitation>("invitations");
+        repo.insert(invitation("a")).await.unwrap();
+        repo.insert(invitation("b")).await.unwrap();
+        let a = repo.read("a").await.unwrap().unwrap();
+
+        let deleted = repo
+            .soft_delete("a", a.last_write, RETENTION)
+            .await
+            .unwrap();
+        assert!(deleted.is_deleted());
+        assert!(deleted.expires_at.unwrap() > chrono::Utc::now().timestamp());
+
+        assert!(repo.read("a").await.unwrap().is_none());
+        assert!(repo.modify("a", |_| {}).await.unwrap().is_none());
+        let listed = repo.list(PageRequest::default()).await.unwrap().items;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(repo.batch_read(["a", "b"]).await.unwrap().len(), 1);
+        assert!(matches!(
+            repo.insert(invitation("a")).await,
+            Err(RepoError::AlreadyExists)
+        ));
+
+        let tombstone = repo.including_deleted().read("a").await.unwrap().unwrap();
+        assert_eq!(tombstone.deleted_at, deleted.deleted_at);
+        let restored = repo.restore("a", tombstone.last_write).await.unwrap();
+        assert_eq!(restored.deleted_at, None);
+        assert_eq!(restored.expires_at, None);
+        assert!(repo.read("a").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_items_are_hidden_and_replaced_on_insert() {
+        let repo = MemoryStorage::new().repo::<Invitation>("invitations");
+        repo.insert_expiring(invitation("a"), Duration::ZERO)
+            .await
+            .unwrap();
+        repo.insert_expiring(invitation("b"), RETENTION)
+            .await
+            .unwrap();
+
+        assert!(repo.read("a").await.unwrap().is_none());
+        let b = repo.read("b").await.unwrap().unwrap();
+        assert!(!b.is_deleted());
+        assert!(matches!(
+            repo.insert(invitation("b")).await,
+            Err(RepoError::AlreadyExists)
+        ));
+
+        repo.insert(invitation("a")).await.unwrap();
+        assert_eq!(repo.read("a").await.unwrap().unwrap().expires_at, None);
+    }
+
+    #[tokio::test]
+    async fn soft_delete_of_changed_item_conflicts() {
+        let repo = MemoryStorage::new().repo::<Invitation>("invitations");
+        repo.insert(invitation("a")).await.unwrap();
+        let a = repo.read("a").await.unwrap().unwrap();
+
+        assert!(matches!(
+            repo.soft_delete("a", a.last_write - 1, RETENTION).await,
+            Err(RepoError::Conflict)
+        ));
+        assert!(matches!(
+            repo.soft_delete("b", a.last_write, RETENTION).await,
+            Err(RepoError::NotFound)
+        ));
+    }
+}
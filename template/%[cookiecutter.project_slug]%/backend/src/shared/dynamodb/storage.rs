@@ -0,0 +1,529 @@
+use crate::shared::dynamodb::RepoError;
+use async_trait::async_trait;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::delete_item::DeleteItemError;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::operation::update_item::UpdateItemError;
+use aws_sdk_dynamodb::types::{
+    self, AttributeValue, CancellationReason, DeleteRequest, KeysAndAttributes, PutRequest,
+    ReturnValue, ReturnValuesOnConditionCheckFailure, TransactWriteItem, WriteRequest,
+};
+use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
+
+pub type Item = HashMap<String, AttributeValue>;
+
+///
+/// The item operations the repositories are built on.
+///
+/// Requests carry DynamoDB expressions with their `#name` and `:value` placeholders.
+/// `DynamoDbStorage` sends them to DynamoDB, `MemoryStorage` evaluates them in memory for tests.
+///
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn get(
+        &self,
+        table: &str,
+        key: Item,
+        consistent: bool,
+    ) -> Result<Option<Item>, StorageError>;
+
+    async fn put(&self, put: Put) -> Result<(), StorageError>;
+
+    async fn delete(&self, delete: Delete) -> Result<(), StorageError>;
+
+    /// Returns the item as written.
+    async fn update(&self, update: UpdateItem) -> Result<Item, StorageError>;
+
+    async fn query(&self, query: Query) -> Result<ItemPage, StorageError>;
+
+    async fn scan(&self, scan: Scan) -> Result<ItemPage, StorageError>;
+
+    /// Returns the found items and the unprocessed keys.
+    async fn batch_get(
+        &self,
+        table: &str,
+        keys: Vec<Item>,
+    ) -> Result<(Vec<Item>, Vec<Item>), StorageError>;
+
+    /// Returns the unprocessed writes.
+    async fn batch_write(
+        &self,
+        table: &str,
+        writes: Vec<BatchWrite>,
+    ) -> Result<Vec<BatchWrite>, StorageError>;
+
+    async fn transact_write(&self, items: Vec<TransactWrite>) -> Result<(), StorageError>;
+}
+
+/// Failure of a storage operation.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The condition of a write failed. Holds the current item, `None` if there is none.
+    ConditionFailed(Option<Item>),
+    /// A transaction was canceled. Holds the failure of each of its items in order, `None` if it was not at fault.
+    TransactionCanceled(Vec<Option<StorageError>>),
+    /// Any other failure.
+    Repo(RepoError),
+}
+
+impl From<StorageError> for RepoError {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::ConditionFailed(_) => RepoError::Conflict,
+            StorageError::TransactionCanceled(reasons) => RepoError::TransactionCanceled(
+                reasons
+                    .into_iter()
+                    .map(|reason| reason.map(RepoError::from))
+                    .collect(),
+            ),
+            StorageError::Repo(e) => e,
+        }
+    }
+}
+
+impl From<RepoError> for StorageError {
+    fn from(e: RepoError) -> Self {
+        StorageError::Repo(e)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Put {
+    pub table: String,
+    pub item: Item,
+    pub condition: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Delete {
+    pub table: String,
+    pub key: Item,
+    pub condition: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+}
+
+/// Requires a condition on an item within a transaction without writing it.
+#[derive(Clone, Debug, Default)]
+pub struct ConditionCheck {
+    pub table: String,
+    pub key: Item,
+    pub condition: String,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UpdateItem {
+    pub table: String,
+    pub key: Item,
+    pub update: String,
+    pub condition: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    pub table: String,
+    /// Queries the global secondary index instead of the table.
+    pub index: Option<String>,
+    pub key_condition: String,
+    pub filter: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+    /// Maximum number of items to evaluate, before the filter.
+    pub limit: Option<u32>,
+    pub start: Option<Item>,
+    /// Returns the items in descending sort key order.
+    pub descending: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scan {
+    pub table: String,
+    pub filter: Option<String>,
+    pub names: HashMap<String, String>,
+    pub values: Item,
+    /// Maximum number of items to evaluate, before the filter.
+    pub limit: Option<u32>,
+    pub start: Option<Item>,
+    /// `(segment, total_segments)` of a parallel scan.
+    pub segment: Option<(u32, u32)>,
+}
+
+/// A page of a query or scan.
+#[derive(Clone, Debug, Default)]
+pub struct ItemPage {
+    pub items: Vec<Item>,
+    /// Key to start the next page from, `None` on the last page.
+    pub last_key: Option<Item>,
+    /// Items evaluated before the filter.
+    pub scanned: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchWrite {
+    Put(Item),
+    /// Deletes the item with the key.
+    Delete(Item),
+}
+
+#[derive(Clone, Debug)]
+pub enum TransactWrite {
+    Put(Put),
+    Delete(Delete),
+    Update(UpdateItem),
+    Check(ConditionCheck),
+}
+
+impl TransactWrite {
+    /// The key of the written item, the whole item for puts.
+    pub(super) fn key(&self) -> &Item {
+        match self {
+            TransactWrite::Put(put) => &put.item,
+            TransactWrite::Delete(delete) => &delete.key,
+            TransactWrite::Update(update) => &update.key,
+            TransactWrite::Check(check) => &check.key,
+        }
+    }
+}
+
+/// `Storage` of the tables in DynamoDB.
+#[derive(Clone)]
+pub struct DynamoDbStorage {
+    client: Client,
+}
+
+impl DynamoDbStorage {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Storage for DynamoDbStorage {
+    async fn get(
+        &self,
+        table: &str,
+        key: Item,
+        consistent: bool,
+    ) -> Result<Option<Item>, StorageError> {
+        let resp = self
+            .client
+            .get_item()
+            .table_name(table)
+            .set_key(Some(key))
+            .consistent_read(consistent)
+            .send()
+            .await
+            .map_err(repo_error)?;
+        Ok(resp.item)
+    }
+
+    async fn put(&self, put: Put) -> Result<(), StorageError> {
+        let conditional = put.condition.is_some();
+        self.client
+            .put_item()
+            .table_name(put.table)
+            .set_item(Some(put.item))
+            .set_condition_expression(put.condition)
+            .set_expression_attribute_names(non_empty(put.names))
+            .set_expression_attribute_values(non_empty(put.values))
+            .set_return_values_on_condition_check_failure(all_old(conditional))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(PutItemError::ConditionalCheckFailedException(c)) => {
+                    StorageError::ConditionFailed(c.item().cloned())
+                }
+                _ => repo_error(e),
+            })?;
+        Ok(())
+    }
+
+    async fn delete(&self, delete: Delete) -> Result<(), StorageError> {
+        let conditional = delete.condition.is_some();
+        self.client
+            .delete_item()
+            .table_name(delete.table)
+            .set_key(Some(delete.key))
+            .set_condition_expression(delete.condition)
+            .set_expression_attribute_names(non_empty(delete.names))
+            .set_expression_attribute_values(non_empty(delete.values))
+            .set_return_values_on_condition_check_failure(all_old(conditional))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(DeleteItemError::ConditionalCheckFailedException(c)) => {
+                    StorageError::ConditionFailed(c.item().cloned())
+                }
+                _ => repo_error(e),
+            })?;
+        Ok(())
+    }
+
+    async fn update(&self, update: UpdateItem) -> Result<Item, StorageError> {
+        let conditional = update.condition.is_some();
+        let resp = self
+            .client
+            .update_item()
+            .table_name(update.table)
+            .set_key(Some(update.key))
+            .update_expression(update.update)
+            .set_condition_expression(update.condition)
+            .set_expression_attribute_names(non_empty(update.names))
+            .set_expression_attribute_values(non_empty(update.values))
+            .return_values(ReturnValue::AllNew)
+            .set_return_values_on_condition_check_failure(all_old(conditional))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(UpdateItemError::ConditionalCheckFailedException(c)) => {
+                    StorageError::ConditionFailed(c.item().cloned())
+                }
+                _ => repo_error(e),
+            })?;
+        Ok(resp.attributes.unwrap_or_default())
+    }
+
+    async fn query(&self, query: Query) -> Result<ItemPage, StorageError> {
+        let resp = self
+            .client
+            .query()
+            .table_name(query.table)
+            .set_index_name(query.index)
+            .key_condition_expression(query.key_condition)
+            .set_filter_expression(query.filter)
+            .set_expression_attribute_names(non_empty(query.names))
+            .set_expression_attribute_values(non_empty(query.values))
+            .set_limit(query.limit.map(|l| l as i32))
+            .set_exclusive_start_key(query.start)
+            .scan_index_forward(!query.descending)
+            .send()
+            .await
+            .map_err(repo_error)?;
+        Ok(ItemPage {
+            items: resp.items.unwrap_or_default(),
+            last_key: resp.last_evaluated_key,
+            scanned: resp.scanned_count.max(0) as usize,
+        })
+    }
+
+    async fn scan(&self, scan: Scan) -> Result<ItemPage, StorageError> {
+        let (segment, total_segments) = scan.segment.unzip();
+        let resp = self
+            .client
+            .scan()
+            .table_name(scan.table)
+            .set_filter_expression(scan.filter)
+            .set_expression_attribute_names(non_empty(scan.names))
+            .set_expression_attribute_values(non_empty(scan.values))
+            .set_limit(scan.limit.map(|l| l as i32))
+            .set_exclusive_start_key(scan.start)
+            .set_segment(segment.map(|s| s as i32))
+            .set_total_segments(total_segments.map(|s| s as i32))
+            .send()
+            .await
+            .map_err(repo_error)?;
+        Ok(ItemPage {
+            items: resp.items.unwrap_or_default(),
+            last_key: resp.last_evaluated_key,
+            scanned: resp.scanned_count.max(0) as usize,
+        })
+    }
+
+    async fn batch_get(
+        &self,
+        table: &str,
+        keys: Vec<Item>,
+    ) -> Result<(Vec<Item>, Vec<Item>), StorageError> {
+        let request = KeysAndAttributes::builder()
+            .set_keys(Some(keys))
+            .build()
+            .expect("keys are set");
+        let resp = self
+            .client
+            .batch_get_item()
+            .request_items(table, request)
+            .send()
+            .await
+            .map_err(repo_error)?;
+
+        let items = resp
+            .responses
+            .and_then(|mut responses| responses.remove(table))
+            .unwrap_or_default();
+        let unprocessed = resp
+            .unprocessed_keys
+            .and_then(|mut unprocessed| unprocessed.remove(table))
+            .map(|unprocessed| unprocessed.keys)
+            .unwrap_or_default();
+        Ok((items, unprocessed))
+    }
+
+    async fn batch_write(
+        &self,
+        table: &str,
+        writes: Vec<BatchWrite>,
+    ) -> Result<Vec<BatchWrite>, StorageError> {
+        let requests = writes
+            .into_iter()
+            .map(|write| match write {
+                BatchWrite::Put(item) => {
+                    let put = PutRequest::builder()
+                        .set_item(Some(item))
+                        .build()
+                        .expect("item is set");
+                    WriteRequest::builder().put_request(put).build()
+                }
+                BatchWrite::Delete(key) => {
+                    let delete = DeleteRequest::builder()
+                        .set_key(Some(key))
+                        .build()
+                        .expect("key is set");
+                    WriteRequest::builder().delete_request(delete).build()
+                }
+            })
+            .collect();
+        let resp = self
+            .client
+            .batch_write_item()
+            .request_items(table, requests)
+            .send()
+            .await
+            .map_err(repo_error)?;
+
+        Ok(resp
+            .unprocessed_items
+            .and_then(|mut unprocessed| unprocessed.remove(table))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(
+                |request| match (request.put_request, request.delete_request) {
+                    (Some(put), _) => Some(BatchWrite::Put(put.item)),
+                    (_, Some(delete)) => Some(BatchWrite::Delete(delete.key)),
+                    _ => None,
+                },
+            )
+            .collect())
+    }
+
+    async fn transact_write(&self, items: Vec<TransactWrite>) -> Result<(), StorageError> {
+        self.client
+            .transact_write_items()
+            .set_transact_items(Some(items.into_iter().map(transact_item).collect()))
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(TransactWriteItemsError::TransactionCanceledException(canceled)) => {
+                    StorageError::TransactionCanceled(
+                        canceled
+                            .cancellation_reasons()
+                            .iter()
+                            .map(cancellation_error)
+                            .collect(),
+                    )
+                }
+                _ => repo_error(e),
+            })?;
+        Ok(())
+    }
+}
+
+fn repo_error<E, R>(e: SdkError<E, R>) -> StorageError
+where
+    RepoError: From<SdkError<E, R>>,
+{
+    StorageError::Repo(e.into())
+}
+
+/// DynamoDB rejects empty placeholder maps.
+fn non_empty<V>(map: HashMap<String, V>) -> Option<HashMap<String, V>> {
+    (!map.is_empty()).then_some(map)
+}
+
+/// Returns the current item on failed conditions to tell conflicts from missing items.
+fn all_old(conditional: bool) -> Option<ReturnValuesOnConditionCheckFailure> {
+    conditional.then_some(ReturnValuesOnConditionCheckFailure::AllOld)
+}
+
+fn transact_item(write: TransactWrite) -> TransactWriteItem {
+    match write {
+        TransactWrite::Put(put) => {
+            let conditional = put.condition.is_some();
+            let put = types::Put::builder()
+                .table_name(put.table)
+                .set_item(Some(put.item))
+                .set_condition_expression(put.condition)
+                .set_expression_attribute_names(non_empty(put.names))
+                .set_expression_attribute_values(non_empty(put.values))
+                .set_return_values_on_condition_check_failure(all_old(conditional))
+                .build()
+                .expect("table and item are set");
+            TransactWriteItem::builder().put(put).build()
+        }
+        TransactWrite::Delete(delete) => {
+            let conditional = delete.condition.is_some();
+            let delete = types::Delete::builder()
+                .table_name(delete.table)
+                .set_key(Some(delete.key))
+                .set_condition_expression(delete.condition)
+                .set_expression_attribute_names(non_empty(delete.names))
+                .set_expression_attribute_values(non_empty(delete.values))
+                .set_return_values_on_condition_check_failure(all_old(conditional))
+                .build()
+                .expect("table and key are set");
+            TransactWriteItem::builder().delete(delete).build()
+        }
+        TransactWrite::Update(update) => {
+            let conditional = update.condition.is_some();
+            let update = types::Update::builder()
+                .table_name(update.table)
+                .set_key(Some(update.key))
+                .update_expression(update.update)
+                .set_condition_expression(update.condition)
+                .set_expression_attribute_names(non_empty(update.names))
+                .set_expression_attribute_values(non_empty(update.values))
+                .set_return_values_on_condition_check_failure(all_old(conditional))
+                .build()
+                .expect("table, key and update are set");
+            TransactWriteItem::builder().update(update).build()
+        }
+        TransactWrite::Check(check) => {
+            let check = types::ConditionCheck::builder()
+                .table_name(check.table)
+                .set_key(Some(check.key))
+                .condition_expression(check.condition)
+                .set_expression_attribute_names(non_empty(check.names))
+                .set_expression_attribute_values(non_empty(check.values))
+                .return_values_on_condition_check_failure(
+                    ReturnValuesOnConditionCheckFailure::AllOld,
+                )
+                .build()
+                .expect("table, key and condition are set");
+            TransactWriteItem::builder().condition_check(check).build()
+        }
+    }
+}
+
+/// The failure of an item of a canceled transaction, `None` if the item was not at fault.
+fn cancellation_error(reason: &CancellationReason) -> Option<StorageError> {
+    match reason.code()? {
+        "None" => None,
+        "ConditionalCheckFailed" => Some(StorageError::ConditionFailed(reason.item().cloned())),
+        // another transaction is writing the item
+        "TransactionConflict" => Some(RepoError::Conflict.into()),
+        "ProvisionedThroughputExceeded" | "ThrottlingError" => Some(RepoError::Throttled.into()),
+        code => Some(
+            RepoError::Transport(
+                format!("{}: {}", code, reason.message().unwrap_or_default()).into(),
+            )
+            .into(),
+        ),
+    }
+}
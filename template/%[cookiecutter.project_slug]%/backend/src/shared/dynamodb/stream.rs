@@ -0,0 +1,284 @@
+use crate::shared::dynamodb::migration::decode as decode_item;
+use crate::shared::dynamodb::{Entity, Key, RepoError, Versioned};
+use async_trait::async_trait;
+use aws_lambda_events::event::dynamodb::{Event, EventRecord};
+use aws_lambda_events::event::streams::{DynamoDbBatchItemFailure, DynamoDbEventResponse};
+use aws_sdk_dynamodb::types::AttributeValue;
+use lambda_runtime::{tracing, Error};
+use std::collections::HashMap;
+
+///
+/// Reacts on the changes of items of `T` in the DynamoDB stream of its table.
+///
+/// The images are decoded into `Versioned<T>` with the migrations of `T` applied.
+/// Implement the kinds of changes you are interested in, the others are ignored.
+/// The stream must be configured with `NEW_AND_OLD_IMAGES`.
+///
+#[async_trait]
+pub trait StreamHandler<T: Entity + 'static>: Send + Sync {
+    async fn insert(&self, _new: Versioned<T>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn modify(&self, _old: Versioned<T>, _new: Versioned<T>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn remove(&self, _old: Versioned<T>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A decoded change of an item of a DynamoDB stream.
+#[derive(Clone, Debug)]
+pub enum Change<T> {
+    Insert(Versioned<T>),
+    Modify(Versioned<T>, Versioned<T>),
+    Remove(Versioned<T>),
+}
+
+impl<T: Entity> Change<T> {
+    ///
+    /// Decodes the images of a stream record.
+    ///
+    /// Returns `None` for records of other entities sharing the table.
+    ///
+    pub fn from_record(record: &EventRecord) -> Result<Option<Self>, RepoError> {
+        let keys: HashMap<String, AttributeValue> = record.change.keys.clone().into();
+        if Key::from_item::<T>(&keys).is_none() {
+            return Ok(None);
+        }
+
+        let new = || decode::<T>(&record.change.new_image, "NewImage");
+        let old = || decode::<T>(&record.change.old_image, "OldImage");
+        let change = match record.event_name.as_str() {
+            "INSERT" => Change::Insert(new()?),
+            "MODIFY" => Change::Modify(old()?, new()?),
+            "REMOVE" => Change::Remove(old()?),
+            other => {
+                return Err(RepoError::Transport(
+                    format!("Unknown stream event {}", other).into(),
+                ))
+            }
+        };
+        Ok(Some(change))
+    }
+}
+
+/// Converts an image into the entity, migrating older shapes.
+fn decode<T: Entity>(image: &serde_dynamo::Item, name: &str) -> Result<Versioned<T>, RepoError> {
+    let item: HashMap<String, AttributeValue> = image.clone().into();
+    if item.is_empty() {
+        return Err(RepoError::Transport(
+            format!("{} is missing, the stream needs NEW_AND_OLD_IMAGES", name).into(),
+        ));
+    }
+    decode_item(item)
+}
+
+///
+/// Dispatches the records of a stream event to the handler in order.
+///
+/// Processing stops at the first record that cannot be decoded or handled.
+/// It is reported as batch item failure, so Lambda retries the batch from this record on,
+/// without reprocessing the records before it.
+/// Configure the event source with `reportBatchItemFailures`, `retryAttempts`, `bisectBatchOnError`
+/// and an `onFailure` destination, so a poisoned record is set aside instead of blocking the shard.
+///
+pub async fn handle_stream<T: Entity + 'static>(
+    event: &Event,
+    handler: &impl StreamHandler<T>,
+) -> DynamoDbEventResponse {
+    let mut response = DynamoDbEventResponse::default();
+    for record in &event.records {
+        if let Err(e) = handle_record(record, handler).await {
+            tracing::error!(
+                "Failed to handle {} of {} ({}): {}",
+                record.event_name,
+                T::NAME,
+                record.event_id,
+                e
+            );
+            let mut failure = DynamoDbBatchItemFailure::default();
+            failure.item_identifier = record.change.sequence_number.clone();
+            response.batch_item_failures.push(failure);
+            break;
+        }
+    }
+    response
+}
+
+async fn handle_record<T: Entity + 'static>(
+    record: &EventRecord,
+    handler: &impl StreamHandler<T>,
+) -> Result<(), Error> {
+    match Change::<T>::from_record(record)? {
+        Some(Change::Insert(new)) => handler.insert(new).await,
+        Some(Change::Modify(old, new)) => handler.modify(old, new).await,
+        Some(Change::Remove(old)) => handler.remove(old).await,
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::Migration;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Mutex;
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Profile {
+        id: String,
+        name: String,
+    }
+
+    impl Entity for Profile {
+        const NAME: &'static str = "profile";
+        const PK_PREFIX: &'static str = "PROFILE#";
+        // v1 -> v2: `title` was renamed to `name`
+        const MIGRATIONS: &'static [Migration] = &[|mut value| {
+            value["name"] = value["title"].take();
+            Ok(value)
+        }];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        changes: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl StreamHandler<Profile> for Recorder {
+        async fn insert(&self, new: Versioned<Profile>) -> Result<(), Error> {
+            if new.data.name == "poison" {
+                return Err("cannot handle".into());
+            }
+            self.record(format!("insert {}", new.data.name))
+        }
+
+        async fn modify(
+            &self,
+            old: Versioned<Profile>,
+            new: Versioned<Profile>,
+        ) -> Result<(), Error> {
+            self.record(format!("modify {} -> {}", old.data.name, new.data.name))
+        }
+
+        async fn remove(&self, old: Versioned<Profile>) -> Result<(), Error> {
+            self.record(format!("remove {}", old.data.name))
+        }
+    }
+
+    impl Recorder {
+        fn record(&self, change: String) -> Result<(), Error> {
+            self.changes.lock().unwrap().push(change);
+            Ok(())
+        }
+    }
+
+    fn image(pk: &str, name: &str, data_version: u16) -> serde_json::Value {
+        let mut image = serde_json::json!({
+            "pk": {"S": pk},
+            "id": {"S": pk.trim_start_matches("PROFILE#")},
+            "data_version": {"N": data_version.to_string()},
+            "last_write": {"N": "1"}
+        });
+        let name_attribute = if data_version == 1 { "title" } else { "name" };
+        image[name_attribute] = serde_json::json!({"S": name});
+        image
+    }
+
+    fn record(
+        sequence_number: &str,
+        event_name: &str,
+        old: Option<serde_json::Value>,
+        new: Option<serde_json::Value>,
+    ) -> serde_json::Value {
+        let pk = old.as_ref().or(new.as_ref()).unwrap()["pk"].clone();
+        let mut change = serde_json::json!({
+            "Keys": {"pk": pk},
+            "SequenceNumber": sequence_number,
+            "SizeBytes": 100,
+            "StreamViewType": "NEW_AND_OLD_IMAGES"
+        });
+        if let Some(old) = old {
+            change["OldImage"] = old;
+        }
+        if let Some(new) = new {
+            change["NewImage"] = new;
+        }
+        serde_json::json!({
+            "eventID": sequence_number,
+            "eventName": event_name,
+            "eventSource": "aws:dynamodb",
+            "awsRegion": "eu-central-1",
+            "dynamodb": change
+        })
+    }
+
+    fn event(records: Vec<serde_json::Value>) -> Event {
+        serde_json::from_value(serde_json::json!({"Records": records})).unwrap()
+    }
+
+    #[tokio::test]
+    async fn dispatches_migrated_images() {
+        let event = event(vec![
+            record("1", "INSERT", None, Some(image("PROFILE#1", "Ann", 1))),
+            record(
+                "2",
+                "MODIFY",
+                Some(image("PROFILE#1", "Ann", 1)),
+                Some(image("PROFILE#1", "Anna", 2)),
+            ),
+            // another entity of the table
+            record("3", "INSERT", None, Some(image("ORDER#1", "Order", 2))),
+            record("4", "REMOVE", Some(image("PROFILE#1", "Anna", 2)), None),
+        ]);
+        let recorder = Recorder::default();
+
+        let response = handle_stream(&event, &recorder).await;
+
+        assert!(response.batch_item_failures.is_empty());
+        assert_eq!(
+            *recorder.changes.lock().unwrap(),
+            vec!["insert Ann", "modify Ann -> Anna", "remove Anna"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_first_failure_and_stops() {
+        let event = event(vec![
+            record("1", "INSERT", None, Some(image("PROFILE#1", "Ann", 2))),
+            record("2", "INSERT", None, Some(image("PROFILE#2", "poison", 2))),
+            record("3", "INSERT", None, Some(image("PROFILE#3", "Bob", 2))),
+        ]);
+        let recorder = Recorder::default();
+
+        let response = handle_stream(&event, &recorder).await;
+
+        assert_eq!(response.batch_item_failures.len(), 1);
+        assert_eq!(
+            response.batch_item_failures[0].item_identifier.as_deref(),
+            Some("2")
+        );
+        assert_eq!(*recorder.changes.lock().unwrap(), vec!["insert Ann"]);
+    }
+
+    #[test]
+    fn missing_image_is_an_error() {
+        let record: EventRecord = serde_json::from_value(record(
+            "1",
+            "MODIFY",
+            None,
+            Some(image("PROFILE#1", "Ann", 2)),
+        ))
+        .unwrap();
+        assert!(Change::<Profile>::from_record(&record).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use crate::shared::dynamodb::key::PK;
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::unique::SENTINEL_PREFIX;
+use crate::shared::dynamodb::{to_item, Entity, Key, RepoError, Versioned, VersionedRepo};
+use aws_sdk_dynamodb::types::AttributeValue;
+
+/// Separates the tenant id from the partition key of an item.
+const SEPARATOR: char = '#';
+
+///
+/// The tenant owning the items of `TENANT_SCOPED` entities, e.g. the customer organization of a B2B product.
+///
+/// Its id prefixes the stored partition keys: `{PK_PREFIX}{tenant}#{pk}`.
+/// APIs take it from the JWT of the caller, see `shared::http::request_tenant`.
+///
+/// ```ignore
+/// let tenant = request_tenant(&req)?;
+/// let projects = projects.for_tenant(&tenant);
+/// let project = projects.read(&id).await?; // never one of another tenant
+/// ```
+///
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    ///
+    /// Fails with `Forbidden` if the id is empty or contains `#`, which would reach into the keys of other tenants,
+    /// or is `UNIQUE`, whose keys would be the sentinels of entities without `PK_PREFIX`.
+    ///
+    pub fn new(id: impl Into<String>) -> Result<Self, RepoError> {
+        let id = id.into();
+        if id.is_empty()
+            || id.contains(SEPARATOR)
+            || SENTINEL_PREFIX.strip_suffix(SEPARATOR) == Some(id.as_str())
+        {
+            return Err(RepoError::Forbidden);
+        }
+        Ok(Self(id))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// The repository reading and writing the items of `tenant` only.
+    ///
+    /// Keys are given and returned without the tenant, it is applied to every request.
+    /// Entities that are not `TENANT_SCOPED` are shared by all tenants and ignore it.
+    ///
+    pub fn for_tenant(&self, tenant: &Tenant) -> Self {
+        let mut repo = self.clone();
+        repo.tenant = Some(tenant.clone());
+        repo
+    }
+
+    /// The tenant of `for_tenant`.
+    pub fn tenant(&self) -> Option<&Tenant> {
+        self.tenant.as_ref()
+    }
+
+    /// The tenant applied to the keys, `None` for entities that are not scoped. Fails with `Forbidden` without one.
+    pub(super) fn scope(&self) -> Result<Option<&Tenant>, RepoError> {
+        if !T::TENANT_SCOPED {
+            return Ok(None);
+        }
+        self.tenant.as_ref().map(Some).ok_or(RepoError::Forbidden)
+    }
+
+    ///
+    /// The key with the tenant in its partition key, still without the prefixes of `T`.
+    ///
+    /// Fails with `InvalidKey` if it would be the key of a sentinel, see `Unique`.
+    ///
+    pub(super) fn scoped(&self, key: Key) -> Result<Key, RepoError> {
+        let key = match self.scope()? {
+            Some(tenant) => Key {
+                pk: format!("{}{}{}", tenant.0, SEPARATOR, key.pk),
+                ..key
+            },
+            None => key,
+        };
+        if T::PK_PREFIX.is_empty() && key.pk.starts_with(SENTINEL_PREFIX) {
+            return Err(RepoError::InvalidKey);
+        }
+        Ok(key)
+    }
+
+    /// The key of `scoped` without the tenant, `None` if it belongs to another tenant.
+    pub(super) fn unscoped(&self, key: Key) -> Option<Key> {
+        match self.scope().ok()? {
+            Some(tenant) => {
+                let pk = key.pk.strip_prefix(tenant.id())?.strip_prefix(SEPARATOR)?;
+                Some(Key {
+                    pk: pk.to_string(),
+                    sk: key.sk,
+                })
+            }
+            None => Some(key),
+        }
+    }
+
+    /// The key attributes as stored, with the tenant and the prefixes of `T`.
+    pub(super) fn stored_key(&self, key: &Key) -> Result<Item, RepoError> {
+        Ok(self.scoped(key.clone())?.to_item::<T>())
+    }
+
+    /// Serializes the entity and injects its stored key attributes next to the data.
+    pub(super) fn entity_item(&self, entity: &Versioned<T>) -> Result<Item, RepoError> {
+        let mut item = to_item(entity)?;
+        item.extend(self.stored_key(&entity.data.key())?);
+        Ok(item)
+    }
+
+    /// Narrows the filter of a scan or query to the items of the tenant.
+    pub(super) fn filter_tenant(
+        &self,
+        filter: &mut Option<String>,
+        values: &mut Item,
+    ) -> Result<(), RepoError> {
+        let Some(tenant) = self.scope()? else {
+            return Ok(());
+        };
+        let condition = format!("begins_with({}, :tenant_prefix)", PK);
+        *filter = Some(match filter.take() {
+            Some(filter) => format!("{} AND {}", filter, condition),
+            None => condition,
+        });
+        values.insert(
+            ":tenant_prefix".to_string(),
+            AttributeValue::S(format!("{}{}{}", T::PK_PREFIX, tenant.0, SEPARATOR)),
+        );
+        Ok(())
+    }
+
+    /// Fails with `Forbidden` if a stored item does not belong to the tenant.
+    pub(super) fn check_tenant(&self, item: &Item) -> Result<(), RepoError> {
+        let Some(tenant) = self.scope()? else {
+            return Ok(());
+        };
+        let prefix = format!("{}{}{}", T::PK_PREFIX, tenant.0, SEPARATOR);
+        match item.get(PK).and_then(|pk| pk.as_s().ok()) {
+            Some(pk) if pk.starts_with(&prefix) => Ok(()),
+            _ => Err(RepoError::Forbidden),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::{
+        Index, Indexes, MemoryStorage, PageRequest, Transaction, Unique,
+    };
+    use futures::TryStreamExt;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Indexes)]
+    #[index(name = "team-index", partition = team)]
+    #[index(name = "code-index", partition = code, keys_only)]
+    struct Project {
+        id: String,
+        team: String,
+        code: String,
+    }
+
+    impl Entity for Project {
+        const NAME: &'static str = "project";
+        const INDEXES: &'static [Index] = &[Self::TEAM_INDEX, Self::CODE_INDEX];
+        const UNIQUE: &'static [Unique<Self>] = &[Unique {
+            attribute: "code",
+            value: |project| project.code.clone(),
+        }];
+        const PK_PREFIX: &'static str = "PROJECT#";
+        const TENANT_SCOPED: bool = true;
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn project(id: &str, code: &str) -> Project {
+        Project {
+            id: id.to_string(),
+            team: "red".to_string(),
+            code: code.to_string(),
+        }
+    }
+
+    /// Repositories of the tenants `a` and `b` on the same table, with project 1 in both.
+    async fn repos(storage: &MemoryStorage) -> (VersionedRepo<Project>, VersionedRepo<Project>) {
+        let repo = storage.repo::<Project>("projects");
+        let a = repo.for_tenant(&Tenant::new("a").unwrap());
+        let b = repo.for_tenant(&Tenant::new("b").unwrap());
+        a.insert(project("1", "apollo")).await.unwrap();
+        a.insert(project("2", "gemini")).await.unwrap();
+        b.insert(project("1", "apollo")).await.unwrap();
+        (a, b)
+    }
+
+    #[tokio::test]
+    async fn tenants_read_their_own_items_only() {
+        let storage = MemoryStorage::new();
+        let (a, b) = repos(&storage).await;
+
+        assert_eq!(b.read("1").await.unwrap().unwrap().data.code, "apollo");
+        assert!(b.read("2").await.unwrap().is_none());
+        assert!(b.read_strong("2").await.unwrap().is_none());
+        assert_eq!(b.batch_read(["1", "2"]).await.unwrap().len(), 1);
+        let partition = b
+            .query_partition("2", None, PageRequest::default())
+            .await
+            .unwrap();
+        assert!(partition.items.is_empty());
+
+        let listed: Vec<_> = b.list_all().try_collect().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(a.list(PageRequest::default()).await.unwrap().items.len(), 2);
+        let page = b
+            .query_by_team("red", PageRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(page.items.len(), 1);
+        let page = b
+            .query_by_code("gemini", PageRequest::default())
+            .await
+            .unwrap();
+        assert!(page.items.is_empty());
+        assert!(b
+            .find_by_index("team-index", "team", "red")
+            .await
+            .unwrap()
+            .is_some_and(|project| project.data.id == "1"));
+
+        // the partition keys are prefixed with the tenant
+        let pks: Vec<_> = storage
+            .items("projects")
+            .into_iter()
+            .filter_map(|item| item[PK].as_s().ok().cloned())
+            .filter(|pk| pk.starts_with("PROJECT#"))
+            .collect();
+        assert_eq!(pks, vec!["PROJECT#a#1", "PROJECT#a#2", "PROJECT#b#1"]);
+    }
+
+    #[tokio::test]
+    async fn cursors_are_valid_for_their_tenant_only() {
+        let storage = MemoryStorage::new();
+        let (a, b) = repos(&storage).await;
+
+        let listed = a.list(PageRequest::first(1)).await.unwrap();
+        let queried = a.query_by_team("red", PageRequest::first(1)).await.unwrap();
+        let (listed, queried) = (listed.next.unwrap(), queried.next.unwrap());
+
+        assert!(a.list(PageRequest::after(1, listed.clone())).await.is_ok());
+        assert!(matches!(
+            b.list(PageRequest::after(1, listed)).await,
+            Err(RepoError::InvalidCursor)
+        ));
+        assert!(matches!(
+            b.query_by_team("red", PageRequest::after(1, queried)).await,
+            Err(RepoError::InvalidCursor)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tenants_cannot_write_items_of_others() {
+        let storage = MemoryStorage::new();
+        let (a, b) = repos(&storage).await;
+        let other = a.read("2").await.unwrap().unwrap();
+
+        assert!(matches!(
+            b.delete("2", other.last_write).await,
+            Err(RepoError::NotFound)
+        ));
+        assert!(matches!(b.update(&other).await, Err(RepoError::NotFound)));
+        assert!(b.modify("2", |_| {}).await.unwrap().is_none());
+
+        let mut transaction = Transaction::new();
+        transaction.delete(&b, "2", other.last_write);
+        assert!(transaction.commit().await.is_err());
+        assert!(a.read("2").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn unique_values_are_claimed_per_tenant() {
+        let storage = MemoryStorage::new();
+        let (a, b) = repos(&storage).await;
+
+        assert!(matches!(
+            b.insert(project("3", "apollo")).await,
+            Err(RepoError::UniqueViolation { attribute: "code" })
+        ));
+        b.insert(project("2", "gemini")).await.unwrap();
+        assert_eq!(a.read("2").await.unwrap().unwrap().data.code, "gemini");
+    }
+
+    #[tokio::test]
+    async fn repositories_without_tenant_are_forbidden() {
+        let storage = MemoryStorage::new();
+        repos(&storage).await;
+        let repo = storage.repo::<Project>("projects");
+
+        assert!(matches!(repo.read("1").await, Err(RepoError::Forbidden)));
+        assert!(matches!(
+            repo.insert(project("3", "mercury")).await,
+            Err(RepoError::Forbidden)
+        ));
+        assert!(matches!(
+            repo.list(PageRequest::default()).await,
+            Err(RepoError::Forbidden)
+        ));
+        assert!(matches!(
+            repo.query_by_team("red", PageRequest::default()).await,
+            Err(RepoError::Forbidden)
+        ));
+    }
+
+    #[tokio::test]
+    async fn items_of_other_tenants_are_forbidden() {
+        let storage = MemoryStorage::new();
+        let (a, b) = repos(&storage).await;
+        let item = storage
+            .items("projects")
+            .into_iter()
+            .find(|item| item[PK] == AttributeValue::S("PROJECT#a#2".to_string()))
+            .unwrap();
+
+        assert!(a.decode_visible(item.clone()).await.unwrap().is_some());
+        assert!(matches!(
+            b.decode_visible(item).await,
+            Err(RepoError::Forbidden)
+        ));
+    }
+
+    #[test]
+    fn tenant_ids_cannot_reach_into_other_keys() {
+        assert!(Tenant::new("acme").is_ok());
+        assert!(matches!(Tenant::new(""), Err(RepoError::Forbidden)));
+        assert!(matches!(Tenant::new("acme#1"), Err(RepoError::Forbidden)));
+        assert!(matches!(Tenant::new("UNIQUE"), Err(RepoError::Forbidden)));
+    }
+}
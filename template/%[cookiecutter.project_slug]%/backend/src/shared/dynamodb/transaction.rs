@@ -0,0 +1,382 @@
+use crate::shared::dynamodb::repo::{expected, next_write, now_seconds, INSERT_CONDITION};
+use crate::shared::dynamodb::{
+    ConditionCheck, Delete, Entity, Key, Put, RepoError, Storage, StorageError, TransactWrite,
+    Versioned, VersionedRepo,
+};
+use std::sync::Arc;
+
+/// Maximum number of items of a `TransactWriteItems` request.
+const MAX_ITEMS: usize = 100;
+
+///
+/// Atomic writes of `Versioned` entities of one or more repositories.
+///
+/// The operations have the same conditions as their single-item counterparts of `VersionedRepo`.
+/// Either all of them succeed on `commit` or none is applied.
+/// Inserts and updates claim the unique values of the entity, but the values an update or delete
+/// gives up are only released lazily, when another entity claims them through the repository.
+/// Inserts, updates and deletes write the events of repositories returned by `publishing`.
+///
+/// ```ignore
+/// let mut transaction = Transaction::new();
+/// let from = transaction.update(&accounts, &from)?;
+/// let to = transaction.update(&accounts, &to)?;
+/// transaction.commit().await?;
+/// ```
+///
+#[derive(Default)]
+pub struct Transaction {
+    storage: Option<Arc<dyn Storage>>,
+    items: Vec<TransactWrite>,
+    operations: Vec<Operation>,
+    /// The first error of an operation, returned by `commit`.
+    error: Option<RepoError>,
+}
+
+/// Kind of an item of the transaction to map its cancellation reason.
+#[derive(Clone, Copy)]
+enum Operation {
+    Insert,
+    Write,
+    Check,
+    Claim(&'static str),
+    Event,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts a new item. Cancels the transaction with `AlreadyExists` if there is one with the same key.
+    pub fn insert<T: Entity>(
+        &mut self,
+        repo: &VersionedRepo<T>,
+        data: T,
+    ) -> Result<Versioned<T>, RepoError> {
+        let inserted = Versioned::new(data);
+        let put = Put {
+            table: repo.table_name.clone(),
+            item: repo.entity_item(&inserted)?,
+            condition: Some(INSERT_CONDITION.to_string()),
+            values: now_seconds(),
+            ..Default::default()
+        };
+        let events = repo.outbox_records(&put.item)?;
+        self.push(repo, Operation::Insert, TransactWrite::Put(put));
+        self.claim(repo, &inserted)?;
+        self.publish(repo, events);
+        Ok(inserted)
+    }
+
+    ///
+    /// Replaces the item if it was not written since the entity was read.
+    ///
+    /// Returns the entity with the `last_write` token it has after the commit.
+    ///
+    pub fn update<T: Entity>(
+        &mut self,
+        repo: &VersionedRepo<T>,
+        entity: &Versioned<T>,
+    ) -> Result<Versioned<T>, RepoError> {
+        let updated = Versioned {
+            last_write: next_write(entity.last_write),
+            ..entity.clone()
+        };
+        let put = Put {
+            table: repo.table_name.clone(),
+            item: repo.entity_item(&updated)?,
+            condition: Some("last_write = :expected".to_string()),
+            values: expected(entity.last_write),
+            ..Default::default()
+        };
+        let events = repo.outbox_records(&put.item)?;
+        self.push(repo, Operation::Write, TransactWrite::Put(put));
+        self.claim(repo, &updated)?;
+        self.publish(repo, events);
+        Ok(updated)
+    }
+
+    ///
+    /// Deletes the item if it was not written since `last_write`.
+    ///
+    /// Errors of the key and of the events of `publishing` are returned by `commit`.
+    ///
+    pub fn delete<T: Entity>(
+        &mut self,
+        repo: &VersionedRepo<T>,
+        key: impl Into<Key>,
+        last_write: i64,
+    ) {
+        let key = match repo.stored_key(&key.into()) {
+            Ok(key) => key,
+            Err(e) => {
+                self.error.get_or_insert(e);
+                return;
+            }
+        };
+        let delete = Delete {
+            table: repo.table_name.clone(),
+            key,
+            condition: Some("last_write = :expected".to_string()),
+            values: expected(last_write),
+            ..Default::default()
+        };
+        match repo.outbox_records(&delete.key) {
+            Ok(events) => {
+                self.push(repo, Operation::Write, TransactWrite::Delete(delete));
+                self.publish(repo, events);
+            }
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    ///
+    /// Requires the item to be unchanged since the entity was read without writing it.
+    ///
+    /// Errors of the key are returned by `commit`.
+    ///
+    pub fn check<T: Entity>(&mut self, repo: &VersionedRepo<T>, entity: &Versioned<T>) {
+        let key = match repo.stored_key(&entity.data.key()) {
+            Ok(key) => key,
+            Err(e) => {
+                self.error.get_or_insert(e);
+                return;
+            }
+        };
+        let check = ConditionCheck {
+            table: repo.table_name.clone(),
+            key,
+            condition: "last_write = :expected".to_string(),
+            values: expected(entity.last_write),
+            ..Default::default()
+        };
+        self.push(repo, Operation::Check, TransactWrite::Check(check));
+    }
+
+    ///
+    /// Writes all items atomically.
+    ///
+    /// Fails with `TransactionCanceled` holding the reason of each item if a condition failed.
+    ///
+    pub async fn commit(self) -> Result<(), RepoError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let Some(storage) = self.storage else {
+            return Ok(());
+        };
+        if self.items.len() > MAX_ITEMS {
+            return Err(RepoError::Transport(
+                format!("Transactions are limited to {} items", MAX_ITEMS).into(),
+            ));
+        }
+
+        let operations = self.operations;
+        storage
+            .transact_write(self.items)
+            .await
+            .map_err(|e| match e {
+                StorageError::TransactionCanceled(reasons) => RepoError::TransactionCanceled(
+                    reasons
+                        .into_iter()
+                        .zip(operations)
+                        .map(|(reason, operation)| Some(cancellation_error(reason?, operation)))
+                        .collect(),
+                ),
+                e => e.into(),
+            })?;
+
+        Ok(())
+    }
+
+    /// Claims the unique values of the entity, see `Unique`.
+    fn claim<T: Entity>(
+        &mut self,
+        repo: &VersionedRepo<T>,
+        entity: &Versioned<T>,
+    ) -> Result<(), RepoError> {
+        for unique in T::UNIQUE {
+            let claim = repo.claim(unique, entity)?;
+            self.push(repo, Operation::Claim(unique.attribute), claim);
+        }
+        Ok(())
+    }
+
+    /// Adds the events of `publishing` of the operation before.
+    fn publish<T>(&mut self, repo: &VersionedRepo<T>, events: Vec<TransactWrite>) {
+        for event in events {
+            self.push(repo, Operation::Event, event);
+        }
+    }
+
+    fn push<T>(&mut self, repo: &VersionedRepo<T>, operation: Operation, item: TransactWrite) {
+        self.storage.get_or_insert_with(|| repo.storage.clone());
+        self.items.push(item);
+        self.operations.push(operation);
+    }
+}
+
+/// The typed error of an item at fault of a canceled transaction.
+fn cancellation_error(reason: StorageError, operation: Operation) -> RepoError {
+    match (reason, operation) {
+        (StorageError::ConditionFailed(_), Operation::Insert) => RepoError::AlreadyExists,
+        (StorageError::ConditionFailed(_), Operation::Claim(attribute)) => {
+            RepoError::UniqueViolation { attribute }
+        }
+        (StorageError::ConditionFailed(None), Operation::Write | Operation::Check) => {
+            RepoError::NotFound
+        }
+        (reason, _) => reason.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Account {
+        id: String,
+        balance: i64,
+    }
+
+    impl Entity for Account {
+        const NAME: &'static str = "account";
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Sentinel {
+        value: String,
+    }
+
+    impl Entity for Sentinel {
+        const NAME: &'static str = "sentinel";
+
+        fn pk(&self) -> String {
+            format!("unique#{}", self.value)
+        }
+    }
+
+    async fn client(server: &MockServer) -> Client {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        Client::new(&shared_config)
+    }
+
+    fn account(id: &str, balance: i64) -> Versioned<Account> {
+        Versioned {
+            data: Account {
+                id: id.to_string(),
+                balance,
+            },
+            data_version: 1,
+            last_write: 42,
+            deleted_at: None,
+            expires_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_items_of_several_tables() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header(
+                "x-amz-target",
+                "DynamoDB_20120810.TransactWriteItems",
+            ))
+            .and(body_partial_json(serde_json::json!({
+                "TransactItems": [
+                    {"Put": {
+                        "TableName": "accounts",
+                        "ConditionExpression": "last_write = :expected",
+                        "ExpressionAttributeValues": {":expected": {"N": "42"}},
+                        "Item": {"pk": {"S": "a"}, "balance": {"N": "0"}}
+                    }},
+                    {"Put": {
+                        "TableName": "sentinels",
+                        "ConditionExpression": "attribute_not_exists(pk) OR expires_at <= :now",
+                        "Item": {"pk": {"S": "unique#x"}}
+                    }},
+                    {"Delete": {"TableName": "accounts", "Key": {"pk": {"S": "b"}}}},
+                    {"ConditionCheck": {"TableName": "accounts", "Key": {"pk": {"S": "c"}}}}
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = client(&server).await;
+        let accounts = VersionedRepo::<Account>::new(client.clone(), "accounts".to_string());
+        let sentinels = VersionedRepo::<Sentinel>::new(client, "sentinels".to_string());
+
+        let mut transaction = Transaction::new();
+        let updated = transaction.update(&accounts, &account("a", 0)).unwrap();
+        transaction
+            .insert(
+                &sentinels,
+                Sentinel {
+                    value: "x".to_string(),
+                },
+            )
+            .unwrap();
+        transaction.delete(&accounts, "b", 42);
+        transaction.check(&accounts, &account("c", 0));
+        transaction.commit().await.unwrap();
+
+        assert!(updated.last_write > 42);
+    }
+
+    #[tokio::test]
+    async fn maps_cancellation_reasons_per_item() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header(
+                "x-amz-target",
+                "DynamoDB_20120810.TransactWriteItems",
+            ))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#TransactionCanceledException",
+                "Message": "Transaction cancelled",
+                "CancellationReasons": [
+                    {"Code": "None"},
+                    {"Code": "ConditionalCheckFailed", "Item": {"pk": {"S": "b"}}},
+                    {"Code": "ConditionalCheckFailed"},
+                    {"Code": "ConditionalCheckFailed"}
+                ]
+            })))
+            .mount(&server)
+            .await;
+
+        let accounts = VersionedRepo::<Account>::new(client(&server).await, "accounts".into());
+        let mut transaction = Transaction::new();
+        transaction.update(&accounts, &account("a", 0)).unwrap();
+        transaction.update(&accounts, &account("b", 0)).unwrap();
+        transaction.delete(&accounts, "c", 42);
+        transaction.insert(&accounts, account("d", 0).data).unwrap();
+
+        let Err(RepoError::TransactionCanceled(reasons)) = transaction.commit().await else {
+            panic!("transaction not canceled");
+        };
+        assert!(reasons[0].is_none());
+        assert!(matches!(reasons[1], Some(RepoError::Conflict)));
+        assert!(matches!(reasons[2], Some(RepoError::NotFound)));
+        assert!(matches!(reasons[3], Some(RepoError::AlreadyExists)));
+    }
+
+    #[tokio::test]
+    async fn empty_transaction_does_not_call_dynamodb() {
+        Transaction::new().commit().await.unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+use crate::shared::dynamodb::key::{PK, SK};
+use crate::shared::dynamodb::migration::decode;
+use crate::shared::dynamodb::storage::Item;
+use crate::shared::dynamodb::{
+    Delete, Entity, Key, Put, RepoError, StorageError, Tenant, TransactWrite, Versioned,
+    VersionedRepo, WriteOperation,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use futures::TryStreamExt;
+use std::collections::HashMap;
+
+/// Partition key prefix of the sentinel items of unique values.
+pub(super) const SENTINEL_PREFIX: &str = "UNIQUE#";
+
+///
+/// An attribute of an entity whose values must be unique among all its items.
+///
+/// Each value is claimed by a sentinel item `UNIQUE#{entity}#{attribute}#{value}` in the table of the entity,
+/// written in one transaction with the entity. Writes of a taken value fail with `UniqueViolation`.
+/// Values of `TENANT_SCOPED` entities are unique per tenant, their sentinels have the tenant after `UNIQUE#`.
+///
+/// ```ignore
+/// const UNIQUE: &'static [Unique<Self>] = &[Unique {
+///     attribute: "email",
+///     value: |user| user.email.to_lowercase(),
+/// }];
+/// ```
+///
+pub struct Unique<T> {
+    /// Name of the attribute. `patch` rejects updates of it, as it cannot swap the sentinels.
+    pub attribute: &'static str,
+    /// The value that must be unique, normalized, e.g. a lowercase email.
+    pub value: fn(&T) -> String,
+}
+
+impl<T: Entity> Unique<T> {
+    /// The key of the sentinel item of the value of `entity` of the tenant.
+    fn sentinel_key(&self, tenant: Option<&Tenant>, entity: &T) -> Item {
+        let tenant = tenant.map(|tenant| format!("{}#", tenant.id()));
+        let key = format!(
+            "{}{}{}#{}#{}",
+            SENTINEL_PREFIX,
+            tenant.unwrap_or_default(),
+            T::NAME,
+            self.attribute,
+            (self.value)(entity)
+        );
+        let mut item = HashMap::from([(PK.to_string(), AttributeValue::S(key.clone()))]);
+        if entity.sk().is_some() {
+            item.insert(SK.to_string(), AttributeValue::S(key));
+        }
+        item
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Writes the item of an entity with the sentinels of its unique values, its history record
+    /// and the events of `publishing`.
+    ///
+    /// `new` is the entity as written, `None` for deletes and patches. Its unique values are claimed.
+    /// `previous` is the stored item with the operation and time for the history, if needed.
+    /// The unique values it no longer has are released, except for patches, which cannot change them.
+    /// A failure of the item itself is mapped by `item_error`, taken values fail with `UniqueViolation`.
+    /// Sentinels of deleted or expired entities are released and the write is retried.
+    ///
+    pub(super) async fn write_entity(
+        &self,
+        write: TransactWrite,
+        new: Option<&Versioned<T>>,
+        previous: Option<(&Item, WriteOperation, i64)>,
+        item_error: fn(StorageError) -> RepoError,
+    ) -> Result<(), RepoError> {
+        let events = self.outbox_records(write.key())?;
+        let mut writes = vec![write];
+        // the unique attribute of each write after the item, `None` for releases, history and events
+        let mut claims = Vec::new();
+        if let Some(new) = new {
+            for unique in T::UNIQUE {
+                writes.push(self.claim(unique, new)?);
+                claims.push(Some(unique));
+            }
+        }
+        if let Some((previous, operation, changed_at)) = previous {
+            if operation != WriteOperation::Patch && !T::UNIQUE.is_empty() {
+                let previous: Versioned<T> = decode(previous.clone())?;
+                for unique in T::UNIQUE {
+                    let value = (unique.value)(&previous.data);
+                    if new.is_none_or(|new| (unique.value)(&new.data) != value) {
+                        writes.push(self.release(unique, &previous.data)?);
+                        claims.push(None);
+                    }
+                }
+            }
+            if let Some(history_table) = &self.history_table {
+                writes.push(self.history_record(history_table, previous, operation, changed_at)?);
+                claims.push(None);
+            }
+        }
+        claims.extend(events.iter().map(|_| None));
+        writes.extend(events);
+
+        let mut released = 0;
+        loop {
+            let result = if writes.len() == 1 {
+                self.write_one(writes[0].clone()).await
+            } else {
+                self.storage.transact_write(writes.clone()).await
+            };
+            let reasons = match result {
+                Ok(()) => return Ok(()),
+                Err(StorageError::TransactionCanceled(reasons)) => reasons,
+                Err(e) => return Err(item_error(e)),
+            };
+
+            let mut reasons = reasons.into_iter();
+            if let Some(Some(reason)) = reasons.next() {
+                return Err(item_error(reason));
+            }
+            let taken = reasons
+                .zip(&claims)
+                .find_map(|(reason, claim)| reason.and(*claim));
+            match (taken, new) {
+                (Some(unique), Some(new))
+                    if released < T::UNIQUE.len() && self.release_stale(unique, new).await? =>
+                {
+                    released += 1;
+                }
+                (Some(unique), _) => {
+                    return Err(RepoError::UniqueViolation {
+                        attribute: unique.attribute,
+                    })
+                }
+                // a released sentinel, the history record or an event
+                (None, _) => return Err(RepoError::Conflict),
+            }
+        }
+    }
+
+    /// Puts the sentinel of the unique value of `entity` unless another item owns it.
+    pub(super) fn claim(
+        &self,
+        unique: &Unique<T>,
+        entity: &Versioned<T>,
+    ) -> Result<TransactWrite, RepoError> {
+        let owner = owner_attributes(&self.stored_key(&entity.data.key())?);
+        let (condition, values) = owned_by(&owner);
+        let mut item = unique.sentinel_key(self.scope()?, &entity.data);
+        item.extend(owner);
+        if let Some(expires_at) = entity.expires_at {
+            // removed by TTL together with the entity
+            item.insert(
+                "expires_at".to_string(),
+                AttributeValue::N(expires_at.to_string()),
+            );
+        }
+
+        Ok(TransactWrite::Put(Put {
+            table: self.table_name.clone(),
+            item,
+            condition: Some(condition),
+            values,
+            ..Default::default()
+        }))
+    }
+
+    /// Deletes the sentinel of the unique value of `entity` if it owns it.
+    fn release(&self, unique: &Unique<T>, entity: &T) -> Result<TransactWrite, RepoError> {
+        let (condition, values) = owned_by(&owner_attributes(&self.stored_key(&entity.key())?));
+        Ok(TransactWrite::Delete(Delete {
+            table: self.table_name.clone(),
+            key: unique.sentinel_key(self.scope()?, entity),
+            condition: Some(condition),
+            values,
+            ..Default::default()
+        }))
+    }
+
+    ///
+    /// Releases the sentinel of the value of `entity` if its owner no longer has the value.
+    ///
+    /// This is the case if the owner was deleted, e.g. by TTL, or written without swapping the sentinel.
+    /// Returns whether the sentinel was released.
+    ///
+    async fn release_stale(
+        &self,
+        unique: &Unique<T>,
+        entity: &Versioned<T>,
+    ) -> Result<bool, RepoError> {
+        let key = unique.sentinel_key(self.scope()?, &entity.data);
+        let Some(sentinel) = self
+            .storage
+            .get(&self.table_name, key.clone(), true)
+            .await?
+        else {
+            // released meanwhile
+            return Ok(true);
+        };
+
+        let owner: Item = OWNER_ATTRIBUTES
+            .iter()
+            .filter_map(|(_, attribute)| {
+                Some((attribute.to_string(), sentinel.get(*attribute)?.clone()))
+            })
+            .collect();
+        let owner_key: Item = OWNER_ATTRIBUTES
+            .iter()
+            .filter_map(|(key, attribute)| Some((key.to_string(), owner.get(*attribute)?.clone())))
+            .collect();
+        let stale = match self.storage.get(&self.table_name, owner_key, true).await? {
+            None => true,
+            Some(item) => {
+                let owner: Versioned<T> = decode(item)?;
+                let expired = owner
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= chrono::Utc::now().timestamp());
+                expired || (unique.value)(&owner.data) != (unique.value)(&entity.data)
+            }
+        };
+        if !stale {
+            return Ok(false);
+        }
+
+        let (condition, values) = owned_by(&owner);
+        let result = self
+            .storage
+            .delete(Delete {
+                table: self.table_name.clone(),
+                key,
+                condition: Some(condition),
+                values,
+                ..Default::default()
+            })
+            .await;
+        match result {
+            // claimed meanwhile
+            Ok(()) | Err(StorageError::ConditionFailed(_)) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    ///
+    /// Claims the sentinels of the unique values of all items, e.g. after adding a `Unique` to an entity
+    /// whose table has items already. Sentinels the items own already are kept, so it can be run again.
+    ///
+    /// Returns the keys and attributes of the items whose value is taken by another item;
+    /// resolve them and run it again. `TENANT_SCOPED` entities are claimed per tenant, see `for_tenant`.
+    ///
+    pub async fn claim_unique_values(&self) -> Result<Vec<(Key, &'static str)>, RepoError> {
+        let mut taken = Vec::new();
+        let mut entities = std::pin::pin!(self.list_all());
+        while let Some(entity) = entities.try_next().await? {
+            for unique in T::UNIQUE {
+                match self.write_one(self.claim(unique, &entity)?).await {
+                    Ok(()) => {}
+                    Err(StorageError::ConditionFailed(_)) => {
+                        taken.push((entity.data.key(), unique.attribute))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(taken)
+    }
+
+    async fn write_one(&self, write: TransactWrite) -> Result<(), StorageError> {
+        match write {
+            TransactWrite::Put(put) => self.storage.put(put).await,
+            TransactWrite::Delete(delete) => self.storage.delete(delete).await,
+            TransactWrite::Update(update) => self.storage.update(update).await.map(|_| ()),
+            TransactWrite::Check(check) => {
+                self.storage
+                    .transact_write(vec![TransactWrite::Check(check)])
+                    .await
+            }
+        }
+    }
+}
+
+/// The key attributes of the entity owning a sentinel and their names in the sentinel.
+const OWNER_ATTRIBUTES: [(&str, &str); 2] = [(PK, "owner_pk"), (SK, "owner_sk")];
+
+/// The owner attributes of a sentinel: the stored key of the entity.
+fn owner_attributes(key: &Item) -> Item {
+    OWNER_ATTRIBUTES
+        .iter()
+        .filter_map(|(key_attribute, attribute)| {
+            Some((attribute.to_string(), key.get(*key_attribute)?.clone()))
+        })
+        .collect()
+}
+
+/// The condition that a sentinel is missing or owned by `owner`, with its values.
+fn owned_by(owner: &Item) -> (String, Item) {
+    let mut conditions = Vec::new();
+    let mut values = HashMap::new();
+    for (_, attribute) in OWNER_ATTRIBUTES {
+        if let Some(value) = owner.get(attribute) {
+            conditions.push(format!("{} = :{}", attribute, attribute));
+            values.insert(format!(":{}", attribute), value.clone());
+        }
+    }
+    let condition = format!("attribute_not_exists(pk) OR ({})", conditions.join(" AND "));
+    (condition, values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity_field;
+    use crate::shared::dynamodb::{MemoryStorage, PageRequest, Storage, Transaction, Update};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Member {
+        id: String,
+        email: String,
+    }
+
+    impl Entity for Member {
+        const NAME: &'static str = "member";
+        const UNIQUE: &'static [Unique<Self>] = &[Unique {
+            attribute: "email",
+            value: |member| member.email.to_lowercase(),
+        }];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    fn member(id: &str, email: &str) -> Member {
+        Member {
+            id: id.to_string(),
+            email: email.to_string(),
+        }
+    }
+
+    fn taken(result: Result<impl std::fmt::Debug, RepoError>) -> bool {
+        matches!(
+            result,
+            Err(RepoError::UniqueViolation { attribute: "email" })
+        )
+    }
+
+    #[tokio::test]
+    async fn insert_of_taken_value_fails() {
+        let repo = MemoryStorage::new().repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+
+        assert!(taken(repo.insert(member("2", "Ann@example.com")).await));
+        assert!(matches!(
+            repo.insert(member("1", "other@example.com")).await,
+            Err(RepoError::AlreadyExists)
+        ));
+        assert!(repo.read("2").await.unwrap().is_none());
+
+        // sentinels are no members
+        let members = repo.list(PageRequest::default()).await.unwrap().items;
+        assert_eq!(members.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn update_swaps_the_sentinel() {
+        let repo = MemoryStorage::new().repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+        repo.insert(member("2", "bob@example.com")).await.unwrap();
+
+        assert!(taken(
+            repo.modify("1", |m| m.email = "bob@example.com".to_string())
+                .await
+        ));
+        repo.modify("1", |m| m.email = "anna@example.com".to_string())
+            .await
+            .unwrap();
+        repo.insert(member("3", "ann@example.com")).await.unwrap();
+        assert!(taken(repo.insert(member("4", "anna@example.com")).await));
+
+        let bob = repo.read("2").await.unwrap().unwrap();
+        repo.delete("2", bob.last_write).await.unwrap();
+        repo.insert(member("4", "bob@example.com")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sentinels_of_removed_entities_are_reclaimed() {
+        let storage = MemoryStorage::new();
+        let repo = storage.repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+        repo.insert_expiring(member("2", "bob@example.com"), Duration::ZERO)
+            .await
+            .unwrap();
+        // removed without releasing the sentinel, like DynamoDB TTL does
+        storage
+            .delete(Delete {
+                table: "members".to_string(),
+                key: Key::new("1").to_item::<Member>(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        repo.insert(member("3", "ann@example.com")).await.unwrap();
+        repo.insert(member("4", "bob@example.com")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unique_attributes_cannot_be_patched() {
+        let repo = MemoryStorage::new().repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+
+        let update = Update::new().set(entity_field!(Member, email), "bob@example.com".to_string());
+        assert!(repo.patch("1", update).await.is_err());
+        assert!(repo
+            .batch_put(vec![member("2", "bob@example.com")])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn transactions_claim_unique_values() {
+        let repo = MemoryStorage::new().repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+
+        let mut transaction = Transaction::new();
+        transaction
+            .insert(&repo, member("2", "ann@example.com"))
+            .unwrap();
+        let Err(RepoError::TransactionCanceled(reasons)) = transaction.commit().await else {
+            panic!("transaction was not canceled");
+        };
+        assert!(matches!(
+            reasons[1],
+            Some(RepoError::UniqueViolation { attribute: "email" })
+        ));
+    }
+
+    #[tokio::test]
+    async fn sentinels_of_existing_items_are_claimed() {
+        let storage = MemoryStorage::new();
+        let repo = storage.repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+        // written before `UNIQUE` was declared
+        for (id, email) in [("2", "bob@example.com"), ("3", "Bob@example.com")] {
+            storage
+                .put(Put {
+                    table: "members".to_string(),
+                    item: repo
+                        .entity_item(&Versioned::new(member(id, email)))
+                        .unwrap(),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        }
+
+        let duplicates = repo.claim_unique_values().await.unwrap();
+        assert_eq!(duplicates, vec![(Key::new("3"), "email")]);
+        assert!(taken(repo.insert(member("4", "bob@example.com")).await));
+    }
+
+    #[tokio::test]
+    async fn keys_of_sentinels_are_invalid() {
+        let repo = MemoryStorage::new().repo::<Member>("members");
+        repo.insert(member("1", "ann@example.com")).await.unwrap();
+
+        assert!(matches!(
+            repo.read("UNIQUE#member#email#ann@example.com").await,
+            Err(RepoError::InvalidKey)
+        ));
+        assert!(matches!(
+            repo.insert(member(
+                "UNIQUE#member#email#bob@example.com",
+                "bob@example.com"
+            ))
+            .await,
+            Err(RepoError::InvalidKey)
+        ));
+    }
+}
@@ -0,0 +1,523 @@
+use crate::shared::dynamodb::migration::{item_last_write, item_version};
+use crate::shared::dynamodb::repo::next_write;
+use crate::shared::dynamodb::{
+    current_version, migrate, Entity, Key, RepoError, StorageError, TransactWrite, UpdateItem,
+    Versioned, VersionedRepo, WriteOperation,
+};
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// Attempts of a `patch` before failing with `Conflict`.
+const MAX_ATTEMPTS: u32 = 3;
+
+///
+/// A top-level field of the entity `T` with the value type `V`.
+///
+/// Create it with `entity_field!` to check the name and type against the struct at compile time.
+///
+pub struct Field<T, V> {
+    name: &'static str,
+    types: PhantomData<fn(&T) -> &V>,
+}
+
+impl<T, V> Field<T, V> {
+    /// The accessor only ties `V` to the type of the field, see `entity_field!`.
+    pub fn new(name: &'static str, _accessor: fn(&T) -> &V) -> Self {
+        Self {
+            name,
+            types: PhantomData,
+        }
+    }
+}
+
+///
+/// The `Field` of an entity, checked at compile time.
+///
+/// ```ignore
+/// let username = entity_field!(UserData, username); // Field<UserData, String>
+/// ```
+///
+/// Fields renamed by serde attributes are not supported.
+///
+#[macro_export]
+macro_rules! entity_field {
+    ($entity:ty, $field:ident) => {
+        $crate::shared::dynamodb::Field::new(stringify!($field), |entity: &$entity| &entity.$field)
+    };
+}
+
+/// Types of number fields, see `Update::add`.
+pub trait Number: Serialize {}
+
+macro_rules! number {
+    ($($t:ty),*) => { $(impl Number for $t {})* };
+}
+
+number!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, f32, f64);
+
+///
+/// A partial update of an entity `T`, written with `VersionedRepo::patch`.
+///
+/// Only the named fields are written, so concurrent patches of different fields do not conflict
+/// unless `if_unchanged_since` is set.
+///
+pub struct Update<T> {
+    set: Vec<String>,
+    remove: Vec<String>,
+    add: Vec<String>,
+    names: HashMap<String, String>,
+    values: HashMap<String, AttributeValue>,
+    expected: Option<i64>,
+    error: Option<serde_dynamo::Error>,
+    entity: PhantomData<fn() -> T>,
+}
+
+impl<T: Entity> Default for Update<T> {
+    fn default() -> Self {
+        Self {
+            set: Vec::new(),
+            remove: Vec::new(),
+            add: Vec::new(),
+            names: HashMap::new(),
+            values: HashMap::new(),
+            expected: None,
+            error: None,
+            entity: PhantomData,
+        }
+    }
+}
+
+impl<T: Entity> Update<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the field to the value.
+    pub fn set<V: Serialize>(self, field: Field<T, V>, value: V) -> Self {
+        self.set_attribute(field.name, value)
+    }
+
+    /// Removes an optional field, it is read as `None`.
+    pub fn remove<V>(self, field: Field<T, Option<V>>) -> Self {
+        self.remove_attribute(field.name)
+    }
+
+    /// Sets an attribute of `Versioned` next to the fields of the entity.
+    pub(super) fn set_attribute<V: Serialize>(mut self, attribute: &str, value: V) -> Self {
+        let (name, value) = self.placeholders(attribute, &value);
+        self.set.push(format!("{} = {}", name, value));
+        self
+    }
+
+    pub(super) fn remove_attribute(mut self, attribute: &str) -> Self {
+        let name = self.name(attribute);
+        self.remove.push(name);
+        self
+    }
+
+    /// Adds the value to a number field.
+    pub fn add<V: Number>(mut self, field: Field<T, V>, value: V) -> Self {
+        let (name, value) = self.placeholders(field.name, &value);
+        self.add.push(format!("{} {}", name, value));
+        self
+    }
+
+    /// Appends the values to a list field, creating it if missing.
+    pub fn append<V: Serialize>(mut self, field: Field<T, Vec<V>>, values: Vec<V>) -> Self {
+        let (name, values) = self.placeholders(field.name, &values);
+        self.set.push(format!(
+            "{name} = list_append(if_not_exists({name}, :empty_list), {values})"
+        ));
+        self.values
+            .insert(":empty_list".to_string(), AttributeValue::L(Vec::new()));
+        self
+    }
+
+    /// Keeps the optimistic lock: fails with `Conflict` if the item was written since `last_write`.
+    pub fn if_unchanged_since(mut self, last_write: i64) -> Self {
+        self.expected = Some(last_write);
+        self
+    }
+
+    fn name(&mut self, field: &str) -> String {
+        let name = format!("#f{}", self.names.len());
+        self.names.insert(name.clone(), field.to_string());
+        name
+    }
+
+    fn placeholders<V: Serialize>(&mut self, field: &str, value: &V) -> (String, String) {
+        let name = self.name(field);
+        let placeholder = format!(":v{}", self.values.len());
+        match serde_dynamo::to_attribute_value(value) {
+            Ok(value) => {
+                self.values.insert(placeholder.clone(), value);
+            }
+            Err(e) => self.error = Some(e),
+        }
+        (name, placeholder)
+    }
+
+    /// The update expression writing `last_write` as `:last_write`.
+    fn expression(&self) -> String {
+        let mut set = self.set.clone();
+        set.push("last_write = :last_write".to_string());
+        let mut expression = format!("SET {}", set.join(", "));
+        if !self.remove.is_empty() {
+            expression.push_str(&format!(" REMOVE {}", self.remove.join(", ")));
+        }
+        if !self.add.is_empty() {
+            expression.push_str(&format!(" ADD {}", self.add.join(", ")));
+        }
+        expression
+    }
+}
+
+impl<T: Entity> VersionedRepo<T> {
+    ///
+    /// Writes only the fields of the update and a new `last_write` token.
+    ///
+    /// Fails with `NotFound` if there is no item for the key
+    /// and with `Conflict` if `if_unchanged_since` is set and the item was written since.
+    /// Items of older `data_version`s are migrated before the update is applied.
+    /// Unique attributes cannot be patched, write them with `update`.
+    /// Returns the entity as written.
+    ///
+    pub async fn patch(
+        &self,
+        key: impl Into<Key>,
+        update: Update<T>,
+    ) -> Result<Versioned<T>, RepoError> {
+        if let Some(e) = update.error {
+            return Err(e.into());
+        }
+        if let Some(unique) = T::UNIQUE
+            .iter()
+            .find(|unique| update.names.values().any(|name| name == unique.attribute))
+        {
+            return Err(RepoError::Transport(
+                format!("{} is unique, write it with update", unique.attribute).into(),
+            ));
+        }
+        if self.history_table.is_some() || !self.events.is_empty() {
+            return self.patch_transactional(key.into(), update).await;
+        }
+        let key = self.stored_key(&key.into())?;
+        let expression = update.expression();
+        let mut condition = "attribute_exists(pk) AND data_version = :data_version".to_string();
+        let mut last_write = match update.expected {
+            Some(expected) => {
+                condition.push_str(" AND last_write = :expected");
+                next_write(expected)
+            }
+            // keeps the token strictly increasing
+            None => {
+                condition.push_str(" AND last_write < :last_write");
+                chrono::Utc::now().timestamp_millis()
+            }
+        };
+
+        for _ in 0..MAX_ATTEMPTS {
+            let mut values = update.values.clone();
+            values.insert(
+                ":last_write".to_string(),
+                AttributeValue::N(last_write.to_string()),
+            );
+            values.insert(
+                ":data_version".to_string(),
+                AttributeValue::N(current_version::<T>().to_string()),
+            );
+            if let Some(expected) = update.expected {
+                values.insert(
+                    ":expected".to_string(),
+                    AttributeValue::N(expected.to_string()),
+                );
+            }
+
+            let result = self
+                .storage
+                .update(UpdateItem {
+                    table: self.table_name.clone(),
+                    key: key.clone(),
+                    update: expression.clone(),
+                    condition: Some(condition.clone()),
+                    names: update.names.clone(),
+                    values,
+                })
+                .await;
+
+            let current = match result {
+                Ok(item) => return self.decode(item).await,
+                Err(StorageError::ConditionFailed(Some(item))) => item,
+                Err(StorageError::ConditionFailed(None)) => return Err(RepoError::NotFound),
+                Err(e) => return Err(e.into()),
+            };
+
+            if item_version(&current) < current_version::<T>() {
+                // bring the item into the shape the update is written for
+                if let Some(migrated) = migrate::<T>(&current)? {
+                    match self.put_migrated(&current, &migrated).await {
+                        Ok(()) | Err(RepoError::Conflict) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            } else if update.expected.is_some() || item_version(&current) > current_version::<T>() {
+                return Err(RepoError::Conflict);
+            } else {
+                // written within the same millisecond
+                last_write = last_write.max(item_last_write(&current) + 1);
+            }
+        }
+
+        Err(RepoError::Conflict)
+    }
+
+    ///
+    /// `patch` in a transaction with the history record of the previous item and the events of `publishing`.
+    ///
+    /// The previous item is read before the update.
+    ///
+    async fn patch_transactional(
+        &self,
+        key: Key,
+        update: Update<T>,
+    ) -> Result<Versioned<T>, RepoError> {
+        let expression = update.expression();
+        for _ in 0..MAX_ATTEMPTS {
+            let Some(previous) = self.read_previous(&key).await? else {
+                return Err(RepoError::NotFound);
+            };
+            let previous_write = item_last_write(&previous);
+            if update
+                .expected
+                .is_some_and(|expected| expected != previous_write)
+                || item_version(&previous) > current_version::<T>()
+            {
+                return Err(RepoError::Conflict);
+            }
+            if let Some(migrated) = migrate::<T>(&previous)? {
+                // bring the item into the shape the update is written for
+                match self.put_migrated(&previous, &migrated).await {
+                    Ok(()) | Err(RepoError::Conflict) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let last_write = next_write(previous_write);
+            let mut values = update.values.clone();
+            values.insert(
+                ":last_write".to_string(),
+                AttributeValue::N(last_write.to_string()),
+            );
+            values.insert(
+                ":data_version".to_string(),
+                AttributeValue::N(current_version::<T>().to_string()),
+            );
+            values.insert(
+                ":expected".to_string(),
+                AttributeValue::N(previous_write.to_string()),
+            );
+            let write = TransactWrite::Update(UpdateItem {
+                table: self.table_name.clone(),
+                key: self.stored_key(&key)?,
+                update: expression.clone(),
+                condition: Some(
+                    "last_write = :expected AND data_version = :data_version".to_string(),
+                ),
+                names: update.names.clone(),
+                values,
+            });
+
+            let result = self
+                .write_entity(
+                    write,
+                    None,
+                    Some((&previous, WriteOperation::Patch, last_write)),
+                    RepoError::from,
+                )
+                .await;
+            match result {
+                Ok(()) => {}
+                // written meanwhile
+                Err(RepoError::Conflict) => continue,
+                Err(e) => return Err(e),
+            }
+            // transactions do not return the written item
+            return self.read_strong(key).await?.ok_or(RepoError::NotFound);
+        }
+
+        Err(RepoError::Conflict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::Migration;
+    use aws_sdk_dynamodb::Client;
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+    struct Profile {
+        id: String,
+        name: String,
+        nickname: Option<String>,
+        logins: u32,
+        tags: Vec<String>,
+    }
+
+    impl Entity for Profile {
+        const NAME: &'static str = "profile";
+        // v1 -> v2: + tags
+        const MIGRATIONS: &'static [Migration] = &[|mut value| {
+            value["tags"] = serde_json::json!([]);
+            Ok(value)
+        }];
+
+        fn pk(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    async fn repo(server: &MockServer) -> VersionedRepo<Profile> {
+        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        VersionedRepo::new(Client::new(&shared_config), "profiles".to_string())
+    }
+
+    fn item(data_version: u16, last_write: i64) -> serde_json::Value {
+        serde_json::json!({
+            "pk": {"S": "1"},
+            "id": {"S": "1"},
+            "name": {"S": "Jane"},
+            "logins": {"N": "1"},
+            "tags": {"L": []},
+            "data_version": {"N": data_version.to_string()},
+            "last_write": {"N": last_write.to_string()}
+        })
+    }
+
+    fn conflict(item: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(400).set_body_json(serde_json::json!({
+            "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+            "message": "The conditional request failed",
+            "Item": item
+        }))
+    }
+
+    #[test]
+    fn builds_update_expression() {
+        let update = Update::<Profile>::new()
+            .set(entity_field!(Profile, name), "Joe".to_string())
+            .remove(entity_field!(Profile, nickname))
+            .add(entity_field!(Profile, logins), 1)
+            .append(entity_field!(Profile, tags), vec!["new".to_string()]);
+
+        assert_eq!(
+            update.expression(),
+            "SET #f0 = :v0, #f3 = list_append(if_not_exists(#f3, :empty_list), :v2), \
+             last_write = :last_write REMOVE #f1 ADD #f2 :v1"
+        );
+        assert_eq!(update.names["#f1"], "nickname");
+        assert_eq!(update.values[":v0"], AttributeValue::S("Joe".to_string()));
+    }
+
+    #[tokio::test]
+    async fn patch_writes_only_the_fields() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateItem"))
+            .and(body_partial_json(serde_json::json!({
+                "Key": {"pk": {"S": "1"}},
+                "ConditionExpression":
+                    "attribute_exists(pk) AND data_version = :data_version AND last_write = :expected",
+                "ExpressionAttributeNames": {"#f0": "name"},
+                "ExpressionAttributeValues": {
+                    ":v0": {"S": "Joe"},
+                    ":data_version": {"N": "2"},
+                    ":expected": {"N": "42"}
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Attributes": item(2, 43)
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let update = Update::new()
+            .set(entity_field!(Profile, name), "Joe".to_string())
+            .if_unchanged_since(42);
+        let patched = repo(&server).await.patch("1", update).await.unwrap();
+        assert_eq!(patched.last_write, 43);
+    }
+
+    #[tokio::test]
+    async fn patch_fails_with_conflict_if_written_since() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateItem"))
+            .respond_with(conflict(item(2, 50)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let update = Update::new()
+            .add(entity_field!(Profile, logins), 1)
+            .if_unchanged_since(42);
+        let result = repo(&server).await.patch("1", update).await;
+        assert!(matches!(result, Err(RepoError::Conflict)));
+    }
+
+    #[tokio::test]
+    async fn patch_migrates_older_items_first() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateItem"))
+            .respond_with(conflict(item(1, 42)))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.PutItem"))
+            .and(body_partial_json(serde_json::json!({
+                "Item": {"data_version": {"N": "2"}, "last_write": {"N": "42"}}
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "Attributes": item(2, 43)
+            })))
+            .mount(&server)
+            .await;
+
+        let update = Update::new().append(entity_field!(Profile, tags), vec!["a".to_string()]);
+        let patched = repo(&server).await.patch("1", update).await.unwrap();
+        assert_eq!(patched.data_version, 2);
+    }
+
+    #[tokio::test]
+    async fn patch_of_missing_item_is_not_found() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "DynamoDB_20120810.UpdateItem"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "__type": "com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException",
+                "message": "The conditional request failed"
+            })))
+            .mount(&server)
+            .await;
+
+        let update = Update::new().remove(entity_field!(Profile, nickname));
+        let result = repo(&server).await.patch("1", update).await;
+        assert!(matches!(result, Err(RepoError::NotFound)));
+    }
+}
@@ -0,0 +1,624 @@
+use crate::shared::dynamodb::{IndexKey, KeyType};
+use aes_gcm_siv::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use aws_config::SdkConfig;
+use aws_sdk_kms::primitives::Blob;
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::de::DeserializeOwned;
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::OnceLock;
+
+/// Environment variable with the `KeyringConfig` as JSON.
+pub const ENCRYPTION_KEYS: &str = "ENCRYPTION_KEYS";
+
+/// Prefix of encrypted values: `enc:{key id}:{base64 of nonce and ciphertext}`.
+const PREFIX: &str = "enc:";
+
+const NONCE_LENGTH: usize = 12;
+
+static INSTALLED: OnceLock<Keyring> = OnceLock::new();
+
+thread_local! {
+    /// Whether `Encrypted` reads values that are not encrypted, see `read_plaintext`.
+    static READ_PLAINTEXT: Cell<bool> = const { Cell::new(false) };
+}
+
+///
+/// Unwraps the data keys of a `Keyring`, which are stored encrypted under a master key.
+///
+/// `KmsKeyProvider` in AWS, `StaticKeyProvider` locally and in tests.
+///
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Decrypts a wrapped data key.
+    async fn unwrap_key(&self, wrapped: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Data keys wrapped by a KMS key, e.g. the `CiphertextBlob` of `aws kms generate-data-key`.
+#[derive(Clone)]
+pub struct KmsKeyProvider {
+    client: aws_sdk_kms::Client,
+}
+
+impl KmsKeyProvider {
+    pub fn new(client: aws_sdk_kms::Client) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for KmsKeyProvider {
+    async fn unwrap_key(&self, wrapped: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let output = self
+            .client
+            .decrypt()
+            .ciphertext_blob(Blob::new(wrapped))
+            .send()
+            .await?;
+        output
+            .plaintext
+            .map(Blob::into_inner)
+            .ok_or_else(|| anyhow!("KMS returned no plaintext"))
+    }
+}
+
+/// Data keys wrapped by a static master key. Never use it in production.
+#[derive(Clone)]
+pub struct StaticKeyProvider {
+    cipher: Aes256GcmSiv,
+}
+
+impl StaticKeyProvider {
+    pub fn new(master_key: [u8; 32]) -> Self {
+        Self {
+            cipher: Aes256GcmSiv::new(&master_key.into()),
+        }
+    }
+
+    /// The master key of local development, LocalStack and tests.
+    pub fn local() -> Self {
+        Self::new(Sha256::digest(b"local master key").into())
+    }
+
+    /// Wraps a data key, e.g. to put it into the `ENCRYPTION_KEYS` of a local environment.
+    pub fn wrap_key(&self, key: &[u8]) -> Vec<u8> {
+        seal(&self.cipher, &Aes256GcmSiv::generate_nonce(&mut OsRng), key)
+            .expect("keys are encryptable")
+    }
+}
+
+#[async_trait]
+impl KeyProvider for StaticKeyProvider {
+    async fn unwrap_key(&self, wrapped: &[u8]) -> anyhow::Result<Vec<u8>> {
+        open(&self.cipher, wrapped)
+    }
+}
+
+///
+/// The data keys of a `Keyring` as configured in `ENCRYPTION_KEYS`.
+///
+/// ```json
+/// {"active": "2026-10", "index": "2026-01", "keys": {"2026-01": "AQIDAHh...", "2026-10": "AQIDAHi..."}}
+/// ```
+///
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyringConfig {
+    /// Id of the key encrypting new randomized values.
+    pub active: String,
+    /// Id of the key of deterministic values and blind indexes. It is not rotated with `active`.
+    pub index: String,
+    /// Base64 encoded wrapped data keys by id. Keep retired keys as long as values are encrypted with them.
+    pub keys: HashMap<String, String>,
+}
+
+/// A data key with the subkeys derived for its purposes.
+#[derive(Clone)]
+struct DataKey {
+    cipher: Aes256GcmSiv,
+    nonce_key: Vec<u8>,
+    index_key: Vec<u8>,
+}
+
+impl DataKey {
+    fn new(key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() != 32 {
+            bail!("Data keys must have 32 bytes, not {}", key.len());
+        }
+        Ok(Self {
+            cipher: Aes256GcmSiv::new_from_slice(&mac(key, b"encryption"))?,
+            nonce_key: mac(key, b"nonce"),
+            index_key: mac(key, b"index"),
+        })
+    }
+}
+
+///
+/// The unwrapped data keys encrypting `Encrypted` attributes.
+///
+/// Values are encrypted with AES-256-GCM-SIV and name the id of their key, so keys can be rotated:
+/// add a new key, make it `active` and keep the old one until all items were written again,
+/// e.g. by a backfill. Deterministic values use the `index` key, so lookups keep working.
+///
+#[derive(Clone)]
+pub struct Keyring {
+    active: String,
+    index: String,
+    keys: HashMap<String, DataKey>,
+}
+
+impl Keyring {
+    pub fn new(active: &str, index: &str, keys: HashMap<String, Vec<u8>>) -> anyhow::Result<Self> {
+        let keys = keys
+            .into_iter()
+            .map(|(id, key)| {
+                if id.contains(':') {
+                    bail!("Key id {} must not contain ':'", id);
+                }
+                Ok((id, DataKey::new(&key)?))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?;
+        for id in [active, index] {
+            if !keys.contains_key(id) {
+                bail!("The keyring has no key {}", id);
+            }
+        }
+        Ok(Self {
+            active: active.to_string(),
+            index: index.to_string(),
+            keys,
+        })
+    }
+
+    /// Unwraps the keys of `config` with `provider`.
+    pub async fn load(provider: &dyn KeyProvider, config: &KeyringConfig) -> anyhow::Result<Self> {
+        let mut keys = HashMap::new();
+        for (id, wrapped) in &config.keys {
+            let wrapped = general_purpose::STANDARD.decode(wrapped)?;
+            keys.insert(id.clone(), provider.unwrap_key(&wrapped).await?);
+        }
+        Self::new(&config.active, &config.index, keys)
+    }
+
+    /// A keyring with a static key, used in debug builds and tests if none is installed.
+    pub fn local() -> Self {
+        let key = Sha256::digest(b"local data key").to_vec();
+        Self::new(
+            "local",
+            "local",
+            HashMap::from([("local".to_string(), key)]),
+        )
+        .expect("the local key is valid")
+    }
+
+    /// Makes this the keyring of all `Encrypted` values of the process. Fails if one is installed already.
+    pub fn install(self) -> anyhow::Result<()> {
+        INSTALLED
+            .set(self)
+            .map_err(|_| anyhow!("A keyring is installed already"))
+    }
+
+    /// The installed keyring, the local one in debug builds and tests if none is.
+    pub fn current() -> anyhow::Result<&'static Keyring> {
+        static LOCAL: OnceLock<Keyring> = OnceLock::new();
+        match INSTALLED.get() {
+            Some(keyring) => Ok(keyring),
+            None if cfg!(any(debug_assertions, test)) => Ok(LOCAL.get_or_init(Keyring::local)),
+            None => Err(anyhow!(
+                "No keyring installed, call install_keyring at startup"
+            )),
+        }
+    }
+
+    /// Encrypts `plaintext` into `enc:{key id}:{base64}`.
+    pub fn encrypt<M: Mode>(&self, plaintext: &[u8]) -> anyhow::Result<String> {
+        let id = if M::DETERMINISTIC {
+            &self.index
+        } else {
+            &self.active
+        };
+        let key = &self.keys[id];
+        let nonce = if M::DETERMINISTIC {
+            // GCM-SIV stays secure if a nonce repeats, it then only reveals equal plaintexts
+            *Nonce::from_slice(&mac(&key.nonce_key, plaintext)[..NONCE_LENGTH])
+        } else {
+            Aes256GcmSiv::generate_nonce(&mut OsRng)
+        };
+        let sealed = seal(&key.cipher, &nonce, plaintext)?;
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            id,
+            general_purpose::STANDARD.encode(sealed)
+        ))
+    }
+
+    /// Decrypts a value of `encrypt`, with whichever key of the keyring it names.
+    pub fn decrypt(&self, value: &str) -> anyhow::Result<Vec<u8>> {
+        let Some((id, sealed)) = value
+            .strip_prefix(PREFIX)
+            .and_then(|value| value.split_once(':'))
+        else {
+            bail!("The value is not encrypted");
+        };
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| anyhow!("The keyring has no key {}", id))?;
+        open(&key.cipher, &general_purpose::STANDARD.decode(sealed)?)
+    }
+
+    /// Keyed hash of `value` with the `index` key, to look up or constrain a value without storing it.
+    pub fn blind_index(&self, value: &str) -> String {
+        let key = &self.keys[&self.index];
+        general_purpose::URL_SAFE_NO_PAD.encode(mac(&key.index_key, value.as_bytes()))
+    }
+}
+
+///
+/// Installs the keyring of `ENCRYPTION_KEYS`, call it at startup of every function reading encrypted entities.
+///
+/// Release builds unwrap the keys with KMS and fail without `ENCRYPTION_KEYS`.
+/// Debug builds unwrap them with `StaticKeyProvider::local`, and use `Keyring::local` without it.
+/// Fails if a key cannot be unwrapped, so a function with a broken keyring fails at startup
+/// instead of on the first request encrypting a value.
+///
+pub async fn install_keyring(config: &SdkConfig) -> anyhow::Result<()> {
+    let keyring = match std::env::var(ENCRYPTION_KEYS) {
+        Ok(keys) => {
+            let keys: KeyringConfig = serde_json::from_str(&keys).with_context(|| {
+                format!(
+                    "{} is no keyring config with active, index and keys",
+                    ENCRYPTION_KEYS
+                )
+            })?;
+            let keyring = if cfg!(debug_assertions) {
+                Keyring::load(&StaticKeyProvider::local(), &keys).await
+            } else {
+                let provider = KmsKeyProvider::new(aws_sdk_kms::Client::new(config));
+                Keyring::load(&provider, &keys).await
+            };
+            keyring.with_context(|| format!("Failed to load the keys of {}", ENCRYPTION_KEYS))?
+        }
+        Err(_) if cfg!(debug_assertions) => Keyring::local(),
+        Err(_) => bail!("{} is not set", ENCRYPTION_KEYS),
+    };
+    keyring.install()
+}
+
+///
+/// Keyed hash of `value` with the current keyring, see `Keyring::blind_index`.
+///
+/// Panics if no keyring is installed in a release build: call `install_keyring` in `main`,
+/// which fails at startup if the keyring cannot be loaded.
+///
+pub fn blind_index(value: &str) -> String {
+    Keyring::current()
+        .expect("install_keyring is called at startup")
+        .blind_index(value)
+}
+
+/// How an `Encrypted` value is encrypted.
+pub trait Mode {
+    const DETERMINISTIC: bool;
+}
+
+/// A new nonce for each write, equal values have different ciphertexts. The default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Randomized;
+
+impl Mode for Randomized {
+    const DETERMINISTIC: bool = false;
+}
+
+/// Equal values have equal ciphertexts, so they can be a key of an index. Reveals equal values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Deterministic;
+
+impl Mode for Deterministic {
+    const DETERMINISTIC: bool = true;
+}
+
+///
+/// Runs `f` reading `Encrypted` values that are not encrypted as plaintext, e.g. to load fixtures written by hand.
+///
+/// Items of the tables never need it, their plaintext values are encrypted by migrations.
+///
+pub fn read_plaintext<R>(f: impl FnOnce() -> R) -> R {
+    let previous = READ_PLAINTEXT.replace(true);
+    let result = f();
+    READ_PLAINTEXT.set(previous);
+    result
+}
+
+///
+/// A field of an entity that is stored encrypted with the current `Keyring`, e.g. personal data.
+///
+/// It is encrypted when the entity is serialized and decrypted when it is deserialized,
+/// so the code works with the plaintext. Values that are not encrypted fail to deserialize (outside of `read_plaintext`):
+/// encrypt the values written before the field was encrypted in a migration, see `encrypt_json`.
+///
+/// ```ignore
+/// pub struct UserData {
+///     pub email: Encrypted<String, Deterministic>, // key of the `email-index`
+///     pub first_name: Encrypted<String>,
+/// }
+/// ```
+///
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Encrypted<T, M = Randomized> {
+    value: T,
+    mode: PhantomData<M>,
+}
+
+impl<T, M> Encrypted<T, M> {
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            mode: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: Serialize + DeserializeOwned, M: Mode> Encrypted<T, M> {
+    /// Encrypts a plaintext value of an item in place, e.g. in the migration encrypting a field.
+    pub fn encrypt_json(value: &mut Value) -> anyhow::Result<()> {
+        let plaintext: T = serde_json::from_value(value.take())?;
+        *value = serde_json::to_value(Self::new(plaintext))?;
+        Ok(())
+    }
+}
+
+impl<T, M> Deref for Encrypted<T, M> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, M> From<T> for Encrypted<T, M> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<M> From<&str> for Encrypted<String, M> {
+    fn from(value: &str) -> Self {
+        Self::new(value.to_string())
+    }
+}
+
+// keeps the plaintext out of logs
+impl<T, M> Debug for Encrypted<T, M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Encrypted(..)")
+    }
+}
+
+impl<T: Serialize, M: Mode> Serialize for Encrypted<T, M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let plaintext = serde_json::to_vec(&self.value).map_err(ser::Error::custom)?;
+        let value = Keyring::current()
+            .and_then(|keyring| keyring.encrypt::<M>(&plaintext))
+            .map_err(ser::Error::custom)?;
+        serializer.serialize_str(&value)
+    }
+}
+
+impl<'de, T: DeserializeOwned, M> Deserialize<'de> for Encrypted<T, M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = match Value::deserialize(deserializer)? {
+            Value::String(encrypted) if encrypted.starts_with(PREFIX) => {
+                let plaintext = Keyring::current()
+                    .and_then(|keyring| keyring.decrypt(&encrypted))
+                    .map_err(de::Error::custom)?;
+                serde_json::from_slice(&plaintext)
+            }
+            plaintext if READ_PLAINTEXT.get() => serde_json::from_value(plaintext),
+            _ => return Err(de::Error::custom("value is not encrypted")),
+        };
+        value.map(Self::new).map_err(de::Error::custom)
+    }
+}
+
+impl<T, M> JsonSchema for Encrypted<T, M> {
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn schema_name() -> Cow<'static, str> {
+        "Encrypted".into()
+    }
+
+    fn json_schema(_generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "format": "encrypted"
+        })
+    }
+}
+
+impl<T: Serialize> IndexKey for Encrypted<T, Deterministic> {
+    const TYPE: KeyType = KeyType::S;
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The nonce followed by the ciphertext.
+fn seal(cipher: &Aes256GcmSiv, nonce: &Nonce, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(cipher: &Aes256GcmSiv, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LENGTH {
+        bail!("The ciphertext is too short");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Decryption failed, wrong key or tampered value"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn keyring(active: &str, keys: &[&str]) -> Keyring {
+        let keys = keys
+            .iter()
+            .map(|id| (id.to_string(), Sha256::digest(id.as_bytes()).to_vec()))
+            .collect();
+        Keyring::new(active, "k1", keys).unwrap()
+    }
+
+    #[test]
+    fn randomized_values_differ_deterministic_ones_do_not() {
+        let keyring = keyring("k1", &["k1"]);
+
+        let first = keyring.encrypt::<Randomized>(b"ann").unwrap();
+        let second = keyring.encrypt::<Randomized>(b"ann").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(keyring.decrypt(&first).unwrap(), b"ann");
+
+        let deterministic = keyring.encrypt::<Deterministic>(b"ann").unwrap();
+        assert!(deterministic.starts_with("enc:k1:"));
+        assert_eq!(
+            deterministic,
+            keyring.encrypt::<Deterministic>(b"ann").unwrap()
+        );
+        assert_ne!(
+            deterministic,
+            keyring.encrypt::<Deterministic>(b"bob").unwrap()
+        );
+        assert_eq!(keyring.decrypt(&deterministic).unwrap(), b"ann");
+    }
+
+    #[test]
+    fn rotated_keys_still_decrypt() {
+        let old = keyring("k1", &["k1"]);
+        let rotated = keyring("k2", &["k1", "k2"]);
+
+        let value = old.encrypt::<Randomized>(b"ann").unwrap();
+        assert_eq!(rotated.decrypt(&value).unwrap(), b"ann");
+        assert!(rotated
+            .encrypt::<Randomized>(b"ann")
+            .unwrap()
+            .starts_with("enc:k2:"));
+        // lookups and unique values do not change with the active key
+        assert_eq!(
+            rotated.encrypt::<Deterministic>(b"ann").unwrap(),
+            old.encrypt::<Deterministic>(b"ann").unwrap()
+        );
+        assert_eq!(rotated.blind_index("ann"), old.blind_index("ann"));
+
+        let unknown = rotated.encrypt::<Randomized>(b"ann").unwrap();
+        assert!(old.decrypt(&unknown).is_err());
+        let tampered = value.replace("enc:k1:", "enc:k1:AAAA");
+        assert!(rotated.decrypt(&tampered).is_err());
+    }
+
+    #[test]
+    fn fields_are_encrypted_transparently() {
+        #[derive(Serialize, Deserialize)]
+        struct Person {
+            name: Encrypted<String>,
+            email: Encrypted<String, Deterministic>,
+        }
+
+        let person = Person {
+            name: "Ann".into(),
+            email: "ann@example.com".into(),
+        };
+        let item = serde_dynamo::to_item::<_, crate::shared::dynamodb::Item>(&person).unwrap();
+        let stored = format!("{:?}", item);
+        assert!(!stored.contains("Ann") && !stored.contains("ann@example.com"));
+
+        let read: Person = serde_dynamo::from_item(item).unwrap();
+        assert_eq!(read.name.as_str(), "Ann");
+        assert_eq!(read.email.as_str(), "ann@example.com");
+
+        // written before the fields were encrypted, or by mistake
+        assert!(
+            serde_json::from_str::<Person>(r#"{"name": "Bob", "email": "bob@example.com"}"#)
+                .is_err()
+        );
+        let mut value = serde_json::json!("enc:Bob");
+        Encrypted::<String>::encrypt_json(&mut value).unwrap();
+        let encrypted: Encrypted<String> = serde_json::from_value(value).unwrap();
+        assert_eq!(encrypted.as_str(), "enc:Bob");
+        let fixture: Person = read_plaintext(|| {
+            serde_json::from_str(r#"{"name": "Bob", "email": "bob@example.com"}"#)
+        })
+        .unwrap();
+        assert_eq!(fixture.name.as_str(), "Bob");
+    }
+
+    #[tokio::test]
+    async fn loads_keys_wrapped_by_a_provider() {
+        let provider = StaticKeyProvider::local();
+        let key = Sha256::digest(b"k1").to_vec();
+        let config = KeyringConfig {
+            active: "k1".to_string(),
+            index: "k1".to_string(),
+            keys: HashMap::from([(
+                "k1".to_string(),
+                general_purpose::STANDARD.encode(provider.wrap_key(&key)),
+            )]),
+        };
+
+        let loaded = Keyring::load(&provider, &config).await.unwrap();
+        assert_eq!(
+            loaded.encrypt::<Deterministic>(b"ann").unwrap(),
+            keyring("k1", &["k1"])
+                .encrypt::<Deterministic>(b"ann")
+                .unwrap()
+        );
+
+        let other = StaticKeyProvider::new([0; 32]);
+        assert!(Keyring::load(&other, &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unwraps_keys_with_kms() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "TrentService.Decrypt"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "KeyId": "arn:aws:kms:eu-central-1:000000000000:key/pii",
+                "Plaintext": general_purpose::STANDARD.encode([7u8; 32])
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let config = crate::shared::aws_config::load_aws_config_for_mock(&server).await;
+
+        let provider = KmsKeyProvider::new(aws_sdk_kms::Client::new(&config));
+        let key = provider.unwrap_key(b"wrapped").await.unwrap();
+        assert_eq!(key, vec![7u8; 32]);
+    }
+}
@@ -0,0 +1,282 @@
+#[cfg(any(debug_assertions, test))]
+use crate::shared::dynamodb::{provision_tables, LocalTable};
+use crate::shared::dynamodb::{OutboxEvent, RepoError};
+use aws_lambda_events::event::dynamodb::{Event, EventRecord};
+use aws_lambda_events::event::sqs::SqsMessage;
+use aws_lambda_events::event::streams::{DynamoDbBatchItemFailure, DynamoDbEventResponse};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_sqs::types::MessageAttributeValue;
+use aws_sdk_sqs::Client;
+use base64::{engine::general_purpose, Engine as _};
+use lambda_runtime::{tracing, Error};
+use prost::{Message, Name};
+use std::collections::HashMap;
+
+/// Message attribute with the full name of the protobuf message of an event.
+pub const EVENT_TYPE: &str = "event_type";
+
+/// Message attribute with the id of an event, to drop duplicates.
+pub const EVENT_ID: &str = "event_id";
+
+///
+/// Publishes the events of the outbox table to an SQS queue, consuming the stream of the table.
+///
+/// The body of a message is the base64 encoded protobuf message, its type and id are message attributes.
+/// FIFO queues get the event id as deduplication id and its source as group, so the events
+/// of an entity are received in order. Delivery is at least once: a failed send is retried
+/// with the rest of the batch, so consumers must drop the ids they have processed.
+///
+#[derive(Clone)]
+pub struct OutboxRelay {
+    client: Client,
+    queue_url: String,
+}
+
+impl OutboxRelay {
+    pub fn new(client: Client, queue_url: String) -> Self {
+        Self { client, queue_url }
+    }
+
+    ///
+    /// Sends the events inserted into the outbox in the order of the stream.
+    ///
+    /// Removals by TTL are skipped. Like `handle_stream`, processing stops at the first failure,
+    /// which is reported as batch item failure, so Lambda retries from it.
+    ///
+    pub async fn handle_stream(&self, event: &Event) -> DynamoDbEventResponse {
+        let mut response = DynamoDbEventResponse::default();
+        for record in event.records.iter().filter(|r| r.event_name == "INSERT") {
+            if let Err(e) = self.publish(record).await {
+                tracing::error!("Failed to publish event ({}): {}", record.event_id, e);
+                let mut failure = DynamoDbBatchItemFailure::default();
+                failure.item_identifier = record.change.sequence_number.clone();
+                response.batch_item_failures.push(failure);
+                break;
+            }
+        }
+        response
+    }
+
+    async fn publish(&self, record: &EventRecord) -> Result<(), Error> {
+        let item: HashMap<String, AttributeValue> = record.change.new_image.clone().into();
+        let event = OutboxEvent::from_item(item)?;
+
+        let mut request = self
+            .client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(general_purpose::STANDARD.encode(&event.payload))
+            .message_attributes(EVENT_TYPE, string_attribute(&event.event_type)?)
+            .message_attributes(EVENT_ID, string_attribute(&event.id)?);
+        if self.queue_url.ends_with(".fifo") {
+            request = request
+                .message_group_id(&event.source)
+                .message_deduplication_id(&event.id);
+        }
+        request.send().await?;
+
+        tracing::info!("Published {} {}", event.event_type, event.id);
+        Ok(())
+    }
+}
+
+fn string_attribute(value: &str) -> Result<MessageAttributeValue, Error> {
+    Ok(MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(value)
+        .build()?)
+}
+
+/// An event sent by the `OutboxRelay`, as received by a queue consumer like `message-handler`.
+#[derive(Clone, Debug)]
+pub struct PublishedEvent {
+    pub id: String,
+    /// Full name of the protobuf message, e.g. `user_events.UserCreated`.
+    pub event_type: String,
+    /// The protobuf encoded message.
+    pub payload: Vec<u8>,
+}
+
+impl PublishedEvent {
+    pub fn from_message(message: &SqsMessage) -> Result<Self, Error> {
+        let attribute = |name: &str| {
+            message
+                .message_attributes
+                .get(name)
+                .and_then(|attribute| attribute.string_value.clone())
+                .ok_or_else(|| Error::from(format!("The message has no {}", name)))
+        };
+        let body = message.body.as_deref().unwrap_or_default();
+
+        Ok(Self {
+            id: attribute(EVENT_ID)?,
+            event_type: attribute(EVENT_TYPE)?,
+            payload: general_purpose::STANDARD.decode(body)?,
+        })
+    }
+
+    /// Decodes the event if it is an `M`, `None` for events of other types.
+    pub fn decode<M: Message + Name + Default>(&self) -> Result<Option<M>, Error> {
+        if self.event_type != M::full_name() {
+            return Ok(None);
+        }
+        Ok(Some(M::decode(self.payload.as_slice())?))
+    }
+}
+
+///
+/// Creates the outbox table if it is missing, e.g. on a fresh LocalStack.
+///
+/// Does nothing in release builds, there the table is created by the CDK.
+/// Locally no relay consumes it, the events can be inspected in the table.
+///
+#[cfg(any(debug_assertions, test))]
+pub async fn ensure_local_table(
+    client: &aws_sdk_dynamodb::Client,
+    table_name: &str,
+) -> Result<(), RepoError> {
+    provision_tables(client, &[LocalTable::new(table_name)]).await
+}
+
+#[cfg(not(any(debug_assertions, test)))]
+pub async fn ensure_local_table(
+    _client: &aws_sdk_dynamodb::Client,
+    _table_name: &str,
+) -> Result<(), RepoError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Clone, PartialEq, Message)]
+    struct AccountChanged {
+        #[prost(string, tag = "1")]
+        id: String,
+    }
+
+    impl Name for AccountChanged {
+        const NAME: &'static str = "AccountChanged";
+        const PACKAGE: &'static str = "account_events";
+    }
+
+    fn record(sequence_number: &str, event_name: &str, id: &str) -> serde_json::Value {
+        let payload = AccountChanged { id: id.to_string() }.encode_to_vec();
+        serde_json::json!({
+            "eventID": sequence_number,
+            "eventName": event_name,
+            "eventSource": "aws:dynamodb",
+            "awsRegion": "eu-central-1",
+            "dynamodb": {
+                "Keys": {"pk": {"S": id}},
+                "NewImage": {
+                    "pk": {"S": id},
+                    "event_type": {"S": "account_events.AccountChanged"},
+                    "payload": {"B": general_purpose::STANDARD.encode(payload)},
+                    "source": {"S": "account#1"},
+                    "created_at": {"N": "1"}
+                },
+                "SequenceNumber": sequence_number,
+                "SizeBytes": 100,
+                "StreamViewType": "NEW_AND_OLD_IMAGES"
+            }
+        })
+    }
+
+    fn event(records: Vec<serde_json::Value>) -> Event {
+        serde_json::from_value(serde_json::json!({"Records": records})).unwrap()
+    }
+
+    async fn relay(server: &MockServer, queue_url: &str) -> OutboxRelay {
+        let config = crate::shared::aws_config::load_aws_config_for_mock(server).await;
+        OutboxRelay::new(Client::new(&config), queue_url.to_string())
+    }
+
+    #[tokio::test]
+    async fn sends_inserted_events_with_dedup_id() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-amz-target", "AmazonSQS.SendMessage"))
+            .and(body_partial_json(serde_json::json!({
+                "QueueUrl": "https://sqs/events.fifo",
+                "MessageGroupId": "account#1",
+                "MessageDeduplicationId": "e1",
+                "MessageAttributes": {
+                    "event_type": {"DataType": "String", "StringValue": "account_events.AccountChanged"},
+                    "event_id": {"DataType": "String", "StringValue": "e1"}
+                }
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"MessageId": "m1"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let event = event(vec![
+            record("1", "INSERT", "e1"),
+            // purged by TTL
+            record("2", "REMOVE", "e0"),
+        ]);
+        let response = relay(&server, "https://sqs/events.fifo")
+            .await
+            .handle_stream(&event)
+            .await;
+
+        assert!(response.batch_item_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_first_failed_send() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+
+        let event = event(vec![
+            record("1", "INSERT", "e1"),
+            record("2", "INSERT", "e2"),
+        ]);
+        let response = relay(&server, "https://sqs/events")
+            .await
+            .handle_stream(&event)
+            .await;
+
+        assert_eq!(response.batch_item_failures.len(), 1);
+        assert_eq!(
+            response.batch_item_failures[0].item_identifier.as_deref(),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn decodes_published_events_of_the_type() {
+        let payload = AccountChanged {
+            id: "1".to_string(),
+        }
+        .encode_to_vec();
+        let message: SqsMessage = serde_json::from_value(serde_json::json!({
+            "body": general_purpose::STANDARD.encode(payload),
+            "messageAttributes": {
+                "event_type": {"stringValue": "account_events.AccountChanged", "dataType": "String"},
+                "event_id": {"stringValue": "e1", "dataType": "String"}
+            }
+        }))
+        .unwrap();
+
+        let event = PublishedEvent::from_message(&message).unwrap();
+        assert_eq!(event.id, "e1");
+        let changed: AccountChanged = event.decode().unwrap().unwrap();
+        assert_eq!(changed.id, "1");
+
+        let other = PublishedEvent {
+            event_type: "account_events.AccountClosed".to_string(),
+            ..event
+        };
+        assert!(other.decode::<AccountChanged>().unwrap().is_none());
+    }
+}
@@ -1,6 +1,12 @@
-use lambda_http::http::{header::CONTENT_TYPE, StatusCode};
-use lambda_http::{Body, Error, Response};
+use crate::shared::dynamodb::{Counters, RateLimit, RepoError, Tenant};
+use lambda_http::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use lambda_http::http::StatusCode;
+use lambda_http::{tracing, Body, Error, Request, Response};
 use serde::Serialize;
+use std::future::Future;
+
+/// JWT claim with the tenant of the caller, the custom attribute `tenant_id` of the Cognito user pool.
+pub const TENANT_CLAIM: &str = "custom:tenant_id";
 
 /// Creates a JSON HTTP response with status code 200 OK and matching Content-Type.
 pub fn json_response<T>(value: T) -> Result<Response<Body>, Error>
@@ -23,12 +29,94 @@ where
         .map_err(Into::into)
 }
 
+/// Creates a JSON error response with the HTTP status matching the repository error.
+/// Details of internal errors are logged but not exposed to the client.
+/// Exceeded rate limits tell the client when to retry with a `Retry-After` header.
+pub fn repo_error_response(error: &RepoError) -> Result<Response<Body>, Error> {
+    let status = error.status_code();
+    let message = if status.is_server_error() {
+        tracing::error!("Repository error: {}", error);
+        "Internal server error".to_string()
+    } else {
+        error.to_string()
+    };
+    let mut response = json_with_status(serde_json::json!({ "error": message }), status)?;
+    if let Some(seconds) = error.retry_after() {
+        response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    }
+    Ok(response)
+}
+
+///
+/// Runs the handler if `key` has hits left within the rate limit, e.g. the `sub` of the caller.
+///
+/// Responds with 429 and `Retry-After` otherwise, without running the handler.
+///
+pub async fn with_rate_limit<Fut>(
+    counters: &Counters,
+    limit: &RateLimit,
+    key: &str,
+    handler: Fut,
+) -> Result<Response<Body>, Error>
+where
+    Fut: Future<Output = Result<Response<Body>, Error>>,
+{
+    match counters.hit(limit, key).await {
+        Ok(_) => handler.await,
+        Err(e) => repo_error_response(&e),
+    }
+}
+
+///
+/// The tenant of the caller from the `TENANT_CLAIM` of its JWT, to scope repositories with `for_tenant`.
+///
+/// Fails with `Forbidden` if the claim is missing or no valid tenant id, answer it with `repo_error_response`.
+///
+pub fn request_tenant(req: &Request) -> Result<Tenant, RepoError> {
+    request_claim(req, TENANT_CLAIM)
+        .ok_or(RepoError::Forbidden)
+        .and_then(Tenant::new)
+}
+
+/// The claim `name` of the JWT of the request, e.g. `sub`, `None` if it is missing.
+#[cfg(not(any(debug_assertions, test)))]
+pub fn request_claim(req: &Request, name: &str) -> Option<String> {
+    use lambda_http::RequestExt;
+    req.request_context()
+        .authorizer()
+        .and_then(|auth| auth.jwt.as_ref())
+        .and_then(|jwt| jwt.claims.get(name).cloned())
+}
+
+/// The claim `name` of the JWT of the request, e.g. `sub`, `None` if it is missing.
+// Locally there is no API Gateway authorizer, need to parse the header
+#[cfg(any(debug_assertions, test))]
+pub fn request_claim(req: &Request, name: &str) -> Option<String> {
+    use base64::{engine::general_purpose, Engine as _};
+    let token = req
+        .headers()
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    let payload = token.split('.').nth(1)?;
+    let decoded = general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&decoded).ok()?;
+    claims.get(name)?.as_str().map(str::to_string)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::json_response;
-    use lambda_http::http::{header::CONTENT_TYPE, StatusCode};
+    use super::{json_response, repo_error_response, request_tenant, with_rate_limit};
+    use crate::shared::dynamodb::{Counters, MemoryStorage, RateLimit, RepoError};
+    use lambda_http::http::header::{CONTENT_TYPE, RETRY_AFTER};
+    use lambda_http::http::StatusCode;
     use lambda_http::{Body, Response};
     use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+    use std::time::Duration;
 
     #[derive(Debug, Serialize, Deserialize, PartialEq)]
     struct Foo {
@@ -59,4 +147,73 @@ mod tests {
         let got: Foo = serde_json::from_slice(&body_bytes(&resp)).unwrap();
         assert_eq!(got, sample());
     }
+
+    #[test]
+    fn repo_error_response_maps_status() {
+        let resp = repo_error_response(&RepoError::Conflict).unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let resp = repo_error_response(&RepoError::Transport("secret details".into())).unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(&resp)).unwrap();
+        assert_eq!(body["error"], "Internal server error");
+    }
+
+    #[test]
+    fn repo_error_response_tells_when_to_retry() {
+        let resp = repo_error_response(&RepoError::RateLimited {
+            retry_after: Duration::from_millis(1500),
+        })
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "2");
+    }
+
+    #[test]
+    fn request_tenant_from_the_claim() {
+        use base64::{engine::general_purpose, Engine as _};
+        let request = |claims: serde_json::Value| {
+            let payload = general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string());
+            lambda_http::http::Request::builder()
+                .header("Authorization", format!("Bearer e30.{}.signature", payload))
+                .body(Body::Empty)
+                .unwrap()
+        };
+
+        let tenant = request_tenant(&request(serde_json::json!({
+            "sub": "1",
+            "custom:tenant_id": "acme"
+        })))
+        .unwrap();
+        assert_eq!(tenant.id(), "acme");
+        assert!(matches!(
+            request_tenant(&request(serde_json::json!({"sub": "1"}))),
+            Err(RepoError::Forbidden)
+        ));
+        assert!(matches!(
+            request_tenant(&request(serde_json::json!({"custom:tenant_id": "acme#2"}))),
+            Err(RepoError::Forbidden)
+        ));
+    }
+
+    #[tokio::test]
+    async fn with_rate_limit_stops_handler_when_exceeded() {
+        let storage = MemoryStorage::new();
+        storage.create_table("counters", &[]);
+        let counters = Counters::from_storage(Arc::new(storage), "counters".to_string());
+        let limit = RateLimit::per_hour("test", 1);
+
+        let resp = with_rate_limit(&counters, &limit, "sub", async { json_response(sample()) })
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = with_rate_limit(&counters, &limit, "sub", async {
+            panic!("handler ran despite the limit")
+        })
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(RETRY_AFTER));
+    }
 }
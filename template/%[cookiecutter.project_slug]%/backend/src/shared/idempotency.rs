@@ -0,0 +1,591 @@
+#[cfg(any(debug_assertions, test))]
+use crate::shared::dynamodb::{provision_tables, LocalTable};
+use crate::shared::dynamodb::{
+    Delete, DynamoDbStorage, Item, Put, RepoError, Storage, StorageError,
+};
+use crate::shared::http::{json_with_status, repo_error_response};
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use base64::{engine::general_purpose, Engine as _};
+use lambda_http::http::header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER};
+use lambda_http::http::{Method, StatusCode};
+use lambda_http::{tracing, Body, Error, Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Header of the key a client generates once per mutating request and sends with each retry.
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+const IN_PROGRESS: &str = "in_progress";
+const COMPLETED: &str = "completed";
+
+///
+/// Runs requests and events once per idempotency key and replays the result for duplicates.
+///
+/// Each key is recorded in a table with the keys `pk` and TTL on `expires_at`.
+/// The record is `in_progress` while the first request runs and `completed` with its result afterwards.
+/// Failed requests remove the record, so a retry runs again.
+///
+#[derive(Clone)]
+pub struct IdempotencyStore {
+    storage: Arc<dyn Storage>,
+    table_name: String,
+    ttl: Duration,
+    lock_timeout: Duration,
+}
+
+/// The record of a key found by `start`.
+enum Started {
+    /// The key is new, run the request.
+    New,
+    /// The record of a completed request.
+    Completed(Item),
+    /// Another request with the key is running.
+    InProgress,
+}
+
+impl IdempotencyStore {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self::from_storage(Arc::new(DynamoDbStorage::new(client)), table_name)
+    }
+
+    /// A store on another storage than DynamoDB, e.g. `MemoryStorage` in tests.
+    pub fn from_storage(storage: Arc<dyn Storage>, table_name: String) -> Self {
+        Self {
+            storage,
+            table_name,
+            ttl: Duration::from_secs(24 * 60 * 60),
+            lock_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// How long results are replayed, 24 hours by default.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    ///
+    /// After which time a request that is still in progress is considered failed, 60 seconds by default.
+    ///
+    /// Set it to at least the timeout of the lambda, so a crashed invocation does not block its key.
+    ///
+    pub fn with_lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    ///
+    /// Runs the HTTP handler once per `Idempotency-Key` header and replays its response for duplicates.
+    ///
+    /// `scope` separates the keys of different callers and endpoints, e.g. `"{sub}/orders"`.
+    /// Requests without header and reading requests are passed through, keys that are empty or not ASCII fail with 400.
+    /// Server errors are not recorded. Duplicates fail with 409 while the first request is in progress
+    /// and with 422 if the key was used for another request.
+    ///
+    pub async fn handle_request<F, Fut>(
+        &self,
+        scope: &str,
+        req: Request,
+        handler: F,
+    ) -> Result<Response<Body>, Error>
+    where
+        F: FnOnce(Request) -> Fut,
+        Fut: Future<Output = Result<Response<Body>, Error>>,
+    {
+        let key = match req.headers().get(IDEMPOTENCY_KEY) {
+            Some(key) if is_mutating(req.method()) => match key.to_str() {
+                Ok(key) if !key.trim().is_empty() => format!("{}#{}", scope, key),
+                _ => {
+                    return json_with_status(
+                        serde_json::json!({ "error": "Invalid Idempotency-Key" }),
+                        StatusCode::BAD_REQUEST,
+                    )
+                }
+            },
+            _ => return handler(req).await,
+        };
+        let fingerprint = fingerprint(&req);
+
+        match self.start(&key, &fingerprint).await {
+            Ok(Started::New) => {}
+            Ok(Started::Completed(record)) => {
+                if string(&record, "fingerprint") != Some(fingerprint.as_str()) {
+                    return json_with_status(
+                        serde_json::json!({ "error": "Idempotency-Key was used for another request" }),
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                    );
+                }
+                return replay(record);
+            }
+            Ok(Started::InProgress) => {
+                let mut response = json_with_status(
+                    serde_json::json!({ "error": "A request with this Idempotency-Key is in progress" }),
+                    StatusCode::CONFLICT,
+                )?;
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, "1".parse().expect("valid header value"));
+                return Ok(response);
+            }
+            Err(e) => return repo_error_response(&e),
+        }
+
+        let result = handler(req).await;
+        match &result {
+            Ok(response) if !response.status().is_server_error() => {
+                let recorded = self
+                    .complete(&key, &fingerprint, response_record(response))
+                    .await;
+                if let Err(e) = recorded {
+                    // the request succeeded, duplicates run again
+                    tracing::error!("Failed to record the response of {}: {:?}", key, e);
+                }
+            }
+            _ => self.release(&key).await,
+        }
+        result
+    }
+
+    ///
+    /// Runs `f` once per key, e.g. the id of an event, and returns the result of the first run for duplicates.
+    ///
+    /// Fails while the first run is in progress, so the event is retried later.
+    /// Errors are not recorded. Fails if the result cannot be recorded, then the retry runs `f` again
+    /// after the lock timeout, so `f` must tolerate that, e.g. treat `AlreadyExists` of an insert as done.
+    ///
+    pub async fn once<T, F, Fut>(&self, key: &str, f: F) -> Result<T, Error>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        match self.start(key, "").await? {
+            Started::New => {}
+            Started::Completed(record) => {
+                let result = string(&record, "result").unwrap_or("null");
+                return Ok(serde_json::from_str(result)?);
+            }
+            Started::InProgress => {
+                return Err(format!("{} is in progress", key).into());
+            }
+        }
+
+        let result = f().await;
+        match &result {
+            Ok(value) => {
+                let record = HashMap::from([(
+                    "result".to_string(),
+                    AttributeValue::S(serde_json::to_string(value)?),
+                )]);
+                self.complete(key, "", record).await?;
+            }
+            Err(_) => self.release(key).await,
+        }
+        result
+    }
+
+    /// Records the key as in progress unless there is a record that is not expired or timed out.
+    async fn start(&self, key: &str, fingerprint: &str) -> Result<Started, RepoError> {
+        let now = chrono::Utc::now().timestamp();
+        let result = self
+            .storage
+            .put(Put {
+                table: self.table_name.clone(),
+                item: HashMap::from([
+                    ("pk".to_string(), AttributeValue::S(key.to_string())),
+                    (
+                        "status".to_string(),
+                        AttributeValue::S(IN_PROGRESS.to_string()),
+                    ),
+                    (
+                        "fingerprint".to_string(),
+                        AttributeValue::S(fingerprint.to_string()),
+                    ),
+                    (
+                        "locked_until".to_string(),
+                        number(now + self.lock_timeout.as_secs() as i64),
+                    ),
+                    (
+                        "expires_at".to_string(),
+                        number(now + self.ttl.as_secs() as i64),
+                    ),
+                ]),
+                condition: Some(
+                    "attribute_not_exists(pk) OR expires_at <= :now \
+                     OR (#status = :in_progress AND locked_until <= :now)"
+                        .to_string(),
+                ),
+                // status is a reserved word
+                names: HashMap::from([("#status".to_string(), "status".to_string())]),
+                values: HashMap::from([
+                    (":now".to_string(), number(now)),
+                    (
+                        ":in_progress".to_string(),
+                        AttributeValue::S(IN_PROGRESS.to_string()),
+                    ),
+                ]),
+            })
+            .await;
+
+        match result {
+            Ok(()) => Ok(Started::New),
+            Err(StorageError::ConditionFailed(Some(record)))
+                if string(&record, "status") == Some(COMPLETED) =>
+            {
+                Ok(Started::Completed(record))
+            }
+            // running, or removed by a failed request meanwhile
+            Err(StorageError::ConditionFailed(_)) => Ok(Started::InProgress),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records the result of the key while it is in progress.
+    async fn complete(&self, key: &str, fingerprint: &str, result: Item) -> Result<(), RepoError> {
+        let now = chrono::Utc::now().timestamp();
+        let mut item = result;
+        item.extend([
+            ("pk".to_string(), AttributeValue::S(key.to_string())),
+            (
+                "status".to_string(),
+                AttributeValue::S(COMPLETED.to_string()),
+            ),
+            (
+                "fingerprint".to_string(),
+                AttributeValue::S(fingerprint.to_string()),
+            ),
+            (
+                "expires_at".to_string(),
+                number(now + self.ttl.as_secs() as i64),
+            ),
+        ]);
+
+        self.storage
+            .put(Put {
+                table: self.table_name.clone(),
+                item,
+                condition: Some("#status = :in_progress".to_string()),
+                names: HashMap::from([("#status".to_string(), "status".to_string())]),
+                values: HashMap::from([(
+                    ":in_progress".to_string(),
+                    AttributeValue::S(IN_PROGRESS.to_string()),
+                )]),
+            })
+            .await
+            .map_err(RepoError::from)
+    }
+
+    /// Removes the record of a failed request, so a retry runs again.
+    async fn release(&self, key: &str) {
+        let result = self
+            .storage
+            .delete(Delete {
+                table: self.table_name.clone(),
+                key: key_item(key),
+                condition: Some("#status = :in_progress".to_string()),
+                names: HashMap::from([("#status".to_string(), "status".to_string())]),
+                values: HashMap::from([(
+                    ":in_progress".to_string(),
+                    AttributeValue::S(IN_PROGRESS.to_string()),
+                )]),
+            })
+            .await;
+        if let Err(e) = result {
+            // retries wait for the lock timeout
+            tracing::warn!("Failed to release {}: {:?}", key, e);
+        }
+    }
+}
+
+fn is_mutating(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Hash of the method, path and body, to detect a key reused for another request.
+fn fingerprint(req: &Request) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(req.uri().path());
+    hasher.update(req.body().as_ref());
+    general_purpose::URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// The attributes of a response to replay it.
+fn response_record(response: &Response<Body>) -> Item {
+    let mut record = HashMap::from([(
+        "status_code".to_string(),
+        number(response.status().as_u16() as i64),
+    )]);
+    for (name, header) in [
+        ("content_type", CONTENT_TYPE),
+        ("content_encoding", CONTENT_ENCODING),
+    ] {
+        if let Some(value) = response.headers().get(header).and_then(|v| v.to_str().ok()) {
+            record.insert(name.to_string(), AttributeValue::S(value.to_string()));
+        }
+    }
+    match response.body() {
+        Body::Text(text) => {
+            record.insert("body".to_string(), AttributeValue::S(text.clone()));
+        }
+        Body::Binary(bytes) => {
+            record.insert(
+                "body".to_string(),
+                AttributeValue::B(Blob::new(bytes.clone())),
+            );
+        }
+        _ => {}
+    }
+    record
+}
+
+/// The recorded response of a completed request.
+fn replay(record: Item) -> Result<Response<Body>, Error> {
+    let status = record
+        .get("status_code")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse::<u16>().ok())
+        .unwrap_or(200);
+    let mut builder = Response::builder().status(status);
+    for (name, header) in [
+        ("content_type", CONTENT_TYPE),
+        ("content_encoding", CONTENT_ENCODING),
+    ] {
+        if let Some(value) = string(&record, name) {
+            builder = builder.header(header, value);
+        }
+    }
+    let body = match record.get("body") {
+        Some(AttributeValue::S(text)) => Body::Text(text.clone()),
+        Some(AttributeValue::B(bytes)) => Body::Binary(bytes.clone().into_inner()),
+        _ => Body::Empty,
+    };
+    builder
+        .body(body)
+        .map_err(|e| Error::from(format!("Failed to build response: {}", e)))
+}
+
+fn key_item(key: &str) -> Item {
+    HashMap::from([("pk".to_string(), AttributeValue::S(key.to_string()))])
+}
+
+fn string<'a>(record: &'a Item, name: &str) -> Option<&'a str> {
+    record
+        .get(name)
+        .and_then(|v| v.as_s().ok())
+        .map(String::as_str)
+}
+
+fn number(n: i64) -> AttributeValue {
+    AttributeValue::N(n.to_string())
+}
+
+///
+/// Creates the idempotency table if it is missing, e.g. on a fresh LocalStack.
+///
+/// Does nothing in release builds, there the table is created by the CDK.
+///
+#[cfg(any(debug_assertions, test))]
+pub async fn ensure_local_table(client: &Client, table_name: &str) -> Result<(), RepoError> {
+    provision_tables(client, &[LocalTable::new(table_name)]).await
+}
+
+#[cfg(not(any(debug_assertions, test)))]
+pub async fn ensure_local_table(_client: &Client, _table_name: &str) -> Result<(), RepoError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::{Counters, MemoryStorage};
+    use lambda_http::http::HeaderValue;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn store() -> IdempotencyStore {
+        let storage = MemoryStorage::new();
+        storage.create_table("idempotency", &[]);
+        IdempotencyStore::from_storage(Arc::new(storage), "idempotency".to_string())
+    }
+
+    fn request(key: Option<&str>, body: &str) -> Request {
+        let mut builder = lambda_http::http::Request::builder()
+            .method(Method::POST)
+            .uri("/orders");
+        if let Some(key) = key {
+            builder = builder.header(IDEMPOTENCY_KEY, key);
+        }
+        builder.body(Body::Text(body.to_string())).unwrap()
+    }
+
+    /// Handler counting its runs, responding with the run number.
+    async fn handle(
+        store: &IdempotencyStore,
+        runs: &AtomicU32,
+        req: Request,
+        status: StatusCode,
+    ) -> Response<Body> {
+        store
+            .handle_request("sub", req, |_| async {
+                let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                json_with_status(serde_json::json!({ "run": run }), status)
+            })
+            .await
+            .unwrap()
+    }
+
+    fn body(response: &Response<Body>) -> serde_json::Value {
+        serde_json::from_slice(response.body().as_ref()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replays_the_response_of_duplicates() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+
+        let first = handle(&store, &runs, request(Some("a"), "{}"), StatusCode::CREATED).await;
+        let duplicate = handle(&store, &runs, request(Some("a"), "{}"), StatusCode::CREATED).await;
+        let other = handle(&store, &runs, request(Some("b"), "{}"), StatusCode::CREATED).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(duplicate.status(), StatusCode::CREATED);
+        assert_eq!(
+            duplicate.headers().get(CONTENT_TYPE),
+            first.headers().get(CONTENT_TYPE)
+        );
+        assert_eq!(body(&duplicate), body(&first));
+        assert_eq!(body(&other)["run"], 2);
+    }
+
+    #[tokio::test]
+    async fn writes_of_duplicates_are_not_repeated() {
+        let storage = MemoryStorage::new();
+        storage.create_table("idempotency", &[]);
+        storage.create_table("counters", &[]);
+        let store =
+            IdempotencyStore::from_storage(Arc::new(storage.clone()), "idempotency".to_string());
+        let counters = Counters::from_storage(Arc::new(storage), "counters".to_string());
+
+        // stub of a handler creating an order
+        let counters = &counters;
+        let create_order = |req: Request| async move {
+            let order: serde_json::Value = serde_json::from_slice(req.body().as_ref())?;
+            let number = counters.increment("orders", 1).await?;
+            json_with_status(
+                serde_json::json!({ "number": number, "item": order["item"] }),
+                StatusCode::CREATED,
+            )
+        };
+        let order = r#"{"item":"book"}"#;
+
+        let first = store
+            .handle_request("sub/orders", request(Some("a"), order), create_order)
+            .await
+            .unwrap();
+        let retried = store
+            .handle_request("sub/orders", request(Some("a"), order), create_order)
+            .await
+            .unwrap();
+
+        assert_eq!(retried.status(), StatusCode::CREATED);
+        assert_eq!(body(&retried), body(&first));
+        assert_eq!(counters.read("orders").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn passes_requests_without_key_through() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+
+        handle(&store, &runs, request(None, "{}"), StatusCode::OK).await;
+        handle(&store, &runs, request(None, "{}"), StatusCode::OK).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_keys() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+        let mut not_ascii = request(None, "{}");
+        not_ascii.headers_mut().insert(
+            IDEMPOTENCY_KEY,
+            HeaderValue::from_bytes("schlüssel".as_bytes()).unwrap(),
+        );
+
+        for req in [request(Some(""), "{}"), request(Some(" "), "{}"), not_ascii] {
+            let response = handle(&store, &runs, req, StatusCode::OK).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_not_recorded() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+
+        let failed = handle(
+            &store,
+            &runs,
+            request(Some("a"), "{}"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )
+        .await;
+        let retried = handle(&store, &runs, request(Some("a"), "{}"), StatusCode::OK).await;
+
+        assert_eq!(failed.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(retried.status(), StatusCode::OK);
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_key_reuse_and_concurrent_duplicates() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+        handle(&store, &runs, request(Some("a"), "{}"), StatusCode::OK).await;
+
+        let reused = handle(
+            &store,
+            &runs,
+            request(Some("a"), "{\"x\":1}"),
+            StatusCode::OK,
+        )
+        .await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // the first request is still running
+        let response = store
+            .handle_request("sub", request(Some("b"), "{}"), |req| async {
+                let duplicate = handle(&store, &runs, req, StatusCode::OK).await;
+                assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+                assert!(duplicate.headers().contains_key(RETRY_AFTER));
+                json_with_status(serde_json::json!({}), StatusCode::OK)
+            })
+            .await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn runs_events_once() {
+        let store = store();
+        let runs = AtomicU32::new(0);
+        let run = || async { Ok::<_, Error>(runs.fetch_add(1, Ordering::SeqCst) + 1) };
+
+        assert_eq!(store.once("event-1", run).await.unwrap(), 1);
+        assert_eq!(store.once("event-1", run).await.unwrap(), 1);
+        assert!(store
+            .once("event-2", || async { Err::<u32, Error>("failed".into()) })
+            .await
+            .is_err());
+        assert_eq!(store.once("event-2", run).await.unwrap(), 2);
+    }
+}
@@ -1,6 +1,11 @@
 pub mod aws_config;
 pub mod cognito_user_pool_event;
 pub mod dynamodb;
+pub mod encryption;
+pub mod events;
 pub mod http;
+pub mod idempotency;
 pub mod protocols;
+#[cfg(any(debug_assertions, test))]
+pub mod seed;
 pub mod users;
@@ -0,0 +1,194 @@
+use crate::shared::dynamodb::{Entity, RepoError, VersionedRepo};
+use crate::shared::encryption::read_plaintext;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+///
+/// Directory of the fixture files, `local/seed` of the project.
+///
+/// Uses the SEED_DIR environment variable if set, e.g. for integration tests with their own fixtures.
+///
+pub fn seed_dir() -> PathBuf {
+    std::env::var("SEED_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| local_dir().join("seed"))
+}
+
+///
+/// The users database of cognito-local.
+///
+/// Uses the COGNITO_LOCAL_USER_POOL environment variable if set.
+///
+pub fn cognito_user_pool_file() -> PathBuf {
+    std::env::var("COGNITO_LOCAL_USER_POOL")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| local_dir().join("cognito-local-volume/db/local_userPool.json"))
+}
+
+fn local_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../local")
+}
+
+///
+/// Reads the fixtures of an entity from `{dir}/{Entity::NAME}.json`, a JSON array of entities.
+/// Encrypted fields are given in plaintext.
+///
+/// Returns no fixtures if the file does not exist.
+///
+pub fn load_fixtures<T: Entity>(dir: &Path) -> Result<Vec<T>> {
+    let file = dir.join(format!("{}.json", T::NAME));
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    let json = std::fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    // fixtures hold the plaintext of encrypted fields
+    read_plaintext(|| serde_json::from_str(&json))
+        .with_context(|| format!("Failed to parse {}", file.display()))
+}
+
+///
+/// Inserts the fixtures of an entity that are missing in the table.
+///
+/// Existing items are left as they are, so seeding is safe on every start and keeps local edits.
+/// Returns the number of inserted items.
+///
+pub async fn seed<T: Entity>(repo: &VersionedRepo<T>, fixtures: Vec<T>) -> Result<usize> {
+    let mut inserted = 0;
+    for fixture in fixtures {
+        let key = fixture.key();
+        match repo.insert(fixture).await {
+            Ok(()) => inserted += 1,
+            Err(RepoError::AlreadyExists) => {}
+            Err(e) => return Err(anyhow!("Failed to seed {} {:?}: {}", T::NAME, key, e)),
+        }
+    }
+    Ok(inserted)
+}
+
+///
+/// Checks that cognito-local has a user for each `(sub, email)`.
+///
+/// Fixtures of user data are keyed by the `sub` of their Cognito user.
+/// A fixture without user could never sign in, one with another email would differ from its token.
+///
+pub fn check_cognito_users<'a>(
+    user_pool_file: &Path,
+    users: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<()> {
+    let json = std::fs::read_to_string(user_pool_file)
+        .with_context(|| format!("Failed to read {}", user_pool_file.display()))?;
+    let pool: serde_json::Value = serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse {}", user_pool_file.display()))?;
+
+    // email by sub
+    let mut emails = HashMap::new();
+    for user in pool["Users"]
+        .as_object()
+        .into_iter()
+        .flat_map(|users| users.values())
+    {
+        let attribute = |name: &str| {
+            user["Attributes"]
+                .as_array()
+                .and_then(|attributes| attributes.iter().find(|a| a["Name"] == name))
+                .and_then(|a| a["Value"].as_str())
+        };
+        if let Some(sub) = attribute("sub") {
+            emails.insert(sub, attribute("email").unwrap_or_default());
+        }
+    }
+
+    for (sub, email) in users {
+        match emails.get(sub) {
+            None => {
+                return Err(anyhow!(
+                    "No cognito-local user with sub {} in {}",
+                    sub,
+                    user_pool_file.display()
+                ))
+            }
+            Some(&pool_email) if pool_email != email => {
+                return Err(anyhow!(
+                    "cognito-local user {} has email {} instead of {}",
+                    sub,
+                    pool_email,
+                    email
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dynamodb::MemoryStorage;
+    use crate::shared::users::{UserData, UserRepo};
+
+    fn fixture_dir(name: &str, files: &[(&str, serde_json::Value)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("seed-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, json) in files {
+            std::fs::write(dir.join(file), json.to_string()).unwrap();
+        }
+        dir
+    }
+
+    fn user_pool() -> serde_json::Value {
+        serde_json::json!({
+            "Users": {
+                "1": {
+                    "Attributes": [
+                        {"Name": "sub", "Value": "1"},
+                        {"Name": "email", "Value": "one@example.com"}
+                    ]
+                }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn seeds_missing_items_only() {
+        let dir = fixture_dir(
+            "users",
+            &[(
+                "user_data.json",
+                serde_json::json!([
+                    {"username": "1", "email": "one@example.com", "first_name": "One", "last_name": "User"},
+                    {"username": "2", "email": "two@example.com", "first_name": "Two", "last_name": "User"}
+                ]),
+            )],
+        );
+        let repo: UserRepo = MemoryStorage::new().repo("users");
+
+        let fixtures = load_fixtures::<UserData>(&dir).unwrap();
+        assert_eq!(seed(&repo, fixtures.clone()).await.unwrap(), 2);
+
+        repo.modify("1", |user| user.first_name = "Changed".into())
+            .await
+            .unwrap();
+        assert_eq!(seed(&repo, fixtures).await.unwrap(), 0);
+        let user = repo.read("1").await.unwrap().unwrap();
+        assert_eq!(user.data.first_name.as_str(), "Changed");
+    }
+
+    #[test]
+    fn missing_fixture_file_seeds_nothing() {
+        let dir = fixture_dir("empty", &[]);
+        assert!(load_fixtures::<UserData>(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn checks_users_against_cognito_local() {
+        let dir = fixture_dir("cognito", &[("pool.json", user_pool())]);
+        let pool = dir.join("pool.json");
+
+        check_cognito_users(&pool, [("1", "one@example.com")]).unwrap();
+        assert!(check_cognito_users(&pool, [("1", "other@example.com")]).is_err());
+        assert!(check_cognito_users(&pool, [("2", "two@example.com")]).is_err());
+    }
+}
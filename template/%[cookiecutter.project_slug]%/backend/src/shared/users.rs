@@ -1,189 +1,161 @@
-use crate::shared::dynamodb::{to_item, Versioned};
+#[cfg(any(debug_assertions, test))]
+use crate::shared::dynamodb::{provision_tables, LocalTable};
+use crate::shared::dynamodb::{
+    Entity, Index, Indexes, Migration, PageRequest, RepoError, Unique, Versioned, VersionedRepo,
+};
+use crate::shared::encryption::{blind_index, Deterministic, Encrypted};
+#[cfg(any(debug_assertions, test))]
+use crate::shared::seed::{
+    check_cognito_users, cognito_user_pool_file, load_fixtures, seed, seed_dir,
+};
 use aws_sdk_dynamodb::Client;
+#[cfg(any(debug_assertions, test))]
+use lambda_http::tracing;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Indexes)]
+#[index(name = "email-index", partition = email)]
 pub struct UserData {
     pub username: String, // Cognito Sub
-    pub email: String,
-    pub first_name: String,
-    pub last_name: String,
+    pub email: Encrypted<String, Deterministic>,
+    pub first_name: Encrypted<String>,
+    pub last_name: Encrypted<String>,
 }
 
 pub type User = Versioned<UserData>;
 
-#[derive(Clone)]
-pub struct UserRepo {
-    client: Client,
-    table_name: String,
-}
+impl Entity for UserData {
+    const NAME: &'static str = "user_data";
+    const MIGRATIONS: &'static [Migration] = &[encrypt_personal_data, lowercase_email];
+    const INDEXES: &'static [Index] = &[Self::EMAIL_INDEX];
+    // the sentinel key holds no plaintext email
+    const UNIQUE: &'static [Unique<Self>] = &[Unique {
+        attribute: "email",
+        value: |user| blind_index(&normalize_email(&user.email)),
+    }];
 
-impl UserRepo {
-    pub fn new(client: Client, table_name: String) -> Self {
-        Self { client, table_name }
+    // We use the username (sub) as pk
+    fn pk(&self) -> String {
+        self.username.clone()
     }
+}
 
-    pub async fn insert(&self, user_data: UserData) -> Result<(), anyhow::Error> {
-        let user = User::new(user_data);
-        let item = to_item(&user)?;
-
-        // We use the username (sub) as pk
-        let pk = user.data.username.clone();
-
-        let mut item_map = item;
-        item_map.insert(
-            "pk".to_string(),
-            aws_sdk_dynamodb::types::AttributeValue::S(pk),
-        );
-
-        self.client
-            .put_item()
-            .table_name(&self.table_name)
-            .set_item(Some(item_map))
-            .condition_expression("attribute_not_exists(pk)")
-            .send()
-            .await?;
+// 1 → 2: email and names are encrypted
+fn encrypt_personal_data(mut value: Value) -> Result<Value, anyhow::Error> {
+    Encrypted::<String, Deterministic>::encrypt_json(&mut value["email"])?;
+    Encrypted::<String>::encrypt_json(&mut value["first_name"])?;
+    Encrypted::<String>::encrypt_json(&mut value["last_name"])?;
+    Ok(value)
+}
 
-        Ok(())
-    }
+// 2 → 3: emails are stored normalized
+fn lowercase_email(mut value: Value) -> Result<Value, anyhow::Error> {
+    let email: Encrypted<String, Deterministic> = serde_json::from_value(value["email"].take())?;
+    value["email"] = serde_json::to_value(Encrypted::<String, Deterministic>::new(
+        normalize_email(&email),
+    ))?;
+    Ok(value)
+}
 
-    pub async fn read(&self, username: &str) -> Result<Option<User>, anyhow::Error> {
-        let resp = self
-            .client
-            .get_item()
-            .table_name(&self.table_name)
-            .key("pk", aws_sdk_dynamodb::types::AttributeValue::S(username.to_string()))
-            .send()
-            .await?;
+///
+/// The email as stored and claimed, so the unique value and the `email-index` agree.
+///
+/// Write users with it, `find_by_email` normalizes the email it is given.
+///
+pub fn normalize_email(email: &str) -> String {
+    email.to_lowercase()
+}
 
-        if let Some(item) = resp.item {
-            Ok(Some(crate::shared::dynamodb::from_item(item)?))
-        } else {
-            Ok(None)
-        }
-    }
+pub type UserRepo = VersionedRepo<UserData>;
 
-    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, anyhow::Error> {
-        let resp = self
-            .client
-            .query()
-            .table_name(&self.table_name)
-            .index_name("email-index")
-            .key_condition_expression("email = :email")
-            .expression_attribute_values(
-                ":email",
-                aws_sdk_dynamodb::types::AttributeValue::S(email.to_string()),
-            )
-            .send()
+impl UserRepo {
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, RepoError> {
+        let page = self
+            .query_by_email(normalize_email(email), PageRequest::first(1))
             .await?;
-
-        if let Some(items) = resp.items {
-            if let Some(item) = items.into_iter().next() {
-                return Ok(Some(crate::shared::dynamodb::from_item(item)?));
-            }
-        }
-        Ok(None)
+        Ok(page.items.into_iter().next())
     }
+}
 
-    pub async fn update(&self, user_data: UserData, version: u16) -> Result<(), anyhow::Error> {
-        let mut user = User::new(user_data);
-        user.data_version = version + 1;
-        let item = to_item(&user)?;
-
-        let pk = user.data.username.clone();
-        let mut item_map = item;
-        item_map.insert(
-            "pk".to_string(),
-            aws_sdk_dynamodb::types::AttributeValue::S(pk),
-        );
-
-        self.client
-            .put_item()
-            .table_name(&self.table_name)
-            .set_item(Some(item_map))
-            .condition_expression("data_version = :version")
-            .expression_attribute_values(
-                ":version",
-                aws_sdk_dynamodb::types::AttributeValue::N(version.to_string()),
-            )
-            .send()
-            .await?;
-
-        Ok(())
-    }
+///
+/// Creates the users table and its indexes if they are missing, e.g. on a fresh LocalStack.
+///
+/// Does nothing in release builds, there the table is created by the CDK.
+///
+#[cfg(any(debug_assertions, test))]
+pub async fn ensure_local_table(client: &Client, table_name: &str) -> Result<(), RepoError> {
+    provision_tables(client, &[LocalTable::new(table_name).entity::<UserData>()]).await
+}
 
-    pub async fn delete(&self, username: String, version: u16) -> Result<(), anyhow::Error> {
-        self.client
-            .delete_item()
-            .table_name(&self.table_name)
-            .key("pk", aws_sdk_dynamodb::types::AttributeValue::S(username))
-            .condition_expression("data_version = :version")
-            .expression_attribute_values(
-                ":version",
-                aws_sdk_dynamodb::types::AttributeValue::N(version.to_string()),
-            )
-            .send()
-            .await?;
+#[cfg(not(any(debug_assertions, test)))]
+pub async fn ensure_local_table(_client: &Client, _table_name: &str) -> Result<(), RepoError> {
+    Ok(())
+}
 
-        Ok(())
+///
+/// Inserts the users of `local/seed/user_data.json` that are missing in the table.
+///
+/// Fails if cognito-local has no user with the same sub and email, so tokens and rows match.
+/// Does nothing in release builds.
+///
+#[cfg(any(debug_assertions, test))]
+pub async fn seed_local_users(repo: &UserRepo) -> anyhow::Result<()> {
+    let mut users = load_fixtures::<UserData>(&seed_dir())?;
+    check_cognito_users(
+        &cognito_user_pool_file(),
+        users
+            .iter()
+            .map(|user| (user.username.as_str(), user.email.as_str())),
+    )?;
+    for user in &mut users {
+        user.email = normalize_email(&user.email).into();
     }
+    let inserted = seed(repo, users).await?;
+    if inserted > 0 {
+        tracing::info!("Seeded {} users", inserted);
+    }
+    Ok(())
+}
+
+#[cfg(not(any(debug_assertions, test)))]
+pub async fn seed_local_users(_repo: &UserRepo) -> anyhow::Result<()> {
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use wiremock::matchers::{method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
-
-    #[tokio::test]
-    async fn test_insert_user() {
-        let server = MockServer::start().await;
+    use crate::shared::dynamodb::{BackfillOptions, MemoryStorage, Put, Storage};
 
-        Mock::given(method("POST"))
-            .and(path("/"))
-            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
-            .mount(&server)
-            .await;
+    crate::entity_schema_test!(UserData);
 
-        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(&server).await;
-        let client = aws_sdk_dynamodb::Client::new(&shared_config);
-        let repo = UserRepo::new(client, "users".to_string());
-
-        let user_data = UserData {
+    fn user_data() -> UserData {
+        UserData {
             username: "test_user".to_string(),
-            email: "test@example.com".to_string(),
-            first_name: "Test".to_string(),
-            last_name: "User".to_string(),
-        };
+            email: "test@example.com".into(),
+            first_name: "Test".into(),
+            last_name: "User".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_insert_user() {
+        let repo: UserRepo = MemoryStorage::new().repo("users");
 
-        let result = repo.insert(user_data).await;
+        let result = repo.insert(user_data()).await;
         assert!(result.is_ok());
+        assert!(matches!(
+            repo.insert(user_data()).await,
+            Err(RepoError::AlreadyExists)
+        ));
     }
 
     #[tokio::test]
     async fn test_read_user() {
-        let server = MockServer::start().await;
-
-        Mock::given(method("POST"))
-            .and(path("/"))
-            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
-                "Item": {
-                    "pk": {"S": "test_user"},
-                    "username": {"S": "test_user"},
-                    "email": {"S": "test@example.com"},
-                    "first_name": {"S": "Test"},
-                    "last_name": {"S": "User"},
-                    "data_version": {"N": "1"},
-                    "last_write": {"N": "1234567890"}
-                }
-            })))
-            .mount(&server)
-            .await;
-
-        let shared_config = crate::shared::aws_config::load_aws_config_for_mock(&server).await;
-        let client = aws_sdk_dynamodb::Client::new(&shared_config);
-        let repo = UserRepo::new(client, "users".to_string());
+        let repo: UserRepo = MemoryStorage::new().repo("users");
+        repo.insert(user_data()).await.unwrap();
 
         let result = repo.read("test_user").await;
         assert!(result.is_ok());
@@ -191,4 +163,129 @@ mod tests {
         assert!(user.is_some());
         assert_eq!(user.unwrap().data.username, "test_user");
     }
+
+    #[tokio::test]
+    async fn test_email_is_unique() {
+        let repo: UserRepo = MemoryStorage::new().repo("users");
+        repo.insert(user_data()).await.unwrap();
+
+        let other = UserData {
+            username: "other_user".to_string(),
+            email: "Test@Example.com".into(),
+            ..user_data()
+        };
+        assert!(matches!(
+            repo.insert(other).await,
+            Err(RepoError::UniqueViolation { attribute: "email" })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_find_by_email() {
+        let repo: UserRepo = MemoryStorage::new().repo("users");
+        repo.insert(user_data()).await.unwrap();
+
+        let user = repo.find_by_email("test@example.com").await.unwrap();
+        assert_eq!(user.unwrap().data.username, "test_user");
+        let user = repo.find_by_email("Test@Example.com").await.unwrap();
+        assert_eq!(user.unwrap().data.username, "test_user");
+        assert!(repo
+            .find_by_email("other@example.com")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_personal_data_is_encrypted() {
+        let storage = MemoryStorage::new();
+        let repo: UserRepo = storage.repo("users");
+        repo.insert(user_data()).await.unwrap();
+
+        let stored = format!("{:?}", storage.items("users"));
+        assert!(stored.contains("test_user"));
+        for plaintext in ["test@example.com", "Test", "User"] {
+            assert!(!stored.contains(plaintext), "{} is stored", plaintext);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_users_are_migrated() {
+        let storage = MemoryStorage::new();
+        let repo: UserRepo = storage.repo("users");
+        // written before encryption
+        let item = serde_dynamo::to_item(serde_json::json!({
+            "pk": "test_user", "username": "test_user", "email": "test@example.com",
+            "first_name": "Test", "last_name": "User", "data_version": 1, "last_write": 1
+        }));
+        storage
+            .put(Put {
+                table: "users".to_string(),
+                item: item.unwrap(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let user = repo.read("test_user").await.unwrap().unwrap();
+        assert_eq!(user.data.first_name.as_str(), "Test");
+        // the index holds the plaintext until the item is written again
+        assert!(repo
+            .find_by_email("test@example.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        // migrate-table
+        let report = repo
+            .backfill(BackfillOptions::for_entity::<UserData>(), |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(report.migrated, 1);
+        assert!(!format!("{:?}", storage.items("users")).contains("test@example.com"));
+        assert!(repo
+            .find_by_email("test@example.com")
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_emails_are_migrated_to_lowercase() {
+        let storage = MemoryStorage::new();
+        let repo: UserRepo = storage.repo("users");
+        // written before emails were normalized
+        let email = Encrypted::<String, Deterministic>::from("Test@Example.com");
+        let item = serde_dynamo::to_item(serde_json::json!({
+            "pk": "test_user", "username": "test_user", "email": email,
+            "first_name": Encrypted::<String>::from("Test"),
+            "last_name": Encrypted::<String>::from("User"),
+            "data_version": 2, "last_write": 1
+        }));
+        storage
+            .put(Put {
+                table: "users".to_string(),
+                item: item.unwrap(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let user = repo.read("test_user").await.unwrap().unwrap();
+        assert_eq!(user.data.email.as_str(), "test@example.com");
+        assert!(repo
+            .find_by_email("test@example.com")
+            .await
+            .unwrap()
+            .is_none());
+
+        repo.backfill(BackfillOptions::for_entity::<UserData>(), |_, _| {})
+            .await
+            .unwrap();
+        assert!(repo
+            .find_by_email("Test@Example.com")
+            .await
+            .unwrap()
+            .is_some());
+    }
 }
@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+use aws_lambda_events::event::dynamodb::Event;
+use aws_lambda_events::event::streams::DynamoDbEventResponse;
+use backend::load_aws_config;
+use backend::shared::dynamodb::{handle_stream, StreamHandler};
+use backend::shared::users::{User, UserData};
+use lambda_runtime::{run, service_fn, tracing, Error, LambdaEvent};
+
+///
+/// This lambda reacts on changes of the users table, delivered by its DynamoDB stream.
+///
+/// The images arrive as `Versioned<UserData>` with the migrations applied.
+/// Returning an error from a handler method reports the record as batch item failure:
+/// Lambda retries the batch from this record on.
+///
+struct UserChanges;
+
+#[async_trait]
+impl StreamHandler<UserData> for UserChanges {
+    async fn insert(&self, new: User) -> Result<(), Error> {
+        tracing::info!("User {} was created", new.data.username);
+        Ok(())
+    }
+
+    async fn modify(&self, _old: User, new: User) -> Result<(), Error> {
+        tracing::info!("User {} was updated", new.data.username);
+        Ok(())
+    }
+
+    async fn remove(&self, old: User) -> Result<(), Error> {
+        tracing::info!("User {} was deleted", old.data.username);
+        Ok(())
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Event>) -> Result<DynamoDbEventResponse, Error> {
+    tracing::info!("Received {} stream records", event.payload.records.len());
+    Ok(handle_stream(&event.payload, &UserChanges).await)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing::init_default_subscriber();
+
+    // the images hold encrypted fields
+    let config = load_aws_config().await;
+    backend::shared::encryption::install_keyring(&config).await?;
+
+    run(service_fn(function_handler)).await
+}
@@ -3,10 +3,12 @@ import * as apigateway from 'aws-cdk-lib/aws-apigateway';
 import * as cognito from "aws-cdk-lib/aws-cognito";
 import {Api} from "./backend/api";
 import {Identity} from "./backend/identity";
+import {Events} from "./backend/events";
+import {Encryption} from "./backend/encryption";
+import {backendLambdaStream} from "./backend/backend-lambda";
 import {DeploymentConfig} from "../config";
 
 import {VersionedTable} from "./backend/dynamodb";
-import {AttributeType, ProjectionType} from "aws-cdk-lib/aws-dynamodb";
 
 interface BackendProps {
     config: DeploymentConfig;
@@ -30,27 +32,58 @@ export class Backend extends Construct {
         const usersTable = new VersionedTable(this, 'UsersTable', {
             tableName: 'users',
             removalPolicy: deploymentConfig.removalPolicy,
+            entity: 'user_data',
+            stream: true,
         });
 
-        usersTable.addGlobalSecondaryIndex({
-            indexName: 'email-index',
-            partitionKey: {
-                name: 'email',
-                type: AttributeType.STRING,
-            },
-            projectionType: ProjectionType.ALL,
+        // results of mutating requests and triggers, see `IdempotencyStore` of the backend
+        const idempotencyTable = new VersionedTable(this, 'IdempotencyTable', {
+            tableName: 'idempotency',
+            removalPolicy: deploymentConfig.removalPolicy,
+        });
+
+        // leases of jobs that must not run twice at the same time, see `Leases` of the backend
+        new VersionedTable(this, 'LeasesTable', {
+            tableName: 'leases',
+            removalPolicy: deploymentConfig.removalPolicy,
+        });
+
+        // atomic counters and rate limits, see `Counters` of the backend
+        new VersionedTable(this, 'CountersTable', {
+            tableName: 'counters',
+            removalPolicy: deploymentConfig.removalPolicy,
+        });
+
+        // domain events written with the entities, see `VersionedRepo::publishing` of the backend
+        const outboxTable = new VersionedTable(this, 'OutboxTable', {
+            tableName: 'outbox',
+            removalPolicy: deploymentConfig.removalPolicy,
+            stream: true,
         });
 
         // Locally cognito-local and cargo lambda watch are used instead
         if (deploymentConfig.aws) {
 
-            const identity = new Identity(this, 'Identity', {deploymentConfig, usersTable});
+            const encryption = new Encryption(this, 'Encryption', {deploymentConfig});
+
+            const identity = new Identity(this, 'Identity', {deploymentConfig, usersTable, idempotencyTable, outboxTable, encryption});
 
             this.userPool = identity.userPool;
             this.userPoolClient = identity.userPoolClient;
 
-            const api = new Api(this, 'Api', {deploymentConfig, userPool: this.userPool, usersTable});
+            const api = new Api(this, 'Api', {deploymentConfig, userPool: this.userPool, usersTable, encryption});
             this.restApi = api.gateway;
+
+            new Events(this, 'Events', {deploymentConfig, outboxTable});
+
+            // reacts on changes of the users, see `stream-handler` of the backend
+            const streamHandler = backendLambdaStream(this, 'StreamHandlerFunction', {
+                deploymentConfig,
+                binaryName: 'stream-handler',
+                table: usersTable,
+            });
+            // the images hold encrypted fields
+            encryption.grantDecrypt(streamHandler);
         }
     }
 }
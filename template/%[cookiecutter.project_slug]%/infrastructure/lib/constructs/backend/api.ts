@@ -5,11 +5,13 @@ import * as cognito from "aws-cdk-lib/aws-cognito";
 import * as dynamodb from "aws-cdk-lib/aws-dynamodb";
 import * as logs from "aws-cdk-lib/aws-logs";
 import { DeploymentConfig } from "../../config";
+import { Encryption } from "./encryption";
 
 interface ApiProps {
   deploymentConfig: DeploymentConfig;
   userPool: cognito.IUserPool;
   usersTable: dynamodb.ITable;
+  encryption: Encryption;
 }
 
 /**
@@ -66,6 +68,7 @@ export class Api extends Construct {
       },
     );
     props.usersTable.grantReadData(userProfileFunction);
+    props.encryption.grantDecrypt(userProfileFunction);
 
     // Grant the lambda permission to describe the user pool
     props.userPool.grant(
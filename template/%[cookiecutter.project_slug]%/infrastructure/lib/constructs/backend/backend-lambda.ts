@@ -5,6 +5,9 @@ import * as apigateway from "aws-cdk-lib/aws-apigateway";
 import * as cdk from 'aws-cdk-lib';
 import * as fs from 'fs';
 import * as logs from 'aws-cdk-lib/aws-logs';
+import * as dynamodb from 'aws-cdk-lib/aws-dynamodb';
+import * as sqs from 'aws-cdk-lib/aws-sqs';
+import {DynamoEventSource, SqsDlq} from 'aws-cdk-lib/aws-lambda-event-sources';
 import {execSync} from 'child_process';
 import {DeploymentConfig} from "../../config";
 
@@ -64,6 +67,39 @@ export function backendLambdaApi(scope: Construct, id: string, props: BackendLam
     return lambdaFunction;
 }
 
+export interface BackendLambdaStreamProps extends BackendLambdaProps {
+    table: dynamodb.ITable; // The table to consume, created with `stream: true`
+    retryAttempts?: number; // Retries of a failing record before it goes to the dead letter queue (default: 3)
+}
+
+/**
+ * Creates a cargo lambda function which consumes the DynamoDB stream of a table.
+ * Failed records are reported as batch item failures, the batch is bisected and retried from them.
+ * Records still failing after the retries are sent to a dead letter queue, so they do not block the shard.
+ *
+ * Use it in AWS deployments only, the local proxy lambda does not forward batch item failures.
+ *
+ * @see backendLambda
+ */
+export function backendLambdaStream(scope: Construct, id: string, props: BackendLambdaStreamProps): lambda.Function {
+    const lambdaFunction = backendLambda(scope, id, props);
+
+    const deadLetterQueue = new sqs.Queue(scope, `${id}DeadLetterQueue`, {
+        retentionPeriod: cdk.Duration.days(14),
+        removalPolicy: props.deploymentConfig.removalPolicy,
+    });
+
+    lambdaFunction.addEventSource(new DynamoEventSource(props.table, {
+        startingPosition: lambda.StartingPosition.TRIM_HORIZON,
+        reportBatchItemFailures: true,
+        bisectBatchOnError: true,
+        retryAttempts: props.retryAttempts ?? 3,
+        onFailure: new SqsDlq(deadLetterQueue),
+    }));
+
+    return lambdaFunction;
+}
+
 // Production deployment
 
 function rustLambda(scope: Construct, id: string, props: BackendLambdaProps) {
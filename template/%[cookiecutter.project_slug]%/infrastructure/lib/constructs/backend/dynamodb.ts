@@ -1,11 +1,50 @@
 import { RemovalPolicy } from 'aws-cdk-lib';
-import { AttributeType, BillingMode, Table } from 'aws-cdk-lib/aws-dynamodb';
+import { AttributeType, BillingMode, ProjectionType, StreamViewType, Table } from 'aws-cdk-lib/aws-dynamodb';
 import { Construct } from 'constructs';
+import * as fs from 'fs';
+import * as path from 'path';
 
 export interface VersionedTableProps {
   tableName: string;
   partitionKey?: string;
+  /** Sort key attribute of tables with composite keys, usually `sk` */
+  sortKey?: string;
   removalPolicy?: RemovalPolicy;
+  /** Enables the DynamoDB stream with the old and new images, as the `StreamHandler` of the backend expects */
+  stream?: boolean;
+  /**
+   * `Entity::NAME` of the stored entity.
+   * Creates the global secondary indexes declared in `backend/schema/{entity}.indexes.json`.
+   */
+  entity?: string;
+}
+
+interface IndexKey {
+  name: string;
+  type: 'S' | 'N';
+}
+
+/** Index declaration as written by the entity tests of the backend */
+export interface EntityIndex {
+  indexName: string;
+  partitionKey: IndexKey;
+  sortKey?: IndexKey;
+  projectionType: 'ALL' | 'KEYS_ONLY' | 'INCLUDE';
+  nonKeyAttributes?: string[];
+}
+
+const schemaPath = path.join(__dirname, '..', '..', '..', '..', 'backend', 'schema');
+
+/**
+ * Reads the index declarations of an entity checked in by the backend.
+ */
+export function entityIndexes(entity: string): EntityIndex[] {
+  const file = path.join(schemaPath, `${entity}.indexes.json`);
+  return fs.existsSync(file) ? JSON.parse(fs.readFileSync(file, 'utf-8')) : [];
+}
+
+function attributeType(key: IndexKey): AttributeType {
+  return key.type === 'N' ? AttributeType.NUMBER : AttributeType.STRING;
 }
 
 export class VersionedTable extends Table {
@@ -16,11 +55,25 @@ export class VersionedTable extends Table {
         name: props.partitionKey || 'pk',
         type: AttributeType.STRING,
       },
+      sortKey: props.sortKey ? { name: props.sortKey, type: AttributeType.STRING } : undefined,
       billingMode: BillingMode.PAY_PER_REQUEST,
+      stream: props.stream ? StreamViewType.NEW_AND_OLD_IMAGES : undefined,
+      // removes soft deleted and expiring items, see `VersionedRepo::soft_delete`
+      timeToLiveAttribute: 'expires_at',
       pointInTimeRecoverySpecification: {
         pointInTimeRecoveryEnabled: true,
       },
       removalPolicy: props.removalPolicy,
     });
+
+    for (const index of props.entity ? entityIndexes(props.entity) : []) {
+      this.addGlobalSecondaryIndex({
+        indexName: index.indexName,
+        partitionKey: { name: index.partitionKey.name, type: attributeType(index.partitionKey) },
+        sortKey: index.sortKey && { name: index.sortKey.name, type: attributeType(index.sortKey) },
+        projectionType: ProjectionType[index.projectionType],
+        nonKeyAttributes: index.nonKeyAttributes,
+      });
+    }
   }
 }
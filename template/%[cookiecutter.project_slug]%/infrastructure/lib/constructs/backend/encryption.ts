@@ -0,0 +1,97 @@
+import { Construct } from "constructs";
+import * as cdk from "aws-cdk-lib";
+import * as cr from "aws-cdk-lib/custom-resources";
+import * as kms from "aws-cdk-lib/aws-kms";
+import * as lambda from "aws-cdk-lib/aws-lambda";
+import { DeploymentConfig } from "../../config";
+
+interface EncryptionProps {
+  deploymentConfig: DeploymentConfig;
+}
+
+/** Id of the data key generated on the first deployment. */
+const INITIAL_KEY = "initial";
+
+/**
+ * The KMS key wrapping the data keys of encrypted entity fields, see `shared::encryption` of the backend.
+ *
+ * The first deployment generates a data key wrapped by the KMS key (`GenerateDataKeyWithoutPlaintext`),
+ * so a fresh project works without manual steps. Its plaintext never leaves KMS: functions receive
+ * the wrapped keys as `KeyringConfig` JSON in `ENCRYPTION_KEYS` and unwrap them with `kms:Decrypt` at startup.
+ *
+ * To rotate, pass all wrapped keys as CDK context `encryptionKeys`, which replaces the generated keyring:
+ * `-c encryptionKeys='{"active": "2026-10", "index": "initial", "keys": {"initial": "<blob>", "2026-10": "<blob>"}}'`.
+ * Keep the generated `initial` key in it, values encrypted with it must stay readable.
+ * Create new keys with `aws kms generate-data-key-without-plaintext --key-id alias/pii --key-spec AES_256`.
+ */
+export class Encryption extends Construct {
+  public readonly key: kms.Key;
+  private readonly keys: string;
+
+  constructor(scope: Construct, id: string, props: EncryptionProps) {
+    super(scope, id);
+
+    this.key = new kms.Key(this, "PiiKey", {
+      alias: "pii",
+      description: "Wraps the data keys of encrypted entity fields",
+      enableKeyRotation: true,
+      removalPolicy: props.deploymentConfig.removalPolicy,
+    });
+
+    const keys = this.node.tryGetContext("encryptionKeys");
+    if (keys !== undefined) {
+      this.keys = typeof keys === "string" ? keys : JSON.stringify(keys);
+      return;
+    }
+
+    const dataKey = this.generateDataKey();
+    this.keys = cdk.Stack.of(this).toJsonString({
+      active: INITIAL_KEY,
+      index: INITIAL_KEY,
+      keys: { [INITIAL_KEY]: dataKey },
+    });
+  }
+
+  /**
+   * Lets the function unwrap the data keys and passes them as `ENCRYPTION_KEYS`.
+   */
+  public grantDecrypt(fn: lambda.Function) {
+    fn.addEnvironment("ENCRYPTION_KEYS", this.keys);
+    this.key.grantDecrypt(fn);
+  }
+
+  /**
+   * Generates a data key wrapped by the KMS key once, when the stack is created. Returns its base64 ciphertext.
+   *
+   * Its only property is the KMS key, so later deployments keep the generated key and the values encrypted with it.
+   */
+  private generateDataKey(): string {
+    const generator = new lambda.Function(this, "DataKeyGenerator", {
+      runtime: lambda.Runtime.NODEJS_22_X,
+      handler: "index.handler",
+      timeout: cdk.Duration.seconds(30),
+      code: lambda.Code.fromInline(`
+const { KMSClient, GenerateDataKeyWithoutPlaintextCommand } = require("@aws-sdk/client-kms");
+
+exports.handler = async (event) => {
+  if (event.RequestType === "Delete") {
+    return {};
+  }
+  const output = await new KMSClient({}).send(
+    new GenerateDataKeyWithoutPlaintextCommand({ KeyId: event.ResourceProperties.KeyId, KeySpec: "AES_256" })
+  );
+  return { PhysicalResourceId: "${INITIAL_KEY}", Data: { Ciphertext: Buffer.from(output.CiphertextBlob).toString("base64") } };
+};`),
+    });
+    this.key.grant(generator, "kms:GenerateDataKeyWithoutPlaintext");
+
+    const provider = new cr.Provider(this, "DataKeyProvider", {
+      onEventHandler: generator,
+    });
+    const resource = new cdk.CustomResource(this, "DataKey", {
+      serviceToken: provider.serviceToken,
+      properties: { KeyId: this.key.keyArn },
+    });
+    return resource.getAttString("Ciphertext");
+  }
+}
@@ -0,0 +1,54 @@
+import { Construct } from "constructs";
+import * as cdk from "aws-cdk-lib";
+import * as dynamodb from "aws-cdk-lib/aws-dynamodb";
+import * as sqs from "aws-cdk-lib/aws-sqs";
+import { SqsEventSource } from "aws-cdk-lib/aws-lambda-event-sources";
+import { backendLambda, backendLambdaStream } from "./backend-lambda";
+import { DeploymentConfig } from "../../config";
+
+interface EventsProps {
+  deploymentConfig: DeploymentConfig;
+  outboxTable: dynamodb.ITable; // The outbox table, created with `stream: true`
+}
+
+/**
+ * Publishes the domain events of the outbox table to the events queue consumed by the message handler.
+ *
+ * The FIFO queue deduplicates events by their id and keeps the events of an entity in order.
+ * Messages failing repeatedly are moved to a dead letter queue.
+ */
+export class Events extends Construct {
+  public readonly queue: sqs.Queue;
+
+  constructor(scope: Construct, id: string, props: EventsProps) {
+    super(scope, id);
+
+    const deadLetterQueue = new sqs.Queue(this, "EventsDeadLetterQueue", {
+      fifo: true,
+      retentionPeriod: cdk.Duration.days(14),
+      removalPolicy: props.deploymentConfig.removalPolicy,
+    });
+
+    this.queue = new sqs.Queue(this, "EventsQueue", {
+      fifo: true,
+      deadLetterQueue: { queue: deadLetterQueue, maxReceiveCount: 5 },
+      removalPolicy: props.deploymentConfig.removalPolicy,
+    });
+
+    const relay = backendLambdaStream(this, "OutboxRelayFunction", {
+      deploymentConfig: props.deploymentConfig,
+      binaryName: "outbox-relay",
+      table: props.outboxTable,
+      environment: {
+        EVENTS_QUEUE_URL: this.queue.queueUrl,
+      },
+    });
+    this.queue.grantSendMessages(relay);
+
+    const messageHandler = backendLambda(this, "MessageHandlerFunction", {
+      deploymentConfig: props.deploymentConfig,
+      binaryName: "message-handler",
+    });
+    messageHandler.addEventSource(new SqsEventSource(this.queue));
+  }
+}
@@ -6,12 +6,16 @@ import * as cdk from "aws-cdk-lib";
 import * as lambda from "aws-cdk-lib/aws-lambda";
 import { backendLambda } from "./backend-lambda";
 import { DeploymentConfig } from "../../config";
+import { Encryption } from "./encryption";
 
 import { Table } from "aws-cdk-lib/aws-dynamodb";
 
 interface IdentityProps {
   deploymentConfig: DeploymentConfig;
   usersTable: Table;
+  idempotencyTable: Table;
+  outboxTable: Table;
+  encryption: Encryption;
 }
 
 export class Identity extends Construct {
@@ -28,10 +32,15 @@ export class Identity extends Construct {
       binaryName: "cognito-handler",
       environment: {
         USERS_TABLE_NAME: props.usersTable.tableName,
+        IDEMPOTENCY_TABLE_NAME: props.idempotencyTable.tableName,
+        OUTBOX_TABLE_NAME: props.outboxTable.tableName,
       },
     });
 
     props.usersTable.grantReadWriteData(this.cognitoHandler);
+    props.idempotencyTable.grantReadWriteData(this.cognitoHandler);
+    props.outboxTable.grantWriteData(this.cognitoHandler);
+    props.encryption.grantDecrypt(this.cognitoHandler);
 
     let userPoolEmail: cognito.UserPoolEmail | undefined = undefined;
 
@@ -74,6 +83,10 @@ export class Identity extends Construct {
         minLength: 8,
         requireSymbols: true,
       },
+      // `custom:tenant_id` claim, scopes the repositories of the caller (`request_tenant`)
+      customAttributes: {
+        tenant_id: new cognito.StringAttribute({ mutable: false }),
+      },
       lambdaTriggers: {
         preSignUp: this.cognitoHandler,
         postConfirmation: this.cognitoHandler,
@@ -88,6 +101,10 @@ export class Identity extends Construct {
       authFlows: {
         userSrp: true,
       },
+      // users must not choose their tenant, it is set by admins or backend functions
+      writeAttributes: new cognito.ClientAttributes().withStandardAttributes({
+        email: true,
+      }),
       preventUserExistenceErrors: true,
     });
   }
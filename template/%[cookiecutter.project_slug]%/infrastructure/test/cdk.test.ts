@@ -1,5 +1,5 @@
 import * as cdk from 'aws-cdk-lib';
-import { Template } from 'aws-cdk-lib/assertions';
+import { Match, Template } from 'aws-cdk-lib/assertions';
 import * as Cdk from '../lib/cdk-stack';
 
 test('Infrastructure Created', () => {
@@ -25,4 +25,12 @@ test('Infrastructure Created', () => {
   template.hasResourceProperties('AWS::ApiGateway::RestApi', {
     Name: 'RestApi',
   });
+
+  // Verify indexes declared by the backend entities
+  template.hasResourceProperties('AWS::DynamoDB::Table', {
+    TableName: 'users',
+    GlobalSecondaryIndexes: Match.arrayWith([
+      Match.objectLike({ IndexName: 'email-index' }),
+    ]),
+  });
 });